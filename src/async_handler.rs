@@ -0,0 +1,230 @@
+use crate::endpoints;
+use crate::handler::Handler;
+use crate::http_request::{HttpMethod, HttpRequest};
+use crate::http_response::{Buildable, Builder, HttpResponse, StatusCode};
+use crate::router::Pattern;
+use crate::server;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// The response an `AsyncHandler` is working on.
+pub type HandlerFuture = Pin<Box<dyn Future<Output = HttpResponse> + Send>>;
+
+/// Something that answers requests asynchronously, awaiting timers or other I/O rather than
+/// blocking a thread: an async closure, an `AsyncRouter`, or any type of yours.
+///
+/// The request is shared rather than borrowed, so that the response doesn't have to be ready
+/// before the handler returns.
+pub trait AsyncHandler: Send + Sync {
+    fn handle(&self, http_request: Arc<HttpRequest>) -> HandlerFuture;
+
+    /// Same as `Handler::reject_before_body`: decided on the header section alone, hence not
+    /// async.
+    fn reject_before_body(&self, _http_request: &HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+}
+
+impl<F, Fut> AsyncHandler for F
+where
+    F: Fn(Arc<HttpRequest>) -> Fut + Send + Sync,
+    Fut: Future<Output = HttpResponse> + Send + 'static,
+{
+    fn handle(&self, http_request: Arc<HttpRequest>) -> HandlerFuture {
+        Box::pin(self(http_request))
+    }
+}
+
+/// Dispatches requests to async handlers by method and path pattern, like a `Router`, and all
+/// the others to a blocking `Handler` (a `Router` usually), run on tokio's blocking threads.
+///
+/// Patterns are those of `Router`. GET routes also answer HEAD; OPTIONS, 404 and 405 are the
+/// blocking handler's business, and so are middlewares: they don't wrap the async routes.
+pub struct AsyncRouter {
+    routes: Vec<AsyncRoute>,
+    fallback: Arc<dyn Handler>,
+}
+
+struct AsyncRoute {
+    method: HttpMethod,
+    pattern: Pattern,
+    handler: Arc<dyn AsyncHandler>,
+}
+
+impl AsyncRouter {
+    /// Router handing the requests its routes leave out to `fallback`.
+    #[must_use]
+    pub fn new<H: Handler + 'static>(fallback: H) -> AsyncRouter {
+        AsyncRouter {
+            routes: Vec::new(),
+            fallback: Arc::new(fallback),
+        }
+    }
+
+    /// Registers `handler` for requests with `method` on paths matching `pattern`. The first
+    /// handler registered for a pattern and method pair is kept.
+    ///
+    /// # Panics
+    /// Panics if the pattern is malformed, see `Router::route`.
+    pub fn route<H>(&mut self, method: HttpMethod, pattern: &str, handler: H) -> &mut AsyncRouter
+    where
+        H: AsyncHandler + 'static,
+    {
+        let pattern = match pattern.parse::<Pattern>() {
+            Ok(pattern) => pattern,
+            Err(e) => panic!("{e}"),
+        };
+        if !self
+            .routes
+            .iter()
+            .any(|route| route.method == method && route.pattern == pattern)
+        {
+            self.routes.push(AsyncRoute {
+                method,
+                pattern,
+                handler: Arc::new(handler),
+            });
+        }
+        self
+    }
+
+    pub fn get<F, Fut>(&mut self, pattern: &str, handler: F) -> &mut AsyncRouter
+    where
+        F: Fn(Arc<HttpRequest>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HttpResponse> + Send + 'static,
+    {
+        self.route(HttpMethod::Get, pattern, handler)
+    }
+
+    pub fn post<F, Fut>(&mut self, pattern: &str, handler: F) -> &mut AsyncRouter
+    where
+        F: Fn(Arc<HttpRequest>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HttpResponse> + Send + 'static,
+    {
+        self.route(HttpMethod::Post, pattern, handler)
+    }
+
+    /// Registers the built-in endpoints that await rather than block a thread: `/sleep`. Like
+    /// every async route, they come before the fallback's routes and skip its middlewares.
+    pub fn built_in_endpoints(&mut self) -> &mut AsyncRouter {
+        endpoints::register_async(self);
+        self
+    }
+
+    /// The most specific async route for the request, and the request as it sees it: with the
+    /// captured path parameters.
+    fn resolve(&self, http_request: &HttpRequest) -> Option<(&AsyncRoute, HttpRequest)> {
+        let method = match http_request.http_method {
+            HttpMethod::Head => HttpMethod::Get,
+            ref method => method.clone(),
+        };
+        let raw_path = http_request.raw_path();
+        let mut best: Option<(&AsyncRoute, HttpRequest)> = None;
+        for route in self.routes.iter().filter(|route| route.method == method) {
+            let Some(path_params) = route.pattern.matches(&raw_path) else {
+                continue;
+            };
+            if best
+                .as_ref()
+                .map_or(true, |(b, _)| route.pattern.rank() < b.pattern.rank())
+            {
+                let mut routed_request = http_request.clone();
+                routed_request.path_params = path_params;
+                best = Some((route, routed_request));
+            }
+        }
+        best
+    }
+}
+
+impl AsyncHandler for AsyncRouter {
+    fn handle(&self, http_request: Arc<HttpRequest>) -> HandlerFuture {
+        let Some((route, routed_request)) = self.resolve(&http_request) else {
+            let fallback = Arc::clone(&self.fallback);
+            return Box::pin(async move {
+                tokio::task::spawn_blocking(move || {
+                    server::run_handler(fallback.as_ref(), &http_request)
+                })
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("Error handling the request: {e}");
+                    let mut builder = HttpResponse::builder();
+                    builder.with_status_code(StatusCode::InternalServerError);
+                    builder.build()
+                })
+            });
+        };
+
+        let omit_body = http_request.http_method == HttpMethod::Head;
+        let response = route.handler.handle(Arc::new(routed_request));
+        Box::pin(async move {
+            let mut http_response = response.await;
+            if omit_body {
+                http_response.omit_body = true;
+            }
+            http_response
+        })
+    }
+
+    fn reject_before_body(&self, http_request: &HttpRequest) -> Option<HttpResponse> {
+        match self.resolve(http_request) {
+            Some((route, routed_request)) => route.handler.reject_before_body(&routed_request),
+            None => self.fallback.reject_before_body(http_request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+
+    fn text(body: &str) -> HttpResponse {
+        let mut builder = HttpResponse::builder();
+        builder.with_body(body.as_bytes());
+        builder.build()
+    }
+
+    fn handle(handler: &dyn AsyncHandler, method: HttpMethod, target: &str) -> HttpResponse {
+        let http_request = Arc::new(HttpRequest::new(method, target).unwrap());
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(handler.handle(http_request))
+    }
+
+    fn router() -> AsyncRouter {
+        let mut fallback = Router::new();
+        fallback.get("/blocking", |_| text("blocking"));
+
+        let mut router = AsyncRouter::new(fallback);
+        router.get("/users/:id", |req: Arc<HttpRequest>| async move {
+            text(&format!("user {}", req.path_params.get("id").unwrap()))
+        });
+        router
+    }
+
+    #[test]
+    fn test_async_routes_then_fallback() {
+        let router = router();
+
+        let http_response = handle(&router, HttpMethod::Get, "/users/42");
+        assert_eq!(http_response.body.unwrap(), b"user 42"[..]);
+
+        let http_response = handle(&router, HttpMethod::Get, "/blocking");
+        assert_eq!(http_response.body.unwrap(), b"blocking"[..]);
+
+        // Another method on an async route's path: up to the fallback
+        let http_response = handle(&router, HttpMethod::Post, "/users/42");
+        assert!(matches!(http_response.status_code, StatusCode::NotFound));
+    }
+
+    #[test]
+    fn test_head_on_async_route() {
+        let http_response = handle(&router(), HttpMethod::Head, "/users/42");
+        assert!(http_response.omit_body);
+        assert_eq!(http_response.body.unwrap(), b"user 42"[..]);
+    }
+}
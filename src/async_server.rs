@@ -0,0 +1,391 @@
+use crate::async_handler::{AsyncHandler, AsyncRouter};
+use crate::endpoints;
+use crate::handler::Handler;
+use crate::http2;
+use crate::http_request::{HttpRequest, Limits};
+use crate::http_response::{Body, HttpResponse, StatusCode};
+use crate::middleware::Compression;
+use crate::outgoing::{to_bytes, ChannelWriter, CHANNEL_BOUND, CHUNK_SIZE};
+use crate::request_parser::RequestParser;
+use crate::router::Router;
+use crate::server;
+use crate::shutdown::Shutdown;
+
+use std::error::Error;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime;
+use tokio::sync::mpsc;
+
+// The server of the `tokio` feature: a task per connection rather than a thread, requests parsed
+// as their bytes come (`RequestParser`), and handlers that may await. Blocking work still has a
+// place: the `AsyncRouter` fallback and streamed bodies (files) run on tokio's blocking threads,
+// and so do HTTP/2 connections, served by the same code as with the threaded `Server`.
+
+/// How long a connection may sit without a byte of the next request.
+const IDLE_TIMEOUT: Duration = Duration::new(30, 0); // 30s
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// The async counterpart of `Server`, to run on a tokio runtime:
+///
+/// ```no_run
+/// use flyweight_http_server::{AsyncServer, Router};
+/// use std::path::Path;
+///
+/// let server = AsyncServer::new(&"127.0.0.1:4221".parse().unwrap(), Path::new("."), Router::new());
+/// tokio::runtime::Runtime::new().unwrap().block_on(server.run()).unwrap();
+/// ```
+pub struct AsyncServer {
+    pub address: SocketAddr,
+    /// Bounds on the requests read from clients.
+    pub limits: Limits,
+    handler: Arc<dyn AsyncHandler>,
+}
+
+impl AsyncServer {
+    /// Server answering with the routes of `router`, then with the built-in endpoints, as
+    /// `Server::new` does: all of them behind its middlewares, on tokio's blocking threads. For a
+    /// `/sleep` that awaits instead, see `AsyncRouter::built_in_endpoints`.
+    #[must_use]
+    pub fn new(address: &SocketAddr, data_dir: &Path, mut router: Router) -> Self {
+        endpoints::register(&mut router, data_dir);
+        router.wrap(Compression);
+        AsyncServer::with_handler(address, AsyncRouter::new(router))
+    }
+
+    /// Server passing every request to `handler`, without the built-in endpoints.
+    #[must_use]
+    pub fn with_handler<H: AsyncHandler + 'static>(address: &SocketAddr, handler: H) -> Self {
+        AsyncServer {
+            address: *address,
+            limits: Limits::default(),
+            handler: Arc::new(handler),
+        }
+    }
+
+    /// Start the server running, a task per connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when an incoming TCP connection can't be accepted
+    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind(self.address).await?;
+        self.serve(&listener).await
+    }
+
+    async fn serve(&self, listener: &TcpListener) -> Result<(), Box<dyn Error>> {
+        loop {
+            let (stream, _) = listener
+                .accept()
+                .await
+                .map_err(|e| format!("Error accepting the connection: {e}"))?;
+            let handler = Arc::clone(&self.handler);
+            let limits = self.limits;
+            tokio::spawn(async move {
+                match handle_stream(stream, handler, limits).await {
+                    Ok(()) => println!("Successfully handled stream"),
+                    Err(e) => eprintln!("Error handling the stream: {e}"),
+                }
+            });
+        }
+    }
+}
+
+async fn handle_stream(
+    mut stream: TcpStream,
+    handler: Arc<dyn AsyncHandler>,
+    limits: Limits,
+) -> Result<(), BoxError> {
+    println!("accepted new connection");
+    let mut parser = RequestParser::new();
+
+    loop {
+        let http_request =
+            match read_request(&mut stream, &mut parser, handler.as_ref(), &limits).await {
+                Ok(Some(http_request)) => http_request,
+                Ok(None) => return Ok(()),
+                Err(http_response) => {
+                    // Terminate the connection: what follows isn't a request boundary
+                    write_response(&mut stream, http_response).await?;
+                    return Ok(());
+                }
+            };
+
+        // Prior knowledge or `Upgrade: h2c`: the connection goes on as HTTP/2
+        if http2::switches(&http_request) {
+            println!("switching to HTTP/2");
+            return serve_http2(stream, parser, handler, limits, http_request).await;
+        }
+        println!("Parsed http-request: {http_request:?}\n");
+
+        let http_request = Arc::new(http_request);
+        let http_response = handler.handle(Arc::clone(&http_request)).await;
+        let http_response = server::finish(&http_request, http_response);
+        let keep_alive = !http_response.conn_close();
+        println!("keep-alive: {keep_alive}");
+        write_response(&mut stream, http_response).await?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the next request, parsing it as its bytes come, and sending `100 Continue` before its
+/// body when the client waits for it. `Ok(None)` when the connection closes, or stays idle past
+/// `IDLE_TIMEOUT`. `Err` is the final response to a request that doesn't get handled, as with
+/// the blocking `Server`.
+async fn read_request(
+    stream: &mut TcpStream,
+    parser: &mut RequestParser,
+    handler: &dyn AsyncHandler,
+    limits: &Limits,
+) -> Result<Option<HttpRequest>, HttpResponse> {
+    let mut buf = vec![0; 16 * 1024];
+    let mut continued = false;
+
+    loop {
+        match parser.parse(limits) {
+            Ok(Some(http_request)) => return Ok(Some(http_request)),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("error parsing the http-request: {e}");
+                return Err(HttpResponse::new_from_bad_request(&e));
+            }
+        }
+
+        if let Some(http_request) = parser.head().filter(|head| head.expects_continue()) {
+            if !continued {
+                continued = true;
+                if let Some(mut rejection) = handler.reject_before_body(http_request) {
+                    rejection.headers.insert("connection", "close");
+                    return Err(rejection);
+                }
+                let interim = to_bytes(HttpResponse::new_interim(StatusCode::Continue));
+                if let Err(e) = stream.write_all(&interim).await {
+                    eprintln!("Error handling the stream: {e}");
+                    return Ok(None);
+                }
+            }
+        }
+
+        match tokio::time::timeout(IDLE_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Err(_) => return Ok(None),
+            Ok(Ok(n)) => parser.feed(&buf[..n]),
+            Ok(Err(e)) => {
+                eprintln!("Error handling the stream: {e}");
+                return Ok(None);
+            }
+        }
+    }
+}
+
+async fn write_response(stream: &mut TcpStream, http_response: HttpResponse) -> io::Result<()> {
+    if !matches!(http_response.body, Some(Body::Stream(_))) {
+        return stream.write_all(&to_bytes(http_response)).await;
+    }
+
+    // A streamed body comes from blocking reads (a file): those happen on a blocking thread,
+    // sending the response back in chunks
+    let (sender, mut receiver) = mpsc::channel(CHANNEL_BOUND);
+    let writing = tokio::task::spawn_blocking(move || {
+        // Into the channel to the connection's task
+        let mut writer = BufWriter::with_capacity(
+            CHUNK_SIZE,
+            ChannelWriter::new(|chunk| sender.blocking_send(chunk).is_ok()),
+        );
+        http_response.write_to(&mut writer)?;
+        writer.flush()
+    });
+    while let Some(chunk) = receiver.recv().await {
+        stream.write_all(&chunk).await?;
+    }
+    writing.await.map_err(io::Error::other)?
+}
+
+/// Serves the connection as HTTP/2 on a blocking thread, the way the threaded `Server` does:
+/// each stream's handler runs to completion on its own thread.
+async fn serve_http2(
+    stream: TcpStream,
+    parser: RequestParser,
+    handler: Arc<dyn AsyncHandler>,
+    limits: Limits,
+    http_request: HttpRequest,
+) -> Result<(), BoxError> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+
+    let handler = BlockOn {
+        handler,
+        runtime: runtime::Handle::current(),
+    };
+    tokio::task::spawn_blocking(move || {
+        let mut reader = parser.into_reader(&stream);
+        // No graceful shutdown for this server: one that's never requested
+        let shutdown = Shutdown::default();
+        let tracked = shutdown.track(&stream)?;
+        http2::serve(
+            &mut reader,
+            &stream,
+            &handler,
+            &limits,
+            http_request,
+            &tracked,
+        )
+    })
+    .await??;
+    Ok(())
+}
+
+/// An `AsyncHandler` driven from a blocking thread.
+struct BlockOn {
+    handler: Arc<dyn AsyncHandler>,
+    runtime: runtime::Handle,
+}
+
+impl Handler for BlockOn {
+    fn handle(&self, http_request: &HttpRequest) -> HttpResponse {
+        let http_request = Arc::new(http_request.clone());
+        self.runtime.block_on(self.handler.handle(http_request))
+    }
+
+    fn reject_before_body(&self, http_request: &HttpRequest) -> Option<HttpResponse> {
+        self.handler.reject_before_body(http_request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_response::{Buildable, Builder};
+    use std::io::{Cursor, Read};
+    use std::time::Instant;
+
+    /// Serves `router` on a free port, on a single-threaded runtime of its own.
+    fn serve(router: AsyncRouter) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        std::thread::spawn(move || {
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let listener = TcpListener::from_std(listener).unwrap();
+                let server = AsyncServer::with_handler(&address, router);
+                let _ = server.serve(&listener).await;
+            });
+        });
+        address
+    }
+
+    fn router() -> AsyncRouter {
+        let mut fallback = Router::new();
+        fallback.get("/stream", |_| {
+            let mut builder = HttpResponse::builder();
+            builder.with_body_stream(Cursor::new(vec![b'x'; 200_000]));
+            builder.with_content_length(200_000);
+            builder.build()
+        });
+        fallback.put("/upload", |req| {
+            let mut builder = HttpResponse::builder();
+            builder.with_body(req.body.as_deref().unwrap_or_default());
+            builder.build()
+        });
+
+        let mut router = AsyncRouter::new(fallback);
+        router.get("/nap", |_| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let mut builder = HttpResponse::builder();
+            builder.with_body(b"rested");
+            builder.build()
+        });
+        router
+    }
+
+    fn exchange(address: SocketAddr, request: &[u8]) -> String {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn test_user_routes_before_built_in_endpoints() {
+        let mut router = Router::new();
+        router.get("/sleep", |_| {
+            let mut builder = HttpResponse::builder();
+            builder.with_body(b"mine");
+            builder.build()
+        });
+        let server = AsyncServer::new(&"127.0.0.1:0".parse().unwrap(), Path::new(""), router);
+
+        let http_request = HttpRequest::new(crate::HttpMethod::Get, "/sleep").unwrap();
+        let http_response = runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(server.handler.handle(Arc::new(http_request)));
+        assert_eq!(http_response.body.unwrap(), b"mine"[..]);
+    }
+
+    #[test]
+    fn test_awaiting_handlers_share_the_thread() {
+        let address = serve(router());
+
+        // One runtime thread, yet the naps overlap
+        let start = Instant::now();
+        let naps: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    exchange(address, b"GET /nap HTTP/1.1\r\nConnection: close\r\n\r\n")
+                })
+            })
+            .collect();
+        for nap in naps {
+            assert!(nap.join().unwrap().ends_with("rested"));
+        }
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_keep_alive_and_blocking_fallback() {
+        let address = serve(router());
+
+        let response = exchange(
+            address,
+            b"PUT /upload HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\
+              GET /stream HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(response.contains("\r\n\r\nabcHTTP/1.1"));
+        assert!(response.ends_with(&"x".repeat(200_000)));
+    }
+
+    #[test]
+    fn test_bad_request_and_expect_continue() {
+        let address = serve(router());
+        assert!(exchange(address, b"GET / HTTP/1.1\r\nBad header\r\n\r\n")
+            .starts_with("HTTP/1.1 400 Bad Request"));
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"PUT /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut interim = [0; 25];
+        stream.read_exact(&mut interim).unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        stream.write_all(b"ok").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("\r\n\r\nok"));
+    }
+}
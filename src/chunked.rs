@@ -0,0 +1,321 @@
+use crate::headers::Headers;
+use crate::http_request::{self, Limits};
+
+use std::fmt;
+use std::io::{BufRead, Write};
+
+// Chunked transfer-coding (RFC 9112, section 7.1):
+//
+//   chunked-body   = *chunk last-chunk trailer-section CRLF
+//   chunk          = chunk-size [ chunk-ext ] CRLF chunk-data CRLF
+//   last-chunk     = 1*("0") [ chunk-ext ] CRLF
+//   chunk-ext      = *( BWS ";" BWS chunk-ext-name [ BWS "=" BWS chunk-ext-val ] )
+
+#[derive(Debug)]
+pub struct ChunkedBody {
+    pub data: Vec<u8>,
+    pub trailers: Headers,
+}
+
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub enum ChunkedError {
+    Io(std::io::Error),
+    ChunkSize(String),
+    ChunkExtension(String),
+    MissingCrlf,
+    Trailer(String),
+    LineTooLong,
+    TooLarge,
+    TrailersTooLarge,
+}
+
+impl From<std::io::Error> for ChunkedError {
+    fn from(e: std::io::Error) -> ChunkedError {
+        ChunkedError::Io(e)
+    }
+}
+
+impl fmt::Display for ChunkedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkedError::Io(e) => write!(f, "I/O while reading chunked body: {e}"),
+            ChunkedError::ChunkSize(s) => write!(f, "invalid chunk size: {s}"),
+            ChunkedError::ChunkExtension(e) => write!(f, "invalid chunk extension: {e}"),
+            ChunkedError::MissingCrlf => write!(f, "chunk data not terminated by CRLF"),
+            ChunkedError::Trailer(t) => write!(f, "invalid trailer field: {t}"),
+            ChunkedError::LineTooLong => write!(f, "chunk-size line too long"),
+            ChunkedError::TooLarge => write!(f, "chunked body too large"),
+            ChunkedError::TrailersTooLarge => write!(f, "too many or too large trailer fields"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkedError {}
+
+/// Decodes a whole chunked body from the reader, returning the concatenated chunk data and the
+/// trailer fields (names lower-cased, like request headers).
+///
+/// The data is bounded by `limits.max_body`, the trailer section like a header section.
+/// # Errors
+/// Returns a `ChunkedError` if the body is not valid chunked framing or goes over the limits.
+pub fn read_chunked_body<R: BufRead>(
+    reader: &mut R,
+    limits: &Limits,
+) -> Result<ChunkedBody, ChunkedError> {
+    let mut data = Vec::new();
+
+    loop {
+        let line = read_crlf_line(reader, limits.max_header_bytes)?;
+        let chunk_size = parse_chunk_size_line(&line)?;
+
+        if chunk_size == 0 {
+            break; // last-chunk
+        }
+        if chunk_size > limits.max_body - data.len() {
+            return Err(ChunkedError::TooLarge);
+        }
+
+        let start = data.len();
+        data.resize(start + chunk_size, 0);
+        reader.read_exact(&mut data[start..])?;
+
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(ChunkedError::MissingCrlf);
+        }
+    }
+
+    // Trailer section, terminated by an empty line
+    let mut trailers = Headers::new();
+    let mut trailer_bytes = 0;
+    loop {
+        let line = match read_crlf_line(
+            reader,
+            limits.max_header_bytes.saturating_sub(trailer_bytes),
+        ) {
+            Err(ChunkedError::LineTooLong) => return Err(ChunkedError::TrailersTooLarge),
+            line => line?,
+        };
+        trailer_bytes += line.len() + 2;
+        if line.is_empty() {
+            break;
+        }
+        if trailers.len() == limits.max_headers {
+            return Err(ChunkedError::TrailersTooLarge);
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| ChunkedError::Trailer(line.clone()))?;
+        let name = name.trim();
+        if name.is_empty() || !name.bytes().all(is_tchar) {
+            return Err(ChunkedError::Trailer(line.clone()));
+        }
+        trailers.append(name, value);
+    }
+
+    Ok(ChunkedBody { data, trailers })
+}
+
+/// Reads one line and strips its line terminator. Hitting EOF before the end of line is an error:
+/// a chunked body always ends with an empty line.
+fn read_crlf_line<R: BufRead>(reader: &mut R, max_len: usize) -> Result<String, ChunkedError> {
+    let mut line = String::new();
+    if !http_request::read_line_within(reader, &mut line, max_len)? {
+        return Err(ChunkedError::LineTooLong);
+    }
+    if !line.ends_with('\n') {
+        return Err(ChunkedError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    line.pop();
+    if line.ends_with('\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+fn parse_chunk_size_line(line: &str) -> Result<usize, ChunkedError> {
+    let (size, extensions) = match line.split_once(';') {
+        Some((size, extensions)) => (size.trim_end(), Some(extensions)),
+        None => (line, None),
+    };
+
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(ChunkedError::ChunkSize(line.into()));
+    }
+    let chunk_size =
+        usize::from_str_radix(size, 16).map_err(|_| ChunkedError::ChunkSize(line.into()))?;
+
+    // Extensions carry no meaning for us, but they still have to be well-formed
+    if let Some(extensions) = extensions {
+        validate_chunk_extensions(extensions)?;
+    }
+
+    Ok(chunk_size)
+}
+
+fn validate_chunk_extensions(extensions: &str) -> Result<(), ChunkedError> {
+    let invalid = || ChunkedError::ChunkExtension(extensions.into());
+
+    let mut rest = extensions;
+    loop {
+        // chunk-ext-name
+        rest = rest.trim_start();
+        let name_len = rest.bytes().take_while(|b| is_tchar(*b)).count();
+        if name_len == 0 {
+            return Err(invalid());
+        }
+        rest = rest[name_len..].trim_start();
+
+        // [ "=" chunk-ext-val ], the value being a token or a quoted-string
+        if let Some(value) = rest.strip_prefix('=') {
+            let value = value.trim_start();
+            let value_len = if value.starts_with('"') {
+                quoted_string_len(value).ok_or_else(invalid)?
+            } else {
+                value.bytes().take_while(|b| is_tchar(*b)).count()
+            };
+            if value_len == 0 {
+                return Err(invalid());
+            }
+            rest = value[value_len..].trim_start();
+        }
+
+        match rest.strip_prefix(';') {
+            Some(next) => rest = next,
+            None if rest.is_empty() => return Ok(()),
+            None => return Err(invalid()),
+        }
+    }
+}
+
+/// Length in bytes of the quoted-string at the start of `s` (quotes included).
+fn quoted_string_len(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, b) in s.bytes().enumerate().skip(1) {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'"' => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Whether the byte may appear in a token (RFC 9110, section 5.6.2).
+pub fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Encodes everything written to it as chunks. Each `write` call becomes one chunk, so wrap the
+/// source in a reasonably sized buffer (`io::copy` uses 8 KiB) to avoid tiny chunks.
+#[allow(clippy::module_name_repetitions)]
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter { inner }
+    }
+
+    /// Writes the last-chunk and the trailer section, then hands back the inner writer.
+    /// # Errors
+    /// Propagates errors from the inner writer.
+    pub fn finish(mut self, trailers: &Headers) -> std::io::Result<W> {
+        write!(self.inner, "0\r\n{trailers}\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // An empty chunk would read as the last-chunk
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        write!(self.inner, "\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_decode_simple_chunks() {
+        let mut reader = Cursor::new("4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n");
+        let body = read_chunked_body(&mut reader, &Limits::default()).unwrap();
+
+        assert_eq!(body.data, b"Wikipedia");
+        assert!(body.trailers.is_empty());
+    }
+
+    #[test]
+    fn test_decode_extensions_and_trailers() {
+        let mut reader = Cursor::new(
+            "A;name=value;flag\r\n0123456789\r\n3 ; q=\"a;b\"\r\nabc\r\n0\r\nExpires: never\r\nX-Checksum: 42\r\n\r\nGET",
+        );
+        let body = read_chunked_body(&mut reader, &Limits::default()).unwrap();
+
+        assert_eq!(body.data, b"0123456789abc");
+        assert_eq!(body.trailers.get("expires").unwrap(), "never");
+        assert_eq!(body.trailers.get("x-checksum").unwrap(), "42");
+
+        // Bytes following the chunked body are left for the next request
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "GET");
+    }
+
+    #[test]
+    fn test_encode_then_decode() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"Wiki").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"pedia").unwrap();
+        let mut trailers = Headers::new();
+        trailers.append("Expires", "never");
+        let encoded = writer.finish(&trailers).unwrap();
+
+        assert_eq!(
+            encoded,
+            b"4\r\nWiki\r\n5\r\npedia\r\n0\r\nexpires: never\r\n\r\n"
+        );
+
+        let body = read_chunked_body(&mut Cursor::new(encoded), &Limits::default()).unwrap();
+        assert_eq!(body.data, b"Wikipedia");
+        assert_eq!(body.trailers.get("expires").unwrap(), "never");
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_bodies() {
+        let malformed = [
+            "z\r\nabc\r\n0\r\n\r\n",              // not hex
+            "-1\r\nabc\r\n0\r\n\r\n",             // sign
+            "3\r\nabcd\r\n0\r\n\r\n",             // data longer than announced
+            "3;=x\r\nabc\r\n0\r\n\r\n",           // extension without a name
+            "3;a=\"x\r\nabc\r\n0\r\n\r\n",        // unterminated quoted-string
+            "3\r\nabc\r\n0\r\nno-colon\r\n\r\n",  // bad trailer
+            "3\r\nabc\r\n",                       // truncated
+            "ffffffffffffffffff\r\nabc\r\n0\r\n", // overflow
+        ];
+        for body in malformed {
+            assert!(
+                read_chunked_body(&mut Cursor::new(body), &Limits::default()).is_err(),
+                "{body:?} should be rejected"
+            );
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use crate::headers::Headers;
+use crate::http_date;
+use crate::http_request::HttpMethod;
+
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Conditional requests (RFC 9110, section 13). Preconditions are evaluated in the order of
+// section 13.2.2:
+//
+//   1. If-Match            (else 2. If-Unmodified-Since)  -> 412 when false
+//   3. If-None-Match       -> 304 for GET/HEAD, 412 otherwise, when false
+//   4. If-Modified-Since   (GET/HEAD, only without If-None-Match) -> 304 when false
+//
+// If-Range is evaluated with the `Range` header itself.
+
+/// Validators of a file representation.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    /// Strong entity-tag derived from the file size and modification time.
+    pub etag: String,
+    /// Modification time, truncated to the second like any HTTP-date.
+    pub last_modified: SystemTime,
+}
+
+impl Validators {
+    pub fn from_metadata(metadata: &fs::Metadata) -> Option<Validators> {
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+        Some(Validators {
+            etag: format!(
+                "\"{:x}-{:x}.{:x}\"",
+                metadata.len(),
+                modified.as_secs(),
+                modified.subsec_nanos()
+            ),
+            last_modified: UNIX_EPOCH + Duration::from_secs(modified.as_secs()),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Precondition {
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+/// Evaluates the request preconditions against the target's current validators (`None` when the
+/// target does not exist).
+pub fn evaluate(
+    headers: &Headers,
+    method: &HttpMethod,
+    validators: Option<&Validators>,
+) -> Precondition {
+    let is_get = matches!(method, HttpMethod::Get | HttpMethod::Head);
+
+    // Entity-tag lists may be split over several fields
+    if let Some(if_match) = headers.get_joined("if-match") {
+        let matches = validators.is_some_and(|v| etag_list_matches(&if_match, &v.etag, true));
+        if !matches {
+            return Precondition::Failed;
+        }
+    } else if let Some(if_unmodified_since) = headers.get("if-unmodified-since") {
+        // An invalid date means the header is ignored
+        if let (Some(date), Some(v)) = (http_date::parse(if_unmodified_since), validators) {
+            if v.last_modified > date {
+                return Precondition::Failed;
+            }
+        }
+    }
+
+    if let Some(if_none_match) = headers.get_joined("if-none-match") {
+        let matches = validators.is_some_and(|v| etag_list_matches(&if_none_match, &v.etag, false));
+        if matches {
+            return if is_get {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if let Some(if_modified_since) = headers.get("if-modified-since") {
+        if let (true, Some(date), Some(v)) =
+            (is_get, http_date::parse(if_modified_since), validators)
+        {
+            if v.last_modified <= date {
+                return Precondition::NotModified;
+            }
+        }
+    }
+
+    Precondition::Proceed
+}
+
+/// Whether an entity-tag (or `*`) in the comma-separated list matches `etag`. Strong comparison
+/// requires both tags to be strong; weak comparison ignores the `W/` prefix.
+pub fn etag_list_matches(list: &str, etag: &str, strong: bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+
+    let (etag_is_weak, etag) = match etag.strip_prefix("W/") {
+        Some(opaque) => (true, opaque),
+        None => (false, etag),
+    };
+    if strong && etag_is_weak {
+        return false;
+    }
+
+    let mut rest = list;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return false;
+        }
+
+        let (candidate_is_weak, candidate) = match rest.strip_prefix("W/") {
+            Some(opaque) => (true, opaque),
+            None => (false, rest),
+        };
+        // opaque-tag = DQUOTE *etagc DQUOTE, where etagc excludes DQUOTE (but not ',')
+        let Some(end) = candidate
+            .strip_prefix('"')
+            .and_then(|tag| tag.find('"'))
+            .map(|i| i + 2)
+        else {
+            return false; // malformed list
+        };
+
+        if candidate[..end] == *etag && !(strong && candidate_is_weak) {
+            return true;
+        }
+        rest = &candidate[end..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validators() -> Validators {
+        Validators {
+            etag: "\"abc-1\"".to_string(),
+            last_modified: UNIX_EPOCH + Duration::from_secs(784_111_777), // Sun, 06 Nov 1994 08:49:37 GMT
+        }
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::new();
+        for (name, value) in pairs {
+            headers.append(name, value);
+        }
+        headers
+    }
+
+    #[test]
+    fn test_etag_list_matching() {
+        assert!(etag_list_matches("\"abc-1\"", "\"abc-1\"", true));
+        assert!(etag_list_matches("\"x\", \"abc-1\"", "\"abc-1\"", true));
+        assert!(etag_list_matches("\"a,b\", \"abc-1\"", "\"abc-1\"", true));
+        assert!(etag_list_matches("*", "\"abc-1\"", true));
+        assert!(etag_list_matches("W/\"abc-1\"", "\"abc-1\"", false));
+        assert!(!etag_list_matches("W/\"abc-1\"", "\"abc-1\"", true));
+        assert!(!etag_list_matches("\"abc-2\"", "\"abc-1\"", false));
+        assert!(!etag_list_matches("abc-1", "\"abc-1\"", false));
+    }
+
+    #[test]
+    fn test_conditional_get() {
+        let v = validators();
+        let get = |pairs: &[(&str, &str)]| evaluate(&headers(pairs), &HttpMethod::Get, Some(&v));
+
+        assert_eq!(get(&[]), Precondition::Proceed);
+        assert_eq!(
+            get(&[("if-none-match", "\"abc-1\"")]),
+            Precondition::NotModified
+        );
+        assert_eq!(get(&[("if-none-match", "\"old\"")]), Precondition::Proceed);
+        // The list may span several fields
+        assert_eq!(
+            get(&[("if-none-match", "\"old\""), ("If-None-Match", "\"abc-1\"")]),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            get(&[("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            get(&[("if-modified-since", "Sat, 05 Nov 1994 08:49:37 GMT")]),
+            Precondition::Proceed
+        );
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(
+            get(&[
+                ("if-none-match", "\"old\""),
+                ("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")
+            ]),
+            Precondition::Proceed
+        );
+        assert_eq!(
+            get(&[("if-modified-since", "not a date")]),
+            Precondition::Proceed
+        );
+    }
+
+    #[test]
+    fn test_conditional_upload() {
+        let v = validators();
+        let post = |pairs: &[(&str, &str)], validators: Option<&Validators>| {
+            evaluate(&headers(pairs), &HttpMethod::Post, validators)
+        };
+
+        assert_eq!(
+            post(&[("if-match", "\"abc-1\"")], Some(&v)),
+            Precondition::Proceed
+        );
+        assert_eq!(
+            post(&[("if-match", "\"old\"")], Some(&v)),
+            Precondition::Failed
+        );
+        assert_eq!(post(&[("if-match", "*")], None), Precondition::Failed);
+        assert_eq!(
+            post(
+                &[("if-unmodified-since", "Sat, 05 Nov 1994 08:49:37 GMT")],
+                Some(&v)
+            ),
+            Precondition::Failed
+        );
+        assert_eq!(
+            post(
+                &[("if-unmodified-since", "Sun, 06 Nov 1994 08:49:37 GMT")],
+                Some(&v)
+            ),
+            Precondition::Proceed
+        );
+        // Create-only upload
+        assert_eq!(
+            post(&[("if-none-match", "*")], Some(&v)),
+            Precondition::Failed
+        );
+        assert_eq!(post(&[("if-none-match", "*")], None), Precondition::Proceed);
+    }
+}
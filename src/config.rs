@@ -1,15 +1,29 @@
+use crate::http_request::Limits;
+use crate::server::{ConnectionLimits, Engine, EngineParseError};
+use crate::thread_pool::{PoolSizing, QueuePolicy, QueuePolicyParseError, DEFAULT_QUEUE_CAPACITY};
+
 use std::{
     fmt, fs,
     net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr},
     num::ParseIntError,
     path::PathBuf,
+    time::Duration,
 };
 
 #[derive(Debug)]
 pub struct Config {
     pub server_addr: SocketAddr,
+    /// Workers at start.
     pub pool_size: usize,
+    /// Bounds within which the pool grows and shrinks: `pool_size` for both, unless set.
+    pub pool_sizing: PoolSizing,
+    pub queue_capacity: usize,
+    pub queue_policy: QueuePolicy,
     pub data_dir: PathBuf, // PathBuf vs Path
+    pub limits: Limits,
+    pub connection_limits: ConnectionLimits,
+    pub engine: Engine,
+    pub shutdown_timeout: Duration,
 }
 
 #[allow(clippy::module_name_repetitions)]
@@ -22,6 +36,11 @@ pub enum ConfigError {
     DataDirIoError(std::io::Error),
     UnknownFlag(String),
     MissingValue(&'static str),
+    LimitZero(&'static str),
+    LimitParseError(&'static str, ParseIntError),
+    UnknownEngine(String),
+    UnknownQueuePolicy(String),
+    DurationParseError(&'static str, ParseIntError),
 }
 
 impl From<ParseIntError> for ConfigError {
@@ -35,6 +54,18 @@ impl From<AddrParseError> for ConfigError {
     }
 }
 
+impl From<EngineParseError> for ConfigError {
+    fn from(e: EngineParseError) -> ConfigError {
+        ConfigError::UnknownEngine(e.found)
+    }
+}
+
+impl From<QueuePolicyParseError> for ConfigError {
+    fn from(e: QueuePolicyParseError) -> ConfigError {
+        ConfigError::UnknownQueuePolicy(e.found)
+    }
+}
+
 impl From<std::io::Error> for ConfigError {
     fn from(e: std::io::Error) -> ConfigError {
         ConfigError::DataDirIoError(e)
@@ -52,7 +83,58 @@ impl std::error::Error for ConfigError {} // empty impl ?!
 pub struct Builder {
     server_addr: Option<SocketAddr>,
     pool_size: Option<usize>,
+    pool_min: Option<usize>,
+    pool_max: Option<usize>,
+    pool_grow_after: Option<Duration>,
+    pool_keep_alive: Option<Duration>,
+    queue_capacity: Option<usize>,
+    queue_policy: Option<QueuePolicy>,
     data_dir: Option<PathBuf>,
+    max_request_line: Option<usize>,
+    max_headers: Option<usize>,
+    max_header_bytes: Option<usize>,
+    max_body: Option<usize>,
+    header_timeout: Option<Duration>,
+    body_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_requests_per_connection: Option<usize>,
+    max_connections: Option<usize>,
+    engine: Option<Engine>,
+    shutdown_timeout: Option<Duration>,
+}
+
+/// Parses a duration in whole seconds, 0 included. `name` is the setting, for errors.
+fn parse_seconds(name: &'static str, value: &str) -> Result<Duration, ConfigError> {
+    match value.parse::<u64>() {
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(e) => Err(ConfigError::DurationParseError(name, e)),
+    }
+}
+
+/// Parses a timeout in whole seconds, which must be positive. `name` is the setting, for errors.
+fn parse_timeout(name: &'static str, value: &str) -> Result<Duration, ConfigError> {
+    match parse_seconds(name, value)? {
+        Duration::ZERO => Err(ConfigError::LimitZero(name)),
+        timeout => Ok(timeout),
+    }
+}
+
+/// Parses a duration in whole milliseconds, 0 included. `name` is the setting, for errors.
+fn parse_millis(name: &'static str, value: &str) -> Result<Duration, ConfigError> {
+    match value.parse::<u64>() {
+        Ok(millis) => Ok(Duration::from_millis(millis)),
+        Err(e) => Err(ConfigError::DurationParseError(name, e)),
+    }
+}
+
+/// Parses a size limit, which must be positive. `name` is the setting, for errors.
+fn parse_limit(name: &'static str, value: &str) -> Result<usize, ConfigError> {
+    match value.parse::<usize>() {
+        Ok(0) => Err(ConfigError::LimitZero(name)),
+        Ok(limit) => Ok(limit),
+        Err(e) => Err(ConfigError::LimitParseError(name, e)),
+    }
 }
 
 impl Builder {
@@ -60,7 +142,25 @@ impl Builder {
         Builder {
             server_addr: None,
             pool_size: None,
+            pool_min: None,
+            pool_max: None,
+            pool_grow_after: None,
+            pool_keep_alive: None,
+            queue_capacity: None,
+            queue_policy: None,
             data_dir: None,
+            max_request_line: None,
+            max_headers: None,
+            max_header_bytes: None,
+            max_body: None,
+            header_timeout: None,
+            body_timeout: None,
+            idle_timeout: None,
+            write_timeout: None,
+            max_requests_per_connection: None,
+            max_connections: None,
+            engine: None,
+            shutdown_timeout: None,
         }
     }
 
@@ -68,10 +168,59 @@ impl Builder {
     pub fn build(self) -> Config {
         let default_socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4221);
         let default_data_dir = PathBuf::from("."); // PathBuf::from("data")
+        let default_limits = Limits::default();
+        let default_connection_limits = ConnectionLimits::default();
+        let pool_size = self.pool_size.unwrap_or(10);
+        let pool_min = self.pool_min.unwrap_or(pool_size);
+        let mut pool_max = self.pool_max.unwrap_or(pool_size.max(pool_min));
+        if pool_max < pool_min {
+            eprintln!("Warning: pool max {pool_max} below pool min {pool_min}: raised to it");
+            pool_max = pool_min;
+        }
         Config {
             server_addr: self.server_addr.unwrap_or(default_socket),
-            pool_size: self.pool_size.unwrap_or(10),
+            pool_size,
+            pool_sizing: PoolSizing {
+                min: pool_min,
+                max: pool_max,
+                grow_after: self.pool_grow_after.unwrap_or(PoolSizing::GROW_AFTER),
+                keep_alive: self.pool_keep_alive.unwrap_or(PoolSizing::KEEP_ALIVE),
+            },
+            queue_capacity: self.queue_capacity.unwrap_or(DEFAULT_QUEUE_CAPACITY),
+            queue_policy: self.queue_policy.unwrap_or_default(),
             data_dir: self.data_dir.unwrap_or(default_data_dir),
+            limits: Limits {
+                max_request_line: self
+                    .max_request_line
+                    .unwrap_or(default_limits.max_request_line),
+                max_headers: self.max_headers.unwrap_or(default_limits.max_headers),
+                max_header_bytes: self
+                    .max_header_bytes
+                    .unwrap_or(default_limits.max_header_bytes),
+                max_body: self.max_body.unwrap_or(default_limits.max_body),
+            },
+            connection_limits: ConnectionLimits {
+                header_timeout: self
+                    .header_timeout
+                    .unwrap_or(default_connection_limits.header_timeout),
+                body_timeout: self
+                    .body_timeout
+                    .unwrap_or(default_connection_limits.body_timeout),
+                idle_timeout: self
+                    .idle_timeout
+                    .unwrap_or(default_connection_limits.idle_timeout),
+                write_timeout: self
+                    .write_timeout
+                    .unwrap_or(default_connection_limits.write_timeout),
+                max_requests: self
+                    .max_requests_per_connection
+                    .unwrap_or(default_connection_limits.max_requests),
+                max_connections: self
+                    .max_connections
+                    .unwrap_or(default_connection_limits.max_connections),
+            },
+            engine: self.engine.unwrap_or_default(),
+            shutdown_timeout: self.shutdown_timeout.unwrap_or(Duration::from_secs(30)),
         }
     }
 
@@ -105,6 +254,39 @@ impl Builder {
 
                     builder.pool_size = Some(size);
                 }
+                "--pool-min" => {
+                    let value = iter.next().ok_or(ConfigError::MissingValue("--pool-min"))?;
+                    builder.pool_min = Some(parse_limit("--pool-min", value)?);
+                }
+                "--pool-max" => {
+                    let value = iter.next().ok_or(ConfigError::MissingValue("--pool-max"))?;
+                    builder.pool_max = Some(parse_limit("--pool-max", value)?);
+                }
+                "--pool-grow-after-ms" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--pool-grow-after-ms"))?;
+                    builder.pool_grow_after = Some(parse_millis("--pool-grow-after-ms", value)?);
+                }
+                "--pool-keep-alive" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--pool-keep-alive"))?;
+                    builder.pool_keep_alive = Some(parse_seconds("--pool-keep-alive", value)?);
+                }
+                "--queue-capacity" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--queue-capacity"))?;
+                    builder.queue_capacity = Some(parse_limit("--queue-capacity", value)?);
+                }
+                "--queue-policy" => {
+                    let policy = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--queue-policy"))?
+                        .parse::<QueuePolicy>()?;
+                    builder.queue_policy = Some(policy);
+                }
                 "--data-dir" | "--directory" | "-d" => {
                     let dir_path = iter.next().ok_or(ConfigError::MissingValue("--data-dir"))?;
                     let dir_path = fs::canonicalize(dir_path)?; // no need for mut ?! for
@@ -115,6 +297,78 @@ impl Builder {
                         Err(e) => return Err(ConfigError::DataDirIoError(e)),
                     }
                 }
+                "--max-request-line" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--max-request-line"))?;
+                    builder.max_request_line = Some(parse_limit("--max-request-line", value)?);
+                }
+                "--max-headers" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--max-headers"))?;
+                    builder.max_headers = Some(parse_limit("--max-headers", value)?);
+                }
+                "--max-header-bytes" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--max-header-bytes"))?;
+                    builder.max_header_bytes = Some(parse_limit("--max-header-bytes", value)?);
+                }
+                "--max-body" => {
+                    let value = iter.next().ok_or(ConfigError::MissingValue("--max-body"))?;
+                    builder.max_body = Some(parse_limit("--max-body", value)?);
+                }
+                "--header-timeout" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--header-timeout"))?;
+                    builder.header_timeout = Some(parse_timeout("--header-timeout", value)?);
+                }
+                "--body-timeout" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--body-timeout"))?;
+                    builder.body_timeout = Some(parse_timeout("--body-timeout", value)?);
+                }
+                "--idle-timeout" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--idle-timeout"))?;
+                    builder.idle_timeout = Some(parse_timeout("--idle-timeout", value)?);
+                }
+                "--write-timeout" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--write-timeout"))?;
+                    builder.write_timeout = Some(parse_timeout("--write-timeout", value)?);
+                }
+                "--max-requests-per-connection" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--max-requests-per-connection"))?;
+                    builder.max_requests_per_connection =
+                        Some(parse_limit("--max-requests-per-connection", value)?);
+                }
+                "--max-connections" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--max-connections"))?;
+                    builder.max_connections = Some(parse_limit("--max-connections", value)?);
+                }
+                "--engine" => {
+                    let engine = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--engine"))?
+                        .parse::<Engine>()?;
+                    builder.engine = Some(engine);
+                }
+                "--shutdown-timeout" => {
+                    let value = iter
+                        .next()
+                        .ok_or(ConfigError::MissingValue("--shutdown-timeout"))?;
+                    builder.shutdown_timeout = Some(parse_seconds("--shutdown-timeout", value)?);
+                }
                 _ => {
                     return Err(ConfigError::UnknownFlag(format!(
                         "Unknown CLI argument flag: {arg}"
@@ -147,6 +401,24 @@ impl Builder {
             }
             builder.pool_size = Some(size);
         }
+        if let Ok(val) = std::env::var("POOL_MIN") {
+            builder.pool_min = Some(parse_limit("POOL_MIN", &val)?);
+        }
+        if let Ok(val) = std::env::var("POOL_MAX") {
+            builder.pool_max = Some(parse_limit("POOL_MAX", &val)?);
+        }
+        if let Ok(val) = std::env::var("POOL_GROW_AFTER_MS") {
+            builder.pool_grow_after = Some(parse_millis("POOL_GROW_AFTER_MS", &val)?);
+        }
+        if let Ok(val) = std::env::var("POOL_KEEP_ALIVE") {
+            builder.pool_keep_alive = Some(parse_seconds("POOL_KEEP_ALIVE", &val)?);
+        }
+        if let Ok(val) = std::env::var("QUEUE_CAPACITY") {
+            builder.queue_capacity = Some(parse_limit("QUEUE_CAPACITY", &val)?);
+        }
+        if let Ok(val) = std::env::var("QUEUE_POLICY") {
+            builder.queue_policy = Some(val.parse::<QueuePolicy>()?);
+        }
         if let Ok(val) = std::env::var("DATA_DIR") {
             let dir_path = fs::canonicalize(val)?; // no need for mut ?! for
                                                    // shadowing here ?
@@ -156,6 +428,43 @@ impl Builder {
                 Err(e) => return Err(ConfigError::DataDirIoError(e)),
             }
         }
+        if let Ok(val) = std::env::var("MAX_REQUEST_LINE") {
+            builder.max_request_line = Some(parse_limit("MAX_REQUEST_LINE", &val)?);
+        }
+        if let Ok(val) = std::env::var("MAX_HEADERS") {
+            builder.max_headers = Some(parse_limit("MAX_HEADERS", &val)?);
+        }
+        if let Ok(val) = std::env::var("MAX_HEADER_BYTES") {
+            builder.max_header_bytes = Some(parse_limit("MAX_HEADER_BYTES", &val)?);
+        }
+        if let Ok(val) = std::env::var("MAX_BODY") {
+            builder.max_body = Some(parse_limit("MAX_BODY", &val)?);
+        }
+        if let Ok(val) = std::env::var("HEADER_TIMEOUT") {
+            builder.header_timeout = Some(parse_timeout("HEADER_TIMEOUT", &val)?);
+        }
+        if let Ok(val) = std::env::var("BODY_TIMEOUT") {
+            builder.body_timeout = Some(parse_timeout("BODY_TIMEOUT", &val)?);
+        }
+        if let Ok(val) = std::env::var("IDLE_TIMEOUT") {
+            builder.idle_timeout = Some(parse_timeout("IDLE_TIMEOUT", &val)?);
+        }
+        if let Ok(val) = std::env::var("WRITE_TIMEOUT") {
+            builder.write_timeout = Some(parse_timeout("WRITE_TIMEOUT", &val)?);
+        }
+        if let Ok(val) = std::env::var("MAX_REQUESTS_PER_CONNECTION") {
+            builder.max_requests_per_connection =
+                Some(parse_limit("MAX_REQUESTS_PER_CONNECTION", &val)?);
+        }
+        if let Ok(val) = std::env::var("MAX_CONNECTIONS") {
+            builder.max_connections = Some(parse_limit("MAX_CONNECTIONS", &val)?);
+        }
+        if let Ok(val) = std::env::var("ENGINE") {
+            builder.engine = Some(val.parse::<Engine>()?);
+        }
+        if let Ok(val) = std::env::var("SHUTDOWN_TIMEOUT") {
+            builder.shutdown_timeout = Some(parse_seconds("SHUTDOWN_TIMEOUT", &val)?);
+        }
 
         Ok(builder)
     }
@@ -207,6 +516,24 @@ impl Builder {
                 Err(e) => return Err(ConfigError::DataDirIoError(e)),
             }}
 ,
+                        "max_request_line" => builder.max_request_line = Some(parse_limit("max_request_line", cfg_value)?),
+                        "max_headers" => builder.max_headers = Some(parse_limit("max_headers", cfg_value)?),
+                        "max_header_bytes" => builder.max_header_bytes = Some(parse_limit("max_header_bytes", cfg_value)?),
+                        "max_body" => builder.max_body = Some(parse_limit("max_body", cfg_value)?),
+                        "header_timeout" => builder.header_timeout = Some(parse_timeout("header_timeout", cfg_value)?),
+                        "body_timeout" => builder.body_timeout = Some(parse_timeout("body_timeout", cfg_value)?),
+                        "idle_timeout" => builder.idle_timeout = Some(parse_timeout("idle_timeout", cfg_value)?),
+                        "write_timeout" => builder.write_timeout = Some(parse_timeout("write_timeout", cfg_value)?),
+                        "max_requests_per_connection" => builder.max_requests_per_connection = Some(parse_limit("max_requests_per_connection", cfg_value)?),
+                        "max_connections" => builder.max_connections = Some(parse_limit("max_connections", cfg_value)?),
+                        "pool_min" => builder.pool_min = Some(parse_limit("pool_min", cfg_value)?),
+                        "pool_max" => builder.pool_max = Some(parse_limit("pool_max", cfg_value)?),
+                        "pool_grow_after_ms" => builder.pool_grow_after = Some(parse_millis("pool_grow_after_ms", cfg_value)?),
+                        "pool_keep_alive" => builder.pool_keep_alive = Some(parse_seconds("pool_keep_alive", cfg_value)?),
+                        "queue_capacity" => builder.queue_capacity = Some(parse_limit("queue_capacity", cfg_value)?),
+                        "queue_policy" => builder.queue_policy = Some(cfg_value.parse::<QueuePolicy>()?),
+                        "engine" => builder.engine = Some(cfg_value.parse::<Engine>()?),
+                        "shutdown_timeout" => builder.shutdown_timeout = Some(parse_seconds("shutdown_timeout", cfg_value)?),
                         _ => eprintln!("Warning: unknown key-value pair found in con)fig file [server] section: {cfg_key} = {cfg_value}"),
                     }
                 }
@@ -221,7 +548,27 @@ impl Builder {
         Builder {
             server_addr: self.server_addr.or(other.server_addr),
             pool_size: self.pool_size.or(other.pool_size), // NOTE: usize is Copy, no clone needed
+            pool_min: self.pool_min.or(other.pool_min),
+            pool_max: self.pool_max.or(other.pool_max),
+            pool_grow_after: self.pool_grow_after.or(other.pool_grow_after),
+            pool_keep_alive: self.pool_keep_alive.or(other.pool_keep_alive),
+            queue_capacity: self.queue_capacity.or(other.queue_capacity),
+            queue_policy: self.queue_policy.or(other.queue_policy),
             data_dir: self.data_dir.clone().or(other.data_dir.clone()),
+            max_request_line: self.max_request_line.or(other.max_request_line),
+            max_headers: self.max_headers.or(other.max_headers),
+            max_header_bytes: self.max_header_bytes.or(other.max_header_bytes),
+            max_body: self.max_body.or(other.max_body),
+            header_timeout: self.header_timeout.or(other.header_timeout),
+            body_timeout: self.body_timeout.or(other.body_timeout),
+            idle_timeout: self.idle_timeout.or(other.idle_timeout),
+            write_timeout: self.write_timeout.or(other.write_timeout),
+            max_requests_per_connection: self
+                .max_requests_per_connection
+                .or(other.max_requests_per_connection),
+            max_connections: self.max_connections.or(other.max_connections),
+            engine: self.engine.or(other.engine),
+            shutdown_timeout: self.shutdown_timeout.or(other.shutdown_timeout),
         }
     }
 }
@@ -39,10 +39,31 @@ impl ContentEncoding {
             ContentEncoding::GZip => gzip_encode_body(body),
         }
     }
-    pub fn from_header(hdr_val: &str) -> Option<ContentEncoding> {
-        // In the header: either a single scheme or a list of schemes
-        hdr_val
-            .split(',')
+    /// Encodes everything read from `reader` into `writer`, without buffering the whole body.
+    /// # Errors
+    /// Propagates I/O errors from both ends.
+    pub fn encode_stream<R: Read + ?Sized, W: Write>(
+        self,
+        reader: &mut R,
+        writer: W,
+    ) -> Result<W, std::io::Error> {
+        match self {
+            ContentEncoding::GZip => {
+                let mut encoder = GzEncoder::new(writer, Compression::default());
+                std::io::copy(reader, &mut encoder)?;
+                encoder.finish()
+            }
+        }
+    }
+    /// The coding to use given the values of every `Accept-Encoding` field of a request.
+    pub fn from_header<'a, I>(hdr_vals: I) -> Option<ContentEncoding>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        // In each field: either a single scheme or a list of schemes
+        hdr_vals
+            .into_iter()
+            .flat_map(|hdr_val| hdr_val.split(','))
             .map(str::trim)
             .filter_map(|s| s.parse::<ContentEncoding>().ok())
             .find(|encoding| *encoding == ContentEncoding::GZip)
@@ -71,23 +92,30 @@ mod tests {
     #[test]
     fn test_encoding_parsing_from_header_value() {
         assert_eq!(
-            ContentEncoding::from_header("gzip").unwrap(),
+            ContentEncoding::from_header(["gzip"]).unwrap(),
             ContentEncoding::GZip
         );
 
         assert_eq!(
-            ContentEncoding::from_header("deflate, gzip").unwrap(),
+            ContentEncoding::from_header(["deflate, gzip"]).unwrap(),
             ContentEncoding::GZip
         );
 
         assert_eq!(
-            ContentEncoding::from_header("br, lorem, gzip, ipsum").unwrap(),
+            ContentEncoding::from_header(["br, lorem, gzip, ipsum"]).unwrap(),
             ContentEncoding::GZip
         );
 
-        assert!(ContentEncoding::from_header("br, lorem, ipsum").is_none());
+        assert!(ContentEncoding::from_header(["br, lorem, ipsum"]).is_none());
         assert!("".parse::<ContentEncoding>().is_err());
 
-        assert!(ContentEncoding::from_header("deflate").is_none());
+        assert!(ContentEncoding::from_header(["deflate"]).is_none());
+
+        // Repeated `Accept-Encoding` fields
+        assert_eq!(
+            ContentEncoding::from_header(["deflate", "br, gzip"]).unwrap(),
+            ContentEncoding::GZip
+        );
+        assert!(ContentEncoding::from_header([]).is_none());
     }
 }
@@ -1,35 +1,42 @@
-use crate::encoding::ContentEncoding;
+#[cfg(feature = "tokio")]
+use crate::async_handler::AsyncRouter;
+use crate::conditional::{self, Precondition, Validators};
 use crate::http_request::HttpMethod;
 use crate::http_request::HttpRequest;
 use crate::http_response::ContentType;
 use crate::http_response::StatusCode;
-use crate::http_response::{Buildable, Builder, HttpResponse};
+use crate::http_response::{Buildable, Builder, HttpResponse, HttpResponseBuilder};
+use crate::range::{self, MultipartRanges, RangeRequest};
+use crate::router::Router;
 
+use std::collections::hash_map::RandomState;
 use std::fmt;
 use std::fs;
-use std::io::Write;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration; // for the 'Sleep' endpoint (used to test multi-threading)
 
-// TODO:
-// Potential refacto with a Router (dynamic vs static dispatch)
+const SLEEP_DURATION: Duration = Duration::from_secs(10);
 
-#[derive(Debug)]
-pub enum Endpoints {
-    Echo,
-    UserAgent,
-    Sleep,
-    File,
-    UrlPath,
-}
+// Built-in endpoints, registered on the server's `Router`:
+//
+//   GET                    /echo/*text     the text back
+//   GET                    /user-agent     the `User-Agent` header back
+//   GET                    /sleep          "Good sleep!", after 10s
+//   GET POST PUT DELETE    /files/*path    files of the data directory
+//   GET POST               /*path          files of the data directory, for the paths above
+//                                          left out (a fallback: `/sleep` isn't a file)
+//
+// HEAD and OPTIONS come with the router.
 
 #[derive(Debug)]
 pub enum EndpointError {
-    EndpointNotRecognized(String),
-    WrongEndpointToAccessFiles(String),
     UserAgentNotFound,
     PostBodyNotFound,
     ContentType(String),
@@ -46,14 +53,6 @@ impl From<std::io::Error> for EndpointError {
 impl fmt::Display for EndpointError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            EndpointError::EndpointNotRecognized(e) => {
-                write!(f, "request-target not recognized: {e}")
-            }
-
-            EndpointError::WrongEndpointToAccessFiles(s) => {
-                write!(f, "wrong endpoint to access a file/url: {s}")
-            }
-
             EndpointError::UserAgentNotFound => {
                 write!(f, "user-agent header not found")
             }
@@ -70,273 +69,540 @@ impl fmt::Display for EndpointError {
 
 impl std::error::Error for EndpointError {}
 
-// TODO: explore/compare the trait approach for the endpoints implementations
-impl Endpoints {
-    /// Handles a HTTP request. Each endpoint handles requests in its own way.
-    /// # Errors
-    /// Endpoints can return errors.
-    pub fn handle_request(
-        &self,
-        http_request: &HttpRequest,
-        data_dir: &Path,
-    ) -> Result<HttpResponse, EndpointError> {
-        let mut builder = HttpResponse::builder();
-
-        builder.with_protocol_version(http_request.protocol_version);
-
-        let content_encoding = http_request
-            .headers
-            .get("accept-encoding")
-            .and_then(|hdr_val| ContentEncoding::from_header(hdr_val));
-
-        builder.with_content_encoding(content_encoding);
-
-        builder.with_conn_close(!http_request.keep_alive());
-
+impl EndpointError {
+    /// Status of the response sent when a request fails with this error.
+    pub fn status_code(&self) -> StatusCode {
         match self {
-            Endpoints::Echo => {
-                const ECHO_PREFIX_LEN: usize = 6; // '/echo/'
-                let to_echo_back = http_request.request_target[ECHO_PREFIX_LEN..].as_bytes(); // '/echo/{str}'
-                builder.with_content_length(to_echo_back.len());
-                builder.with_body(to_echo_back);
+            EndpointError::BadRequest(_) | EndpointError::PostBodyNotFound => {
+                StatusCode::BadRequest
             }
-            Endpoints::UserAgent => {
-                let user_agent_body = http_request
-                    .headers
-                    .get("user-agent")
-                    .ok_or(EndpointError::UserAgentNotFound)?;
-                // .ok_or("User-Agent endpoint expects 'User-Agent' header")?;
-
-                builder.with_content_length(user_agent_body.len());
-                builder.with_body(user_agent_body.as_bytes());
+            EndpointError::Io(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                StatusCode::NotFound
             }
-            Endpoints::Sleep => {
-                thread::sleep(Duration::from_secs(10));
-                let sleep_msg = "Good sleep!".as_bytes();
-                builder.with_content_length(sleep_msg.len());
-                builder.with_body(sleep_msg);
-            }
-            Endpoints::UrlPath | Endpoints::File => match http_request.http_method {
-                HttpMethod::Get => match self.get_file_content(http_request, data_dir) {
-                    Ok(file_content) => {
-                        let content_type = self.get_file_content_type(http_request, data_dir)?;
-                        builder.with_content_type(content_type);
-                        builder.with_content_length(file_content.len());
-                        builder.with_body(&file_content);
-                    }
-                    Err(e) => {
-                        // TODO: could be more precise here, depending on the EndpointError
-                        eprintln!("error getting file content: {e})");
-                        builder.with_status_code(StatusCode::NotFound);
-                    }
-                },
-                HttpMethod::Post => {
-                    let filename = self.get_target_filename(http_request)?;
-                    let file_path = data_dir.join(filename);
-                    let content = http_request
-                        .body
-                        .clone()
-                        .ok_or(EndpointError::PostBodyNotFound)?;
-                    // .ok_or("Body should have been provided")?;
-
-                    match fs::write(file_path, content) {
-                        Ok(()) => {
-                            builder.with_status_code(StatusCode::Created);
-                            builder.with_content_type(ContentType::OctetStream);
-                        }
-
-                        Err(_) => {
-                            builder.with_status_code(StatusCode::NotFound);
-                        }
-                    }
-                }
-            },
-        };
-        Ok(builder.build())
+            _ => StatusCode::InternalServerError,
+        }
     }
 }
 
-impl std::str::FromStr for Endpoints {
-    type Err = EndpointError;
-
-    fn from_str(request_target: &str) -> Result<Self, Self::Err> {
-        match request_target {
-            s if s.starts_with("/echo/") => Ok(Self::Echo),
-            "/user-agent" => Ok(Self::UserAgent),
-            "/sleep" => Ok(Self::Sleep),
-            s if s.starts_with("/files/") => Ok(Self::File),
-            s if s.starts_with('/') => Ok(Self::UrlPath),
-            _ => {
-                eprintln!("Error parsing the endpoint: {request_target}");
-                Err(EndpointError::EndpointNotRecognized(request_target.into()))
-            }
+/// Registers the built-in endpoints, serving files from `data_dir`.
+pub fn register(router: &mut Router, data_dir: &Path) {
+    router.get("/echo/*text", |req| respond(req, echo(req)));
+    router.get("/user-agent", |req| respond(req, user_agent(req)));
+    router.get("/sleep", |req| respond(req, sleep(req)));
+
+    let data_dir: Arc<Path> = Arc::from(data_dir);
+    let dir = Arc::clone(&data_dir);
+    router.get("/files/*path", move |req| respond(req, get_file(req, &dir)));
+    let dir = Arc::clone(&data_dir);
+    router.post("/files/*path", move |req| {
+        respond(req, write_file(req, &dir))
+    });
+    let dir = Arc::clone(&data_dir);
+    router.put("/files/*path", move |req| {
+        respond(req, write_file(req, &dir))
+    });
+    let dir = Arc::clone(&data_dir);
+    router.delete("/files/*path", move |req| {
+        respond(req, delete_file(req, &dir))
+    });
+
+    let dir = Arc::clone(&data_dir);
+    router.fallback(HttpMethod::Get, "/*path", move |req: &HttpRequest| {
+        respond(req, get_file(req, &dir))
+    });
+    router.fallback(HttpMethod::Post, "/*path", move |req: &HttpRequest| {
+        respond(req, write_file(req, &data_dir))
+    });
+}
+
+/// The endpoint's response, or the error response matching its failure.
+fn respond(
+    http_request: &HttpRequest,
+    result: Result<HttpResponse, EndpointError>,
+) -> HttpResponse {
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Error handling the request: {e}");
+            let mut builder = HttpResponse::builder();
+            builder.with_protocol_version(http_request.protocol_version);
+            builder.with_status_code(e.status_code());
+            builder.build()
         }
     }
 }
 
-// Private utils
-impl Endpoints {
-    const DEFAULT_TARGET: &str = "index.html";
-
-    fn get_target_filename<'a>(
-        &self,
-        http_request: &'a HttpRequest,
-    ) -> Result<&'a str, EndpointError> {
-        let request_target = match self {
-            Endpoints::UrlPath => Self::clean_target(http_request, "/")?,
-            Endpoints::File => Self::clean_target(http_request, "/files/")?,
-            _ => {
-                return Err(EndpointError::WrongEndpointToAccessFiles(
-                    http_request.request_target.to_string(),
-                ))
+/// Builder for a response to `http_request`. The content-coding is negotiated by the
+/// `Compression` middleware.
+fn response_builder(http_request: &HttpRequest) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::builder();
+    builder.with_protocol_version(http_request.protocol_version);
+    builder
+}
+
+fn echo(http_request: &HttpRequest) -> Result<HttpResponse, EndpointError> {
+    let mut builder = response_builder(http_request);
+    let to_echo_back = http_request
+        .path_params
+        .get("text")
+        .unwrap_or_default()
+        .as_bytes();
+    builder.with_content_length(to_echo_back.len());
+    builder.with_body(to_echo_back);
+    Ok(builder.build())
+}
+
+fn user_agent(http_request: &HttpRequest) -> Result<HttpResponse, EndpointError> {
+    let mut builder = response_builder(http_request);
+    let user_agent_body = http_request
+        .headers
+        .get("user-agent")
+        .ok_or(EndpointError::UserAgentNotFound)?;
+    // .ok_or("User-Agent endpoint expects 'User-Agent' header")?;
+
+    builder.with_content_length(user_agent_body.len());
+    builder.with_body(user_agent_body.as_bytes());
+    Ok(builder.build())
+}
+
+/// Registers the built-in endpoints that await rather than block a thread: `/sleep`. Opt-in, see
+/// `AsyncRouter::built_in_endpoints`.
+#[cfg(feature = "tokio")]
+pub fn register_async(router: &mut AsyncRouter) {
+    router.get("/sleep", |req: Arc<HttpRequest>| async move {
+        tokio::time::sleep(SLEEP_DURATION).await;
+        respond(&req, slept(&req))
+    });
+}
+
+fn sleep(http_request: &HttpRequest) -> Result<HttpResponse, EndpointError> {
+    thread::sleep(SLEEP_DURATION);
+    slept(http_request)
+}
+
+fn slept(http_request: &HttpRequest) -> Result<HttpResponse, EndpointError> {
+    let mut builder = response_builder(http_request);
+    let sleep_msg = "Good sleep!".as_bytes();
+    builder.with_content_length(sleep_msg.len());
+    builder.with_body(sleep_msg);
+    Ok(builder.build())
+}
+
+fn get_file(http_request: &HttpRequest, data_dir: &Path) -> Result<HttpResponse, EndpointError> {
+    let mut builder = response_builder(http_request);
+    let request_target = target_param(http_request);
+
+    match get_file_content(request_target, data_dir) {
+        Ok(file_content) => {
+            let content_type = get_file_content_type(request_target)?;
+            builder.with_content_type(content_type);
+            match file_content {
+                FileContent::File(file, metadata) => {
+                    serve_file(&mut builder, http_request, file, &metadata, content_type)?;
+                }
+                FileContent::Listing(listing) => builder.with_body_stream(listing),
             }
-        };
+        }
+        Err(e) => {
+            // TODO: could be more precise here, depending on the EndpointError
+            eprintln!("error getting file content: {e})");
+            builder.with_status_code(StatusCode::NotFound);
+        }
+    }
+    Ok(builder.build())
+}
+
+/// POST and PUT: create or replace the file with the request body.
+fn write_file(http_request: &HttpRequest, data_dir: &Path) -> Result<HttpResponse, EndpointError> {
+    let mut builder = response_builder(http_request);
+    let file_path = get_writable_path(target_param(http_request), data_dir)?;
+    let content = http_request
+        .body
+        .as_ref()
+        .ok_or(EndpointError::PostBodyNotFound)?;
+    // .ok_or("Body should have been provided")?;
+
+    // If-Match / If-Unmodified-Since guard against overwriting someone else's
+    // changes (lost updates)
+    let existing = existing_file(&file_path);
+    if !preconditions_hold(http_request, existing.as_ref()) {
+        builder.with_status_code(StatusCode::PreconditionFailed);
+        return Ok(builder.build());
+    }
 
-        if request_target.is_empty() {
-            return Ok(Self::DEFAULT_TARGET);
+    match write_atomically(data_dir, &file_path, content) {
+        // PUT tells a replacement from a creation, POST always creates
+        Ok(()) if existing.is_some() && http_request.http_method == HttpMethod::Put => {
+            builder.with_status_code(StatusCode::NoContent);
+        }
+        Ok(()) => {
+            builder.with_status_code(StatusCode::Created);
+            builder.with_content_type(ContentType::OctetStream);
         }
 
-        Ok(request_target)
+        Err(e) => {
+            eprintln!("error writing {}: {e}", file_path.display());
+            builder.with_status_code(StatusCode::NotFound);
+        }
     }
+    Ok(builder.build())
+}
 
-    fn get_file_content(
-        &self,
-        http_request: &HttpRequest,
-        data_dir: &Path,
-    ) -> Result<Vec<u8>, EndpointError> {
-        let request_target = match self {
-            Endpoints::UrlPath => Self::clean_target(http_request, "/")?,
-            Endpoints::File => Self::clean_target(http_request, "/files/")?,
-            _ => {
-                return Err(EndpointError::WrongEndpointToAccessFiles(
-                    http_request.request_target.to_string(),
-                ))
-            }
-        };
+fn delete_file(http_request: &HttpRequest, data_dir: &Path) -> Result<HttpResponse, EndpointError> {
+    let mut builder = response_builder(http_request);
+    let file_path = get_writable_path(target_param(http_request), data_dir)?;
+    let Some(existing) = existing_file(&file_path) else {
+        builder.with_status_code(StatusCode::NotFound);
+        return Ok(builder.build());
+    };
+    if !preconditions_hold(http_request, Some(&existing)) {
+        builder.with_status_code(StatusCode::PreconditionFailed);
+        return Ok(builder.build());
+    }
 
-        // Empty target: return `DEFAULT_TARGET` content if possible, else a directory listing
-        if request_target.is_empty() {
-            let file_path = data_dir.join(Self::DEFAULT_TARGET);
-
-            match fs::read(file_path) {
-                Ok(bytes) => Ok(bytes),
-                // `ref e` to borrow the error, making the read-only need explicit. Why not, but
-                // here also
-                // compiles without the `ref`
-                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
-                    Ok(Self::render_directory_listing(data_dir)?)
-                }
-                Err(e) => Err(EndpointError::Io(e)),
+    match fs::remove_file(&file_path) {
+        Ok(()) => builder.with_status_code(StatusCode::NoContent),
+        // Deleted by someone else in the meantime
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            builder.with_status_code(StatusCode::NotFound);
+        }
+        Err(e) => return Err(EndpointError::Io(e)),
+    }
+    Ok(builder.build())
+}
+
+// Private utils
+
+const DEFAULT_TARGET: &str = "index.html";
+
+/// The part of the path captured by the file routes' `*path` wildcard.
+fn target_param(http_request: &HttpRequest) -> &str {
+    http_request.path_params.get("path").unwrap_or_default()
+}
+
+fn get_target_filename(raw_target: &str) -> Result<&str, EndpointError> {
+    let request_target = clean_target(raw_target)?;
+
+    if request_target.is_empty() {
+        return Ok(DEFAULT_TARGET);
+    }
+
+    Ok(request_target)
+}
+
+fn get_file_content(raw_target: &str, data_dir: &Path) -> Result<FileContent, EndpointError> {
+    let request_target = clean_target(raw_target)?;
+
+    // Empty target: return `DEFAULT_TARGET` content if possible, else a directory listing
+    if request_target.is_empty() {
+        let file_path = data_dir.join(DEFAULT_TARGET);
+
+        match open_regular_file(&file_path) {
+            Ok(file_content) => Ok(file_content),
+            // `ref e` to borrow the error, making the read-only need explicit. Why not, but
+            // here also
+            // compiles without the `ref`
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(FileContent::Listing(DirectoryListing::new(data_dir)?))
             }
+            Err(e) => Err(EndpointError::Io(e)),
+        }
+    }
+    // Regular code path: try to read target
+    else {
+        let requested_file_path = data_dir.join(request_target);
+
+        // ----------------------------------------------------------------------------------
+        // Canonicalise and ensure we still live *inside* data_dir
+        let real_file_path = requested_file_path
+            .canonicalize()
+            .map_err(EndpointError::Io)?;
+
+        if !real_file_path.starts_with(data_dir) {
+            return Err(EndpointError::BadRequest(request_target.into())); // attempted escape
+        }
+        // ----------------------------------------------------------------------------------
+
+        Ok(open_regular_file(&real_file_path)?)
+    }
+}
+
+/// Streams the file, or the part(s) of it selected by a `Range` header.
+fn serve_file(
+    builder: &mut HttpResponseBuilder,
+    http_request: &HttpRequest,
+    mut file: fs::File,
+    metadata: &fs::Metadata,
+    content_type: ContentType,
+) -> Result<(), EndpointError> {
+    let file_len = metadata.len();
+    let to_usize =
+        |len: u64| usize::try_from(len).map_err(|e| EndpointError::BadRequest(e.to_string()));
+
+    let validators = Validators::from_metadata(metadata);
+    if let Some(validators) = &validators {
+        builder.with_etag(&validators.etag);
+        builder.with_last_modified(validators.last_modified);
+    }
+
+    match conditional::evaluate(
+        &http_request.headers,
+        &http_request.http_method,
+        validators.as_ref(),
+    ) {
+        Precondition::Proceed => {}
+        Precondition::NotModified => {
+            builder.with_status_code(StatusCode::NotModified);
+            return Ok(());
+        }
+        Precondition::Failed => {
+            builder.with_status_code(StatusCode::PreconditionFailed);
+            return Ok(());
+        }
+    }
+
+    builder.with_accept_ranges(true);
+
+    // A failed `If-Range` means the client's partial copy is stale: send everything
+    let range_header = http_request.headers.get("range").filter(|_| {
+        http_request
+            .headers
+            .get("if-range")
+            .map_or(true, |if_range| {
+                validators
+                    .as_ref()
+                    .is_some_and(|v| range::if_range_matches(if_range, v))
+            })
+    });
+
+    match range::evaluate(range_header, file_len) {
+        RangeRequest::Full => {
+            builder.with_content_length(to_usize(file_len)?);
+            builder.with_body_stream(file);
+        }
+        RangeRequest::NotSatisfiable => {
+            builder.with_status_code(StatusCode::RangeNotSatisfiable);
+            builder.with_content_range(&range::unsatisfied_content_range(file_len));
         }
-        // Regular code path: try to read target
-        else {
-            let requested_file_path = data_dir.join(request_target);
-
-            // ----------------------------------------------------------------------------------
-            // Canonicalise and ensure we still live *inside* data_dir
-            let real_file_path = requested_file_path
-                .canonicalize()
-                .map_err(EndpointError::Io)?;
-
-            if !real_file_path.starts_with(data_dir) {
-                return Err(EndpointError::BadRequest(request_target.into())); // attempted escape
+        RangeRequest::Satisfiable(ranges) => {
+            // Ranges are over the identity representation: no on-the-fly encoding (the
+            // `Compression` middleware leaves 206 responses alone)
+            builder.with_status_code(StatusCode::PartialContent);
+
+            if let [range] = ranges[..] {
+                file.seek(SeekFrom::Start(range.first))?;
+                builder.with_content_range(&range.content_range(file_len));
+                builder.with_content_length(to_usize(range.len())?);
+                builder.with_body_stream(file.take(range.len()));
+            } else {
+                let boundary = RandomState::new().build_hasher().finish();
+                let multipart = MultipartRanges::new(
+                    file,
+                    &ranges,
+                    file_len,
+                    content_type,
+                    &format!("{boundary:016x}"),
+                );
+                builder.with_content_type(ContentType::MultipartByteRanges(boundary));
+                builder.with_content_length(to_usize(multipart.len())?);
+                builder.with_body_stream(multipart);
             }
-            // ----------------------------------------------------------------------------------
+        }
+    }
+    Ok(())
+}
+
+/// Path of the file a POST, PUT or DELETE targets. The file itself may not exist (yet), so
+/// it's its directory that must resolve inside `data_dir`.
+fn get_writable_path(raw_target: &str, data_dir: &Path) -> Result<PathBuf, EndpointError> {
+    let filename = get_target_filename(raw_target)?;
+    let file_path = data_dir.join(filename);
+
+    let real_parent = file_path
+        .parent()
+        .ok_or_else(|| EndpointError::BadRequest(filename.into()))?
+        .canonicalize()?;
+    if !real_parent.starts_with(data_dir) {
+        return Err(EndpointError::BadRequest(filename.into())); // attempted escape
+    }
 
-            let file_content = fs::read(real_file_path)?;
-            Ok(file_content)
+    Ok(file_path)
+}
+
+/// Metadata of the regular file at `file_path`, if there is one.
+fn existing_file(file_path: &Path) -> Option<fs::Metadata> {
+    fs::metadata(file_path).ok().filter(fs::Metadata::is_file)
+}
+
+/// Evaluates the preconditions of a state-changing request against the current file.
+fn preconditions_hold(http_request: &HttpRequest, existing: Option<&fs::Metadata>) -> bool {
+    let validators = existing.and_then(Validators::from_metadata);
+    conditional::evaluate(
+        &http_request.headers,
+        &http_request.http_method,
+        validators.as_ref(),
+    ) == Precondition::Proceed
+}
+
+/// Opens the file for streaming; nothing is read yet. Directories are reported as not found.
+fn open_regular_file(file_path: &Path) -> std::io::Result<FileContent> {
+    let file = fs::File::open(file_path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(std::io::ErrorKind::NotFound.into());
+    }
+    Ok(FileContent::File(file, metadata))
+}
+
+fn get_file_content_type(raw_target: &str) -> Result<ContentType, EndpointError> {
+    let filename = get_target_filename(raw_target)?;
+
+    let ext_str = Path::new(filename)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+
+    ext_str
+        .parse::<ContentType>()
+        .map_err(|()| EndpointError::ContentType(filename.into()))
+}
+
+/// o3 generated: return a *sanitised* target
+fn clean_target(raw: &str) -> Result<&str, EndpointError> {
+    // 1. Reject obviously dangerous bytes early
+    if raw.len() > 1024                 // Simple DoS limiter
+        || raw.bytes().any(|b| b == 0)      // Embedded NUL
+        || raw.contains('\\')
+    // Windows back‑slashes
+    {
+        return Err(EndpointError::BadRequest(raw.into()));
+    }
+
+    // 2. Percent‑decoding already happened when the request-target was parsed: `%2e%2e%2f`
+    //    arrives here as `../`, which the checks below are meant to catch
+    let decoded = raw;
+
+    // 3. Check for path‑traversal after decoding
+    if decoded.contains("..") || decoded.starts_with('.') {
+        return Err(EndpointError::BadRequest(decoded.into()));
+    }
+
+    // 4. Reject any component that is empty or “.”
+    for comp in PathBuf::from(decoded).components() {
+        match comp {
+            Component::Normal(c) if !c.is_empty() => {}
+            _ => return Err(EndpointError::BadRequest(raw.into())),
         }
     }
 
-    pub fn get_file_content_type(
-        &self,
-        http_request: &HttpRequest,
-        data_dir: &Path,
-    ) -> Result<ContentType, EndpointError> {
-        let filename = self.get_target_filename(http_request)?;
-        let file_path = data_dir.join(filename);
-
-        let ext_str = file_path
-            .extension()
-            .and_then(|ext| ext.to_str())
-            .unwrap_or("");
-
-        ext_str
-            .parse::<ContentType>()
-            .map_err(|()| EndpointError::ContentType(filename.into()))
+    Ok(decoded)
+}
+
+/// Writes `content` to a temporary file in `data_dir`, then renames it over `file_path`: readers
+/// see the old content or the new one, never a partial write.
+fn write_atomically(data_dir: &Path, file_path: &Path, content: &[u8]) -> std::io::Result<()> {
+    static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // Dotfiles can't be requested nor listed
+    let temp_path = data_dir.join(format!(
+        ".upload-{}-{}.tmp",
+        std::process::id(),
+        UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let write_then_rename = || -> std::io::Result<()> {
+        let mut temp_file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        temp_file.write_all(content)?;
+        temp_file.sync_all()?;
+        fs::rename(&temp_path, file_path)
+    };
+
+    let result = write_then_rename();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
     }
+    result
+}
 
-    /// o3 generated: tiny, dependency‑free directory‑listing generator.
-    fn render_directory_listing(dir: &Path) -> std::io::Result<Vec<u8>> {
-        let mut html = Vec::new();
+/// What a GET on a file endpoint serves: an opened regular file, or the generated listing of
+/// `data_dir` when there is no `index.html`.
+enum FileContent {
+    File(fs::File, fs::Metadata),
+    Listing(DirectoryListing),
+}
 
+/// Tiny, dependency‑free directory‑listing generator. Entries are rendered one at a time as the
+/// response body is read, so the listing is never held in memory as a whole.
+struct DirectoryListing {
+    entries: fs::ReadDir,
+    rendered: Vec<u8>, // rendered but not yet read
+    pos: usize,
+    done: bool,
+}
+
+impl DirectoryListing {
+    fn new(dir: &Path) -> std::io::Result<DirectoryListing> {
+        let mut rendered = Vec::new();
         writeln!(
-            html,
+            rendered,
             "<!doctype html><meta charset=\"utf-8\">\
          <title>Index of {}</title><h1>Index of {}</h1><ul>",
             dir.display(),
             dir.display()
         )?;
 
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let name = entry.file_name();
-            let name = name.to_string_lossy();
-            if name.starts_with('.') {
-                continue;
-            } // hide dotfiles
-
-            let display = if entry.file_type()?.is_dir() {
-                format!("{name}/")
-            } else {
-                name.to_string()
-            };
-
-            writeln!(html, r#"<li><a href="{display}">{display}</a></li>"#)?;
-        }
-
-        writeln!(html, "</ul>")?;
-        Ok(html)
+        Ok(DirectoryListing {
+            entries: fs::read_dir(dir)?,
+            rendered,
+            pos: 0,
+            done: false,
+        })
     }
 
-    /// o3 generated: return a *sanitised* target
-    fn clean_target<'a>(
-        http_request: &'a HttpRequest,
-        prefix: &str,
-    ) -> Result<&'a str, EndpointError> {
-        // Strip the URL prefix (`/` or `/files/`)
-        let raw = http_request
-            .request_target
-            .strip_prefix(prefix)
-            .unwrap_or_default();
-
-        // 1. Reject obviously dangerous bytes early
-        if raw.len() > 1024                 // Simple DoS limiter
-            || raw.bytes().any(|b| b == 0)      // Embedded NUL
-            || raw.contains('\\')
-        // Windows back‑slashes
-        {
-            return Err(EndpointError::BadRequest(raw.into()));
-        }
+    fn render_next_entry(&mut self) -> std::io::Result<()> {
+        let Some(entry) = self.entries.next() else {
+            writeln!(self.rendered, "</ul>")?;
+            self.done = true;
+            return Ok(());
+        };
 
-        // // 2. Percent‑decode once, using LOSSY to avoid panicking on invalid UTF‑8
-        // let decoded = percent_decode_str(raw).decode_utf8_lossy();
-        let decoded = raw;
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') {
+            return Ok(());
+        } // hide dotfiles
+
+        let display = if entry.file_type()?.is_dir() {
+            format!("{name}/")
+        } else {
+            name.to_string()
+        };
 
-        // 3. Check for path‑traversal after decoding
-        if decoded.contains("..") || decoded.starts_with('.') {
-            return Err(EndpointError::BadRequest(decoded.into()));
-        }
+        writeln!(
+            self.rendered,
+            r#"<li><a href="{display}">{display}</a></li>"#
+        )
+    }
+}
 
-        // 4. Reject any component that is empty or “.”
-        for comp in PathBuf::from(decoded).components() {
-            match comp {
-                Component::Normal(c) if !c.is_empty() => {}
-                _ => return Err(EndpointError::BadRequest(raw.into())),
+impl Read for DirectoryListing {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.rendered.len() {
+            if self.done {
+                return Ok(0);
             }
+            self.rendered.clear();
+            self.pos = 0;
+            self.render_next_entry()?;
         }
 
-        Ok(decoded)
+        let n = buf.len().min(self.rendered.len() - self.pos);
+        buf[..n].copy_from_slice(&self.rendered[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
     }
 }
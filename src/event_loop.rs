@@ -0,0 +1,859 @@
+use crate::handler::Handler;
+use crate::http2;
+use crate::http_request::{HttpRequest, Limits, RequestError};
+use crate::http_response::{HttpResponse, StatusCode};
+use crate::outgoing::{to_bytes, ChannelWriter, CHANNEL_BOUND, CHUNK_SIZE};
+use crate::request_parser::RequestParser;
+use crate::server::{self, ConnectionLimits, OpenConnections, Server, Slot};
+use crate::shutdown::Shutdown;
+use crate::thread_pool::{QueuePolicy, ThreadPool};
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// The event-driven engine: one thread waits on every connection with epoll, reads and parses the
+// requests as their bytes come, and writes the responses as the sockets take them. Only the
+// handlers run on the pool, so an idle keep-alive connection costs a few buffers, not a thread.
+//
+//   listener  (token 0, level-triggered)  -> accept until WouldBlock
+//   waker     (token 1, an eventfd)       -> workers have response bytes ready
+//   sockets   (tokens 2.., edge-triggered) -> drive the connection until it would block
+//
+// A worker writes its response into a bounded channel, so a slow client holds back the worker
+// writing to it rather than filling the memory. HTTP/2 connections are handed over to a worker
+// for good, the way the threaded engine serves them. When the pool's queue is full, a request
+// is answered 503 right away, or held back until there's room: see `QueuePolicy`. The loop never
+// waits for the pool, since the workers may be waiting for it to take their responses.
+//
+// The sweep holds each connection to its timeouts: idle between requests, header and body of
+// the request underway (408 then), and a response the client doesn't take.
+//
+// On shutdown the listener leaves the epoll set, idle connections are closed, the others after
+// their response, and the loop returns once none is left or the deadline has passed.
+
+const LISTENER: u64 = 0;
+const WAKER: u64 = 1;
+
+/// How often timed-out connections are looked for, at most: more often with shorter timeouts.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A handler's work, for the pool.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Serves the connections of `listener` for `server`, until a shutdown is requested and they are
+/// done with, or the server's `shutdown_timeout` has passed.
+/// # Errors
+/// Returns an error when epoll can't be set up or a connection can't be accepted
+pub(crate) fn run(listener: &TcpListener, server: &Server) -> Result<(), Box<dyn Error>> {
+    let shutdown = &server.shutdown;
+    let connection_limits = server.connection_limits;
+    let timeouts = [
+        connection_limits.header_timeout,
+        connection_limits.body_timeout,
+        connection_limits.idle_timeout,
+        connection_limits.write_timeout,
+    ];
+    let sweep_interval = timeouts
+        .into_iter()
+        .map(|timeout| timeout / 4)
+        .fold(SWEEP_INTERVAL, Duration::min)
+        .max(Duration::from_millis(10));
+
+    let epoll = Epoll::new()?;
+    let waker = Arc::new(Waker::new()?);
+
+    listener.set_nonblocking(true)?;
+    epoll.add(listener.as_raw_fd(), libc::EPOLLIN as u32, LISTENER)?;
+    epoll.add(waker.eventfd.as_raw_fd(), libc::EPOLLIN as u32, WAKER)?;
+
+    let mut event_loop = EventLoop {
+        epoll,
+        waker,
+        pool: &server.thread_pool,
+        handler: &server.handler,
+        limits: server.limits,
+        connection_limits,
+        queue_policy: server.queue_policy,
+        shutdown,
+        open_connections: &server.connections,
+        connections: HashMap::new(),
+        next_token: WAKER + 1,
+        held: VecDeque::new(),
+    };
+    let mut events = Vec::with_capacity(1024);
+    let mut last_sweep = Instant::now();
+    let mut deadline = None;
+
+    loop {
+        event_loop.epoll.wait(&mut events, sweep_interval)?;
+
+        if deadline.is_none() && shutdown.is_requested() {
+            event_loop.epoll.delete(listener.as_raw_fd())?;
+            deadline = Some(shutdown.deadline(server.shutdown_timeout));
+        }
+        for event in &events {
+            match event.u64 {
+                LISTENER if deadline.is_some() => {}
+                LISTENER => event_loop.accept(listener)?,
+                WAKER => {
+                    for token in event_loop.waker.take() {
+                        event_loop.drive(token);
+                    }
+                }
+                token => event_loop.drive(token),
+            }
+        }
+
+        event_loop.submit_held();
+
+        if last_sweep.elapsed() >= sweep_interval {
+            event_loop.sweep();
+            last_sweep = Instant::now();
+        }
+        if let Some(deadline) = deadline {
+            event_loop.close_waiting();
+            if event_loop.connections.is_empty() && event_loop.held.is_empty() {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                println!(
+                    "Shutdown deadline passed: closing {} connections",
+                    event_loop.connections.len()
+                );
+                return Ok(());
+            }
+        }
+    }
+}
+
+struct EventLoop<'a> {
+    epoll: Epoll,
+    waker: Arc<Waker>,
+    pool: &'a ThreadPool,
+    handler: &'a Arc<dyn Handler>,
+    limits: Limits,
+    connection_limits: ConnectionLimits,
+    queue_policy: QueuePolicy,
+    shutdown: &'a Arc<Shutdown>,
+    open_connections: &'a Arc<OpenConnections>,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    /// Jobs the pool's queue had no room for (`QueuePolicy::Block`), oldest first.
+    held: VecDeque<Job>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    parser: RequestParser,
+    /// Bytes to write, from `sent` on.
+    outgoing: Vec<u8>,
+    sent: usize,
+    state: State,
+    /// Whether `100 Continue` (or the refusal) went out for the request underway.
+    continued: bool,
+    read_closed: bool,
+    last_active: Instant,
+    /// When the first byte of the request underway came, and when its header section was done.
+    request_started: Option<Instant>,
+    body_started: Option<Instant>,
+    /// Requests answered, or being answered, on the connection.
+    served: usize,
+    /// Counts the connection as open, until it closes.
+    slot: Slot,
+}
+
+enum State {
+    /// Reading the next request.
+    Reading,
+    /// A worker is answering the request read.
+    Responding(Receiver<Output>),
+    /// The connection closes once the bytes outgoing are sent.
+    Closing,
+}
+
+/// What a worker sends the loop about its response.
+enum Output {
+    Data(Vec<u8>),
+    End { keep_alive: bool },
+}
+
+/// What to do with a connection after driving it.
+enum Next {
+    Wait,
+    Close,
+    /// The connection switches to HTTP/2 with this request.
+    Upgrade(Box<HttpRequest>),
+}
+
+impl EventLoop<'_> {
+    fn accept(&mut self, listener: &TcpListener) -> Result<(), Box<dyn Error>> {
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(format!("Error accepting the connection: {e}").into()),
+            };
+            let Some(slot) = self
+                .open_connections
+                .open(self.connection_limits.max_connections)
+            else {
+                eprintln!(
+                    "Too many connections ({} open): 503",
+                    self.connection_limits.max_connections
+                );
+                server::refuse(stream);
+                continue;
+            };
+            println!("accepted new connection");
+            stream.set_nonblocking(true)?;
+
+            let token = self.next_token;
+            self.next_token += 1;
+            let events = libc::EPOLLIN | libc::EPOLLOUT | libc::EPOLLRDHUP | libc::EPOLLET;
+            self.epoll.add(stream.as_raw_fd(), events as u32, token)?;
+            self.connections.insert(
+                token,
+                Connection {
+                    stream,
+                    parser: RequestParser::new(),
+                    outgoing: Vec::new(),
+                    sent: 0,
+                    state: State::Reading,
+                    continued: false,
+                    read_closed: false,
+                    last_active: Instant::now(),
+                    request_started: None,
+                    body_started: None,
+                    served: 0,
+                    slot,
+                },
+            );
+        }
+    }
+
+    /// Moves the connection along as far as it goes without blocking.
+    fn drive(&mut self, token: u64) {
+        let Some(mut connection) = self.connections.remove(&token) else {
+            return; // closed already
+        };
+        match self.step(token, &mut connection) {
+            Next::Wait => {
+                self.connections.insert(token, connection);
+            }
+            // Closing the socket takes it out of the epoll set
+            Next::Close => {}
+            Next::Upgrade(http_request) => self.upgrade(token, connection, *http_request),
+        }
+    }
+
+    fn step(&mut self, token: u64, connection: &mut Connection) -> Next {
+        let mut buf = [0; 16 * 1024];
+        loop {
+            // Whatever is outgoing goes first
+            while connection.sent < connection.outgoing.len() {
+                match connection
+                    .stream
+                    .write(&connection.outgoing[connection.sent..])
+                {
+                    Ok(0) => return Next::Close,
+                    Ok(n) => {
+                        connection.sent += n;
+                        connection.last_active = Instant::now();
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Next::Wait,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        eprintln!("Error handling the stream: {e}");
+                        return Next::Close;
+                    }
+                }
+            }
+            connection.outgoing.clear();
+            connection.sent = 0;
+
+            match &connection.state {
+                State::Reading => {}
+                State::Responding(receiver) => {
+                    match receiver.try_recv() {
+                        Ok(Output::Data(data)) => connection.outgoing = data,
+                        Ok(Output::End { keep_alive }) => {
+                            println!("keep-alive: {keep_alive}");
+                            connection.state = if keep_alive && !self.shutdown.is_requested() {
+                                State::Reading
+                            } else {
+                                State::Closing
+                            };
+                        }
+                        Err(TryRecvError::Empty) => return Next::Wait,
+                        // The worker gave up on the response halfway
+                        Err(TryRecvError::Disconnected) => return Next::Close,
+                    }
+                    continue;
+                }
+                State::Closing => return Next::Close,
+            }
+
+            match connection.parser.parse(&self.limits) {
+                Ok(Some(http_request)) => {
+                    connection.continued = false;
+                    connection.request_started = None;
+                    connection.body_started = None;
+                    // Prior knowledge or `Upgrade: h2c`: the connection goes on as HTTP/2
+                    if http2::switches(&http_request) {
+                        return Next::Upgrade(Box::new(http_request));
+                    }
+                    println!("Parsed http-request: {http_request:?}\n");
+                    connection.served += 1;
+                    let last = connection.served >= self.connection_limits.max_requests;
+                    connection.state = match self.dispatch(token, http_request, last) {
+                        Some(receiver) => State::Responding(receiver),
+                        None => {
+                            let stats = self.pool.stats();
+                            eprintln!(
+                                "Job queue full ({} queued, {} rejected so far): 503",
+                                stats.queued, stats.rejected
+                            );
+                            connection.outgoing = to_bytes(HttpResponse::new_service_unavailable(
+                                server::RETRY_AFTER,
+                            ));
+                            State::Closing
+                        }
+                    };
+                    continue;
+                }
+                Ok(None) => {
+                    // The request's timeouts run from its first byte, then from its body's start
+                    if !connection.parser.is_empty() {
+                        connection.request_started.get_or_insert_with(Instant::now);
+                    }
+                    if connection.parser.head().is_some() {
+                        connection.body_started.get_or_insert_with(Instant::now);
+                    }
+                    if let Some(response) = self.expect_continue(connection) {
+                        connection.outgoing = response;
+                        continue;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("error parsing the http-request: {e}");
+                    connection.outgoing = to_bytes(HttpResponse::new_from_bad_request(&e));
+                    connection.state = State::Closing;
+                    continue;
+                }
+            }
+
+            if connection.read_closed {
+                return Next::Close;
+            }
+            match connection.stream.read(&mut buf) {
+                Ok(0) => connection.read_closed = true,
+                Ok(n) => {
+                    connection.parser.feed(&buf[..n]);
+                    connection.last_active = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Next::Wait,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    eprintln!("Error handling the stream: {e}");
+                    return Next::Close;
+                }
+            }
+        }
+    }
+
+    /// The interim `100 Continue`, or the final refusal, owed to a client waiting before it sends
+    /// the body of the request underway. Asked of the handler right here: it's about headers only.
+    fn expect_continue(&self, connection: &mut Connection) -> Option<Vec<u8>> {
+        let http_request = connection.parser.head()?;
+        if connection.continued || !http_request.expects_continue() {
+            return None;
+        }
+        connection.continued = true;
+
+        if let Some(mut rejection) = self.handler.reject_before_body(http_request) {
+            rejection.headers.insert("connection", "close");
+            connection.state = State::Closing;
+            return Some(to_bytes(rejection));
+        }
+        Some(to_bytes(HttpResponse::new_interim(StatusCode::Continue)))
+    }
+
+    /// Has a worker answer the request, the response coming back through the channel returned,
+    /// closing the connection if it's the `last` one allowed on it. `None` if the request was
+    /// refused, the pool's queue being full.
+    fn dispatch(
+        &mut self,
+        token: u64,
+        http_request: HttpRequest,
+        last: bool,
+    ) -> Option<Receiver<Output>> {
+        let (sender, receiver) = mpsc::sync_channel(CHANNEL_BOUND);
+        let handler = Arc::clone(self.handler);
+        let waker = Arc::clone(&self.waker);
+        let shutdown = Arc::clone(self.shutdown);
+        let makes_room = self.queue_policy == QueuePolicy::Block;
+
+        let job = move || {
+            // Taken off the queue: the loop may have jobs held back for the room
+            if makes_room {
+                waker.wake(token);
+            }
+            let mut http_response = server::respond(handler.as_ref(), &http_request);
+            if last || shutdown.is_requested() {
+                http_response.headers.insert("connection", "close");
+            }
+            let keep_alive = !http_response.conn_close();
+
+            // Into the channel to the loop, waking it up
+            let mut writer = BufWriter::with_capacity(
+                CHUNK_SIZE,
+                ChannelWriter::new(|chunk| {
+                    let sent = sender.send(Output::Data(chunk)).is_ok();
+                    if sent {
+                        waker.wake(token);
+                    }
+                    sent
+                }),
+            );
+            let result = http_response
+                .write_to(&mut writer)
+                .and_then(|()| writer.flush());
+            drop(writer);
+
+            match result {
+                Ok(()) => {
+                    // The loop may be gone with the connection already: nobody to tell then
+                    if sender.send(Output::End { keep_alive }).is_ok() {
+                        waker.wake(token);
+                    }
+                    println!("Successfully handled stream");
+                }
+                Err(e) => eprintln!("Error handling the stream: {e}"),
+            }
+        };
+        self.submit(Box::new(job)).then_some(receiver)
+    }
+
+    /// Hands the connection over to a worker, which speaks HTTP/2 on it until it closes.
+    fn upgrade(&mut self, token: u64, connection: Connection, http_request: HttpRequest) {
+        println!("switching to HTTP/2");
+        let stream = connection.stream;
+        let prepared = self
+            .epoll
+            .delete(stream.as_raw_fd())
+            .and_then(|()| stream.set_nonblocking(false))
+            .and_then(|()| stream.set_read_timeout(Some(self.connection_limits.idle_timeout)))
+            .and_then(|()| stream.set_write_timeout(Some(self.connection_limits.write_timeout)));
+        if let Err(e) = prepared {
+            eprintln!("Error handling the stream: {e}");
+            return;
+        }
+
+        let parser = connection.parser;
+        let slot = connection.slot;
+        let handler = Arc::clone(self.handler);
+        let limits = self.limits;
+        let shutdown = Arc::clone(self.shutdown);
+        let waker = Arc::clone(&self.waker);
+        let makes_room = self.queue_policy == QueuePolicy::Block;
+        let job = move || {
+            // As in `dispatch`; the connection is gone from the loop, not the jobs held back
+            if makes_room {
+                waker.wake(token);
+            }
+            let _slot = slot;
+            // Off the loop, the connection is drained like the threaded engine's
+            let connection = match shutdown.track(&stream) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    eprintln!("Error handling the stream: {e}");
+                    return;
+                }
+            };
+            let mut reader = parser.into_reader(&stream);
+            match http2::serve(
+                &mut reader,
+                &stream,
+                handler.as_ref(),
+                &limits,
+                http_request,
+                &connection,
+            ) {
+                Ok(()) => println!("Successfully handled stream"),
+                Err(e) => eprintln!("Error handling the stream: {e}"),
+            }
+        };
+        if !self.submit(Box::new(job)) {
+            eprintln!("Job queue full: HTTP/2 connection closed");
+        }
+    }
+
+    /// Queues the job on the pool, `false` if it's refused for want of room. With
+    /// `QueuePolicy::Block`, it's held back instead, until there's room.
+    fn submit(&mut self, job: Job) -> bool {
+        match self.queue_policy {
+            QueuePolicy::Reject => self.pool.try_execute(job).is_ok(),
+            QueuePolicy::Block => {
+                self.held.push_back(job);
+                self.submit_held();
+                true
+            }
+        }
+    }
+
+    /// Queues the jobs held back, as many as there's room for.
+    fn submit_held(&mut self) {
+        while !self.held.is_empty() {
+            // Room is looked for first: a job the queue turns down counts as rejected
+            let stats = self.pool.stats();
+            if stats.queued >= stats.queue_capacity {
+                return;
+            }
+            if let Some(job) = self.held.pop_front() {
+                if let Err(job) = self.pool.try_execute(job) {
+                    self.held.push_front(job);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Holds the connections to their timeouts. Those idle past `idle_timeout`, or whose client
+    /// hasn't taken any of the bytes outgoing for `write_timeout`, are closed; those whose request
+    /// is slower to come than its header or body timeout get a 408 first. A connection waiting
+    /// for its handler doesn't time out.
+    fn sweep(&mut self) {
+        let limits = self.connection_limits;
+        let mut timed_out = Vec::new();
+        self.connections.retain(|&token, connection| {
+            if connection.sent < connection.outgoing.len() {
+                return connection.last_active.elapsed() < limits.write_timeout;
+            }
+            if !matches!(connection.state, State::Reading) {
+                return true;
+            }
+            let request_late = match (connection.request_started, connection.body_started) {
+                (_, Some(body_started)) => body_started.elapsed() >= limits.body_timeout,
+                (Some(request_started), None) => request_started.elapsed() >= limits.header_timeout,
+                (None, None) => return connection.last_active.elapsed() < limits.idle_timeout,
+            };
+            if request_late {
+                timed_out.push(token);
+            }
+            true
+        });
+
+        for token in timed_out {
+            if let Some(connection) = self.connections.get_mut(&token) {
+                eprintln!("error parsing the http-request: {}", RequestError::Timeout);
+                connection.outgoing =
+                    to_bytes(HttpResponse::new_from_bad_request(&RequestError::Timeout));
+                connection.state = State::Closing;
+                connection.last_active = Instant::now();
+            }
+            self.drive(token);
+        }
+    }
+
+    /// Closes the connections waiting for a request, with nothing of one received yet.
+    fn close_waiting(&mut self) {
+        self.connections.retain(|_, connection| {
+            !matches!(connection.state, State::Reading)
+                || !connection.parser.is_empty()
+                || connection.sent < connection.outgoing.len()
+        });
+    }
+}
+
+/// Lets workers wake the loop up, telling it which connections have something for it.
+struct Waker {
+    eventfd: File,
+    ready: Mutex<Vec<u64>>,
+}
+
+impl Waker {
+    fn new() -> io::Result<Waker> {
+        // SAFETY: eventfd only takes flags; the descriptor returned is ours alone
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a valid descriptor, owned by nothing else
+        let eventfd = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        Ok(Waker {
+            eventfd,
+            ready: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn wake(&self, token: u64) {
+        self.ready.lock().unwrap().push(token);
+        // Only fails when the counter is about to overflow, and then the loop is awake already
+        let _ = (&self.eventfd).write(&1u64.to_ne_bytes());
+    }
+
+    /// The connections woken for, since the last time.
+    fn take(&self) -> Vec<u64> {
+        let mut counter = [0; 8];
+        let _ = (&self.eventfd).read(&mut counter);
+        let mut tokens = std::mem::take(&mut *self.ready.lock().unwrap());
+        tokens.dedup();
+        tokens
+    }
+}
+
+/// An epoll instance.
+struct Epoll {
+    fd: OwnedFd,
+}
+
+impl Epoll {
+    fn new() -> io::Result<Epoll> {
+        // SAFETY: epoll_create1 only takes flags; the descriptor returned is ours alone
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a valid descriptor, owned by nothing else
+        Ok(Epoll {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    fn add(&self, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: token };
+        // SAFETY: both descriptors are open, and `event` outlives the call
+        let result =
+            unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut event) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        // SAFETY: both descriptors are open; no event is needed to delete
+        let result = unsafe {
+            libc::epoll_ctl(
+                self.fd.as_raw_fd(),
+                libc::EPOLL_CTL_DEL,
+                fd,
+                std::ptr::null_mut(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Waits for events, up to `timeout`. `events` is filled up to its capacity.
+    fn wait(&self, events: &mut Vec<libc::epoll_event>, timeout: Duration) -> io::Result<()> {
+        events.clear();
+        let max_events = i32::try_from(events.capacity()).unwrap_or(i32::MAX);
+        let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        // SAFETY: the kernel writes at most `max_events` events, within the capacity
+        let n = unsafe {
+            libc::epoll_wait(
+                self.fd.as_raw_fd(),
+                events.as_mut_ptr(),
+                max_events,
+                timeout,
+            )
+        };
+        if n < 0 {
+            let error = io::Error::last_os_error();
+            // A signal isn't an error: back to waiting
+            return match error.kind() {
+                io::ErrorKind::Interrupted => Ok(()),
+                _ => Err(error),
+            };
+        }
+        // SAFETY: the first `n` events were written by the kernel
+        unsafe { events.set_len(usize::try_from(n).unwrap_or(0)) };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_response::{Buildable, Builder};
+    use crate::router::Router;
+    use std::thread;
+
+    /// Serves `router` with the event loop on a free port, returning its address.
+    fn serve(router: Router) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let server = Server::with_handler(&address, 2, router);
+            let _ = run(&listener, &server);
+        });
+        address
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/hello", |_| {
+            let mut builder = HttpResponse::builder();
+            builder.with_body(b"hello");
+            builder.build()
+        });
+        router.get("/big", |_| {
+            let mut builder = HttpResponse::builder();
+            builder.with_body(&vec![b'x'; 1 << 20]);
+            builder.build()
+        });
+        router.get("/slow", |_| {
+            thread::sleep(Duration::from_millis(300));
+            HttpResponse::builder().build()
+        });
+        router.put("/upload", |req| {
+            let mut builder = HttpResponse::builder();
+            builder.with_body(req.body.as_deref().unwrap_or_default());
+            builder.build()
+        });
+        router
+    }
+
+    fn read_to_end(mut stream: TcpStream) -> String {
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[test]
+    fn test_keep_alive_and_pipelining() {
+        let address = serve(router());
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        // Two requests at once, the second one in pieces, then a last one closing
+        stream
+            .write_all(b"GET /hello HTTP/1.1\r\n\r\nPUT /upload HTTP/1.1\r\nContent-Le")
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream
+            .write_all(b"ngth: 3\r\n\r\nabcGET /hello HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let response = read_to_end(stream);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+        assert!(response.contains("\r\n\r\nhello"));
+        assert!(response.contains("\r\n\r\nabc"));
+        assert!(response.ends_with("hello"));
+    }
+
+    #[test]
+    fn test_large_response_and_concurrency() {
+        let address = serve(router());
+
+        // Slow handlers don't hold the other connections up beyond the pool
+        let start = Instant::now();
+        let slow: Vec<_> = (0..2)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut stream = TcpStream::connect(address).unwrap();
+                    stream
+                        .write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+                        .unwrap();
+                    read_to_end(stream)
+                })
+            })
+            .collect();
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /big HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let response = read_to_end(stream);
+        assert!(response.ends_with(&"x".repeat(1 << 20)));
+
+        for handle in slow {
+            assert!(handle.join().unwrap().starts_with("HTTP/1.1 200 OK"));
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_full_queue_held_back_without_blocking_the_loop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut router = router();
+        router.get("/slow-big", |_| {
+            thread::sleep(Duration::from_millis(200));
+            let mut builder = HttpResponse::builder();
+            builder.with_body_stream(std::io::Cursor::new(vec![b'x'; 1 << 20]));
+            builder.with_content_length(1 << 20);
+            builder.build()
+        });
+        thread::spawn(move || {
+            let mut server = Server::with_handler(&address, 2, router);
+            server.queue_policy = QueuePolicy::Block;
+            server.thread_pool.set_queue_capacity(1);
+            let _ = run(&listener, &server);
+        });
+
+        // Two requests for the workers, one queued, the rest held back: the workers need the
+        // loop to take responses streamed in more chunks than their channels hold
+        let streams: Vec<_> = (0..6)
+            .map(|_| {
+                let mut stream = TcpStream::connect(address).unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                stream
+                    .write_all(b"GET /slow-big HTTP/1.1\r\nConnection: close\r\n\r\n")
+                    .unwrap();
+                stream
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(100));
+        for stream in streams {
+            assert!(read_to_end(stream).ends_with(&"x".repeat(1 << 20)));
+        }
+    }
+
+    #[test]
+    fn test_idle_connections_take_no_worker() {
+        let address = serve(router());
+
+        // More idle connections than workers
+        let idle: Vec<_> = (0..8)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        assert!(read_to_end(stream).ends_with("hello"));
+        drop(idle);
+    }
+
+    #[test]
+    fn test_bad_request_and_expect_continue() {
+        let address = serve(router());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nBad header\r\n\r\n")
+            .unwrap();
+        assert!(read_to_end(stream).starts_with("HTTP/1.1 400 Bad Request"));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"PUT /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut interim = [0; 25];
+        stream.read_exact(&mut interim).unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        stream.write_all(b"ok").unwrap();
+        assert!(read_to_end(stream).ends_with("\r\n\r\nok"));
+    }
+}
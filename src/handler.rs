@@ -0,0 +1,25 @@
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponse;
+
+/// Something that answers requests: a `Router`, a closure, or any type of yours.
+///
+/// Handlers run on the server's worker threads, concurrently, hence `Send + Sync`.
+pub trait Handler: Send + Sync {
+    fn handle(&self, http_request: &HttpRequest) -> HttpResponse;
+
+    /// Final response for a request refused on its header section alone, before the body is
+    /// read: asked when the client waits for `100 Continue` before sending it. `None`, the
+    /// default, lets the body come.
+    fn reject_before_body(&self, _http_request: &HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&HttpRequest) -> HttpResponse + Send + Sync,
+{
+    fn handle(&self, http_request: &HttpRequest) -> HttpResponse {
+        self(http_request)
+    }
+}
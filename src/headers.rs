@@ -0,0 +1,176 @@
+use std::fmt;
+
+/// Header fields, in the order they were added.
+///
+/// Names are case-insensitive: they are stored (and rendered) lower-cased, which is also what
+/// HTTP/2 requires. A name can hold several values, e.g. one `set-cookie` per cookie.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    #[must_use]
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Adds a value, keeping any previous value of the same name.
+    ///
+    /// CR, LF and NUL are replaced by spaces in both name and value: they would let a value
+    /// smuggle extra header fields (response splitting).
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((
+            sanitize(name).to_lowercase(),
+            sanitize(value).trim().to_string(),
+        ));
+    }
+
+    /// Sets the value, replacing every previous value of the same name. The field keeps the
+    /// position of its first occurrence.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        let name = sanitize(name).to_lowercase();
+        let value = sanitize(value).trim().to_string();
+
+        match self.fields.iter().position(|(n, _)| *n == name) {
+            Some(first) => {
+                self.fields[first].1 = value;
+                let mut index = 0;
+                self.fields.retain(|(n, _)| {
+                    index += 1;
+                    index - 1 == first || *n != name
+                });
+            }
+            None => self.fields.push((name, value)),
+        }
+    }
+
+    /// Removes every value of the name.
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    /// First value of the name.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of the name, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of the name combined into one comma-separated list, which is equivalent for
+    /// list-based fields (RFC 9110, section 5.3). `set-cookie` is the notable exception.
+    #[must_use]
+    pub fn get_joined(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self.get_all(name).collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join(", "))
+        }
+    }
+
+    #[must_use]
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Name-value pairs, in order, one per value.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+/// Renders the fields as they go on the wire: one `name: value` line per value.
+impl fmt::Display for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in self.iter() {
+            write!(f, "{name}: {value}\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+fn sanitize(s: &str) -> String {
+    s.replace(['\r', '\n', '\0'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_case_insensitive_multi_values() {
+        let mut headers = Headers::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Vary", "Accept-Encoding");
+        headers.append("set-cookie", "b=2");
+
+        assert_eq!(headers.get("SET-COOKIE"), Some("a=1"));
+        assert_eq!(
+            headers.get_all("set-cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+        assert_eq!(headers.get_joined("Set-Cookie").unwrap(), "a=1, b=2");
+        assert!(headers.get_joined("location").is_none());
+        assert_eq!(headers.len(), 3);
+    }
+
+    #[test]
+    fn test_insert_replaces_in_place() {
+        let mut headers = Headers::new();
+        headers.append("x-first", "1");
+        headers.append("cache-control", "no-cache");
+        headers.append("x-last", "2");
+        headers.append("Cache-Control", "private");
+
+        headers.insert("CACHE-CONTROL", "max-age=60");
+
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [
+                ("x-first", "1"),
+                ("cache-control", "max-age=60"),
+                ("x-last", "2")
+            ]
+        );
+
+        headers.remove("X-First");
+        assert!(!headers.contains("x-first"));
+        assert_eq!(
+            headers.to_string(),
+            "cache-control: max-age=60\r\nx-last: 2\r\n"
+        );
+    }
+
+    #[test]
+    fn test_no_response_splitting() {
+        let mut headers = Headers::new();
+        headers.append("location", "/next\r\nset-cookie: evil=1");
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(
+            headers.to_string(),
+            "location: /next  set-cookie: evil=1\r\n"
+        );
+    }
+}
@@ -0,0 +1,584 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::OnceLock;
+
+// HPACK, the header compression of HTTP/2 (RFC 7541). A header block is a sequence of:
+//
+//   1xxxxxxx             indexed field: name and value from the tables
+//   01xxxxxx             literal field, added to the dynamic table
+//   0000xxxx, 0001xxxx   literal field, not added (0001: never to be indexed downstream)
+//   001xxxxx             dynamic table size update, at the start of a block only
+//
+// Indices 1 to 61 are the static table, the dynamic table follows, newest entry first. Names
+// and values are sent as is or Huffman-coded.
+//
+// The dynamic table is built by the client's encoder and mirrored here: it's connection state,
+// so every header block goes through the decoder, in order, even that of a refused stream.
+// Encoding never adds entries: the client's decoder has nothing to mirror.
+
+/// A decoded header field, as bytes: HPACK doesn't care whether they are text.
+pub(crate) type Field = (Vec<u8>, Vec<u8>);
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum HpackError {
+    Truncated,
+    IntegerOverflow,
+    InvalidIndex(usize),
+    Huffman,
+    TableSizeUpdate(usize),
+}
+
+impl fmt::Display for HpackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HpackError::Truncated => write!(f, "truncated header block"),
+            HpackError::IntegerOverflow => write!(f, "integer too large"),
+            HpackError::InvalidIndex(i) => write!(f, "no table entry at index {i}"),
+            HpackError::Huffman => write!(f, "invalid Huffman-coded string"),
+            HpackError::TableSizeUpdate(s) => write!(f, "invalid dynamic table size update: {s}"),
+        }
+    }
+}
+
+impl std::error::Error for HpackError {}
+
+/// Size counted for a field on top of its name and value, in the dynamic table and in header
+/// list sizes (section 4.1).
+pub(crate) const FIELD_OVERHEAD: usize = 32;
+
+/// Decoder of the header blocks of one connection.
+pub(crate) struct Decoder {
+    dynamic_table: VecDeque<Field>,
+    size: usize,
+    max_size: usize,
+    /// The size advertised in SETTINGS_HEADER_TABLE_SIZE, that updates can't go over.
+    size_limit: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new(size_limit: usize) -> Decoder {
+        Decoder {
+            dynamic_table: VecDeque::new(),
+            size: 0,
+            max_size: size_limit,
+            size_limit,
+        }
+    }
+
+    /// Decodes a complete header block. `Ok(None)` when the header list is larger than
+    /// `max_list_size`: the block is decoded to the end all the same, for the dynamic table.
+    pub(crate) fn decode(
+        &mut self,
+        mut block: &[u8],
+        max_list_size: usize,
+    ) -> Result<Option<Vec<Field>>, HpackError> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+
+        while let Some(&first) = block.first() {
+            let field = if first & 0x80 != 0 {
+                let index = decode_integer(&mut block, 7)?;
+                self.field(index)?
+            } else if first & 0x40 != 0 {
+                let field = self.decode_literal(&mut block, 6)?;
+                self.insert(field.clone());
+                field
+            } else if first & 0x20 != 0 {
+                let size = decode_integer(&mut block, 5)?;
+                if list_size > 0 || size > self.size_limit {
+                    return Err(HpackError::TableSizeUpdate(size));
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                self.decode_literal(&mut block, 4)?
+            };
+
+            list_size += field.0.len() + field.1.len() + FIELD_OVERHEAD;
+            if list_size <= max_list_size {
+                fields.push(field);
+            }
+        }
+
+        Ok((list_size <= max_list_size).then_some(fields))
+    }
+
+    fn field(&self, index: usize) -> Result<Field, HpackError> {
+        match index {
+            0 => Err(HpackError::InvalidIndex(index)),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.into(), value.into()))
+            }
+            _ => self
+                .dynamic_table
+                .get(index - 62)
+                .cloned()
+                .ok_or(HpackError::InvalidIndex(index)),
+        }
+    }
+
+    /// A literal field whose name is either indexed (in the prefix) or a literal too.
+    fn decode_literal(&self, block: &mut &[u8], prefix_bits: u32) -> Result<Field, HpackError> {
+        let name = match decode_integer(block, prefix_bits)? {
+            0 => decode_string(block)?,
+            index => self.field(index)?.0,
+        };
+        let value = decode_string(block)?;
+        Ok((name, value))
+    }
+
+    fn insert(&mut self, field: Field) {
+        let entry_size = field.0.len() + field.1.len() + FIELD_OVERHEAD;
+        // An entry larger than the whole table empties it, and isn't added
+        if entry_size > self.max_size {
+            self.dynamic_table.clear();
+            self.size = 0;
+            return;
+        }
+        self.evict(entry_size);
+        self.dynamic_table.push_front(field);
+        self.size += entry_size;
+    }
+
+    /// Drops the oldest entries until `room` more bytes fit in the table.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some((name, value)) = self.dynamic_table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + FIELD_OVERHEAD;
+        }
+    }
+}
+
+/// Encodes the fields as a header block. Whole fields and names come from the static table
+/// when it has them; the rest is sent as literals that aren't added to the dynamic table.
+pub(crate) fn encode<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(fields: I) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        if let Some(i) = STATIC_TABLE
+            .iter()
+            .position(|&field| field == (name, value))
+        {
+            encode_integer(&mut block, 0x80, 7, i + 1);
+            continue;
+        }
+        // Literal without indexing, its name indexed if the static table has it
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(i) => encode_integer(&mut block, 0x00, 4, i + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name.as_bytes());
+            }
+        }
+        encode_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+/// Integer with an N-bit prefix (section 5.1): the prefix holds it if it can, otherwise it's
+/// all ones and the rest follows 7 bits per byte, least significant first.
+fn decode_integer(block: &mut &[u8], prefix_bits: u32) -> Result<usize, HpackError> {
+    let (&first, rest) = block.split_first().ok_or(HpackError::Truncated)?;
+    *block = rest;
+    let max_prefix = (1 << prefix_bits) - 1;
+    let mut value = u64::from(first) & max_prefix;
+    if value < max_prefix {
+        return usize::try_from(value).map_err(|_| HpackError::IntegerOverflow);
+    }
+
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or(HpackError::Truncated)?;
+        *block = rest;
+        // Nothing sensible needs more than 32 bits
+        if shift > 28 {
+            return Err(HpackError::IntegerOverflow);
+        }
+        value += u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return usize::try_from(value).map_err(|_| HpackError::IntegerOverflow);
+        }
+        shift += 7;
+    }
+}
+
+/// `flags` are the bits above the prefix in the first byte.
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix_bits: u32, value: usize) {
+    let max_prefix = (1 << prefix_bits) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max_prefix as u8);
+    let mut rest = value - max_prefix;
+    while rest >= 0x80 {
+        block.push(0x80 | (rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    block.push(rest as u8);
+}
+
+/// String literal (section 5.2): the H bit, the length on a 7-bit prefix, the octets.
+fn decode_string(block: &mut &[u8]) -> Result<Vec<u8>, HpackError> {
+    let huffman = block.first().ok_or(HpackError::Truncated)? & 0x80 != 0;
+    let len = decode_integer(block, 7)?;
+    if block.len() < len {
+        return Err(HpackError::Truncated);
+    }
+    let (string, rest) = block.split_at(len);
+    *block = rest;
+    if huffman {
+        huffman_decode(string)
+    } else {
+        Ok(string.to_vec())
+    }
+}
+
+/// Huffman-codes the string when that makes it shorter.
+fn encode_string(block: &mut Vec<u8>, string: &[u8]) {
+    let huffman_bits: usize = string
+        .iter()
+        .map(|&b| usize::from(HUFFMAN_LENGTHS[usize::from(b)]))
+        .sum();
+    let huffman_len = huffman_bits.div_ceil(8);
+    if huffman_len < string.len() {
+        encode_integer(block, 0x80, 7, huffman_len);
+        huffman_encode(block, string);
+    } else {
+        encode_integer(block, 0x00, 7, string.len());
+        block.extend_from_slice(string);
+    }
+}
+
+fn huffman_decode(encoded: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let symbols = huffman_symbols();
+    let mut decoded = Vec::with_capacity(encoded.len() * 8 / 5);
+    let (mut code, mut len) = (0u32, 0u8);
+
+    for byte in encoded {
+        for bit in (0..8).rev() {
+            code = code << 1 | u32::from(byte >> bit & 1);
+            len += 1;
+            match symbols.get(&(len, code)) {
+                // EOS must not appear in a string
+                Some(256) => return Err(HpackError::Huffman),
+                Some(&symbol) => {
+                    decoded.push(symbol as u8);
+                    (code, len) = (0, 0);
+                }
+                None if len >= 30 => return Err(HpackError::Huffman),
+                None => {}
+            }
+        }
+    }
+
+    // Padding is the most significant bits of EOS (all ones), and shorter than a byte
+    if len > 7 || code != (1 << len) - 1 {
+        return Err(HpackError::Huffman);
+    }
+    Ok(decoded)
+}
+
+fn huffman_encode(block: &mut Vec<u8>, string: &[u8]) {
+    // Only the pending bits matter: older ones are shifted out
+    let (mut bits, mut n_bits) = (0u64, 0u32);
+    for &b in string {
+        let len = u32::from(HUFFMAN_LENGTHS[usize::from(b)]);
+        bits = bits << len | u64::from(HUFFMAN_CODES[usize::from(b)]);
+        n_bits += len;
+        while n_bits >= 8 {
+            n_bits -= 8;
+            block.push((bits >> n_bits) as u8);
+        }
+    }
+    if n_bits > 0 {
+        // Padded with the start of EOS
+        block.push((bits << (8 - n_bits)) as u8 | 0xff >> n_bits);
+    }
+}
+
+/// Symbol of each (length, code) pair.
+fn huffman_symbols() -> &'static HashMap<(u8, u32), u16> {
+    static SYMBOLS: OnceLock<HashMap<(u8, u32), u16>> = OnceLock::new();
+    SYMBOLS.get_or_init(|| {
+        (0..=256u16)
+            .map(|symbol| {
+                let i = usize::from(symbol);
+                ((HUFFMAN_LENGTHS[i], HUFFMAN_CODES[i]), symbol)
+            })
+            .collect()
+    })
+}
+
+/// Static table (Appendix A), index 1 first.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Huffman code of each symbol, EOS (256) last (RFC 7541, Appendix B).
+const HUFFMAN_CODES: [u32; 257] = [
+    0x1ff8, 0x7fffd8, 0xfffffe2, 0xfffffe3, 0xfffffe4, 0xfffffe5, 0xfffffe6, 0xfffffe7, 0xfffffe8,
+    0xffffea, 0x3ffffffc, 0xfffffe9, 0xfffffea, 0x3ffffffd, 0xfffffeb, 0xfffffec, 0xfffffed,
+    0xfffffee, 0xfffffef, 0xffffff0, 0xffffff1, 0xffffff2, 0x3ffffffe, 0xffffff3, 0xffffff4,
+    0xffffff5, 0xffffff6, 0xffffff7, 0xffffff8, 0xffffff9, 0xffffffa, 0xffffffb, 0x14, 0x3f8,
+    0x3f9, 0xffa, 0x1ff9, 0x15, 0xf8, 0x7fa, 0x3fa, 0x3fb, 0xf9, 0x7fb, 0xfa, 0x16, 0x17, 0x18,
+    0x0, 0x1, 0x2, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f, 0x5c, 0xfb, 0x7ffc, 0x20, 0xffb,
+    0x3fc, 0x1ffa, 0x21, 0x5d, 0x5e, 0x5f, 0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f, 0x70, 0x71, 0x72, 0xfc, 0x73, 0xfd, 0x1ffb, 0x7fff0,
+    0x1ffc, 0x3ffc, 0x22, 0x7ffd, 0x3, 0x23, 0x4, 0x24, 0x5, 0x25, 0x26, 0x27, 0x6, 0x74, 0x75,
+    0x28, 0x29, 0x2a, 0x7, 0x2b, 0x76, 0x2c, 0x8, 0x9, 0x2d, 0x77, 0x78, 0x79, 0x7a, 0x7b, 0x7ffe,
+    0x7fc, 0x3ffd, 0x1ffd, 0xffffffc, 0xfffe6, 0x3fffd2, 0xfffe7, 0xfffe8, 0x3fffd3, 0x3fffd4,
+    0x3fffd5, 0x7fffd9, 0x3fffd6, 0x7fffda, 0x7fffdb, 0x7fffdc, 0x7fffdd, 0x7fffde, 0xffffeb,
+    0x7fffdf, 0xffffec, 0xffffed, 0x3fffd7, 0x7fffe0, 0xffffee, 0x7fffe1, 0x7fffe2, 0x7fffe3,
+    0x7fffe4, 0x1fffdc, 0x3fffd8, 0x7fffe5, 0x3fffd9, 0x7fffe6, 0x7fffe7, 0xffffef, 0x3fffda,
+    0x1fffdd, 0xfffe9, 0x3fffdb, 0x3fffdc, 0x7fffe8, 0x7fffe9, 0x1fffde, 0x7fffea, 0x3fffdd,
+    0x3fffde, 0xfffff0, 0x1fffdf, 0x3fffdf, 0x7fffeb, 0x7fffec, 0x1fffe0, 0x1fffe1, 0x3fffe0,
+    0x1fffe2, 0x7fffed, 0x3fffe1, 0x7fffee, 0x7fffef, 0xfffea, 0x3fffe2, 0x3fffe3, 0x3fffe4,
+    0x7ffff0, 0x3fffe5, 0x3fffe6, 0x7ffff1, 0x3ffffe0, 0x3ffffe1, 0xfffeb, 0x7fff1, 0x3fffe7,
+    0x7ffff2, 0x3fffe8, 0x1ffffec, 0x3ffffe2, 0x3ffffe3, 0x3ffffe4, 0x7ffffde, 0x7ffffdf,
+    0x3ffffe5, 0xfffff1, 0x1ffffed, 0x7fff2, 0x1fffe3, 0x3ffffe6, 0x7ffffe0, 0x7ffffe1, 0x3ffffe7,
+    0x7ffffe2, 0xfffff2, 0x1fffe4, 0x1fffe5, 0x3ffffe8, 0x3ffffe9, 0xffffffd, 0x7ffffe3, 0x7ffffe4,
+    0x7ffffe5, 0xfffec, 0xfffff3, 0xfffed, 0x1fffe6, 0x3fffe9, 0x1fffe7, 0x1fffe8, 0x7ffff3,
+    0x3fffea, 0x3fffeb, 0x1ffffee, 0x1ffffef, 0xfffff4, 0xfffff5, 0x3ffffea, 0x7ffff4, 0x3ffffeb,
+    0x7ffffe6, 0x3ffffec, 0x3ffffed, 0x7ffffe7, 0x7ffffe8, 0x7ffffe9, 0x7ffffea, 0x7ffffeb,
+    0xffffffe, 0x7ffffec, 0x7ffffed, 0x7ffffee, 0x7ffffef, 0x7fffff0, 0x3ffffee, 0x3fffffff,
+];
+/// Length in bits of each code of `HUFFMAN_CODES`.
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, 30,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn text(fields: &[Field]) -> Vec<(&str, &str)> {
+        fields
+            .iter()
+            .map(|(n, v)| {
+                (
+                    std::str::from_utf8(n).unwrap(),
+                    std::str::from_utf8(v).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_integers() {
+        // RFC 7541, C.1
+        for (value, prefix_bits, encoded) in [(10, 5, "0a"), (1337, 5, "1f9a0a"), (42, 8, "2a")] {
+            let mut block = Vec::new();
+            encode_integer(&mut block, 0, prefix_bits, value);
+            assert_eq!(block, hex(encoded));
+            assert_eq!(
+                decode_integer(&mut block.as_slice(), prefix_bits),
+                Ok(value)
+            );
+        }
+
+        assert_eq!(
+            decode_integer(&mut hex("1f9a").as_slice(), 5),
+            Err(HpackError::Truncated)
+        );
+        assert_eq!(
+            decode_integer(&mut hex("1fffffffffff01").as_slice(), 5),
+            Err(HpackError::IntegerOverflow)
+        );
+    }
+
+    #[test]
+    fn test_requests_with_huffman() {
+        // RFC 7541, C.4: three requests on one connection
+        let mut decoder = Decoder::new(4096);
+
+        let fields = decoder
+            .decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"), 1024)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            text(&fields),
+            [
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com")
+            ]
+        );
+        assert_eq!(decoder.size, 57);
+
+        let fields = decoder
+            .decode(&hex("8286 84be 5886 a8eb 1064 9cbf"), 1024)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            text(&fields)[3..],
+            [
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache")
+            ]
+        );
+        assert_eq!(decoder.size, 110);
+
+        let fields = decoder
+            .decode(
+                &hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+                1024,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            text(&fields),
+            [
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value")
+            ]
+        );
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let fields = [
+            (":status", "200"),
+            (":status", "413"),
+            ("content-type", "text/plain"),
+            ("x-custom", "\u{e9}t\u{e9}"),
+            ("etag", "\"1a-5f3e8c2b.0\""),
+        ];
+        let block = encode(fields);
+        // Indexed, then literals with indexed names: nothing goes to the dynamic table
+        assert_eq!(block[0], 0x88);
+
+        let mut decoder = Decoder::new(4096);
+        let decoded = decoder.decode(&block, 1024).unwrap().unwrap();
+        assert_eq!(text(&decoded), fields);
+        assert_eq!(decoder.size, 0);
+    }
+
+    #[test]
+    fn test_huffman_padding() {
+        let www = hex("f1e3 c2e5 f23a 6ba0 ab90 f4ff");
+        assert_eq!(huffman_decode(&www).unwrap(), b"www.example.com");
+
+        let mut too_long = www.clone();
+        too_long.push(0xff);
+        assert_eq!(huffman_decode(&too_long), Err(HpackError::Huffman));
+
+        let mut not_eos = www;
+        *not_eos.last_mut().unwrap() = 0xfe;
+        assert_eq!(huffman_decode(&not_eos), Err(HpackError::Huffman));
+    }
+
+    #[test]
+    fn test_dynamic_table_size() {
+        let mut decoder = Decoder::new(4096);
+        // :authority www.example.com, added to the table (57 bytes)
+        let authority = hex("41 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff");
+
+        // Too large for the list, but the table follows all the same
+        assert_eq!(decoder.decode(&authority, 40), Ok(None));
+        assert_eq!(decoder.size, 57);
+
+        // Shrinking the table evicts
+        assert_eq!(decoder.decode(&hex("3f 11"), 1024), Ok(Some(vec![])));
+        assert_eq!(decoder.size, 0);
+        assert_eq!(
+            decoder.decode(&hex("be"), 1024),
+            Err(HpackError::InvalidIndex(62))
+        );
+
+        // Updates come first, and within the advertised size
+        assert_eq!(
+            decoder.decode(&hex("82 20"), 1024),
+            Err(HpackError::TableSizeUpdate(0))
+        );
+        assert_eq!(
+            decoder.decode(&hex("3fe2 1f"), 1024),
+            Err(HpackError::TableSizeUpdate(4097))
+        );
+    }
+}
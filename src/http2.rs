@@ -0,0 +1,1561 @@
+use crate::chunked;
+use crate::handler::Handler;
+use crate::headers::Headers;
+use crate::hpack::{self, Decoder, Field};
+use crate::http_commons::HttpVersion;
+use crate::http_request::{self, HttpRequest, Limits, RequestError};
+use crate::http_response::{HttpResponse, StatusCode};
+use crate::server;
+use crate::shutdown::Guard;
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, Scope};
+
+// HTTP/2 over cleartext TCP, "h2c" (RFC 9113). A connection gets there in one of two ways:
+//
+//   prior knowledge   the client opens with the connection preface, whose first line reads as
+//                     an HTTP/1 request-line: `PRI * HTTP/2.0`
+//   Upgrade: h2c      an HTTP/1.1 request asks to switch (RFC 7540, section 3.2): it's answered
+//                     `101 Switching Protocols`, then over HTTP/2 as stream 1
+//
+// Both sides then send their SETTINGS, and everything else is frames:
+//
+//   length (24) | type (8) | flags (8) | R (1), stream identifier (31) | payload
+//
+// Requests come as HEADERS (+ CONTINUATION) then DATA frames on odd-numbered streams, several at
+// once. The connection's thread reads every frame; each complete request is handled on a thread
+// of its own, which writes its response frames in between those of other streams. DATA is sent
+// within the flow-control windows the client grants, a response waiting for WINDOW_UPDATE when
+// they run out. DATA received is granted back right away: `max_body` is what bounds a request.
+//
+// On shutdown the server sends GOAWAY with the last stream it took: the requests up to it get
+// their responses, those above are ignored, and the connection closes once they are done.
+
+/// What a client sends first once it speaks HTTP/2: an HTTP/1 request-line that no HTTP/1
+/// server would take, then `SM`.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Length of what an HTTP/1 parser reads of the preface: the request-line and an empty line.
+const PREFACE_HEAD_LEN: usize = 18;
+
+const FRAME_HEADER_LEN: usize = 9;
+
+// Frame types (section 6)
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+// Settings parameters (section 6.5.2)
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Streams of a connection underway at once, each handled on a thread of its own: few, as
+/// those threads are on top of the pool's. Advertised in the server's SETTINGS.
+const MAX_CONCURRENT_STREAMS: usize = 8;
+/// Largest frame either side may send until told otherwise, the only size the server accepts.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// HPACK dynamic table size: the default, which the server never changes.
+const HEADER_TABLE_SIZE: usize = 4096;
+
+/// Error codes of RST_STREAM and GOAWAY frames (section 7).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    FlowControlError = 0x3,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    CompressionError = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+#[derive(Debug)]
+pub(crate) enum Http2Error {
+    Io(io::Error),
+    /// An error of the whole connection (section 5.4.1): it's ended with a GOAWAY.
+    Connection(ErrorCode, &'static str),
+}
+
+impl From<io::Error> for Http2Error {
+    fn from(e: io::Error) -> Http2Error {
+        Http2Error::Io(e)
+    }
+}
+
+impl fmt::Display for Http2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Http2Error::Io(e) => write!(f, "I/O on the HTTP/2 connection: {e}"),
+            Http2Error::Connection(code, reason) => {
+                write!(f, "HTTP/2 connection error ({code:?}): {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Http2Error {}
+
+fn protocol_error(reason: &'static str) -> Http2Error {
+    Http2Error::Connection(ErrorCode::ProtocolError, reason)
+}
+
+/// Whether the request switches the connection to HTTP/2: the request-line of the preface, or
+/// an HTTP/1.1 request asking to upgrade.
+pub(crate) fn switches(http_request: &HttpRequest) -> bool {
+    // The parser only lets `PRI * HTTP/2.0` through as HTTP/2
+    http_request.protocol_version == HttpVersion::Http2 || upgrade_settings(http_request).is_some()
+}
+
+/// The client's settings if the request asks to upgrade to h2c: `Upgrade: h2c` and exactly one
+/// valid `HTTP2-Settings`, both named in `Connection`. Anything less is answered as HTTP/1.1.
+fn upgrade_settings(http_request: &HttpRequest) -> Option<Vec<u8>> {
+    let has_option = |name: &str, option: &str| {
+        http_request
+            .headers
+            .get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|o| o.trim().eq_ignore_ascii_case(option))
+    };
+    if http_request.protocol_version != HttpVersion::Http11
+        || !has_option("upgrade", "h2c")
+        || !has_option("connection", "upgrade")
+        || !has_option("connection", "http2-settings")
+    {
+        return None;
+    }
+
+    let mut values = http_request.headers.get_all("http2-settings");
+    let (Some(settings), None) = (values.next(), values.next()) else {
+        return None;
+    };
+    base64url_decode(settings).filter(|settings| settings.len() % 6 == 0)
+}
+
+/// Decodes the URL-safe base64 of `HTTP2-Settings`, padded or not.
+fn base64url_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut bits, mut n_bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6 | u32::from(value)) & 0xffff;
+        n_bits += 6;
+        if n_bits >= 8 {
+            n_bits -= 8;
+            decoded.push((bits >> n_bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// Speaks HTTP/2 on the connection until the client closes it or the server shuts down, every
+/// request going to `handler`. `http_request` is the one that `switches` the connection: after an
+/// upgrade, it's answered as stream 1. `tracked` is the connection as the shutdown knows it.
+/// Returns once the responses underway are sent.
+/// # Errors
+/// Returns an `Http2Error` on I/O errors and protocol violations, the latter after a GOAWAY.
+pub(crate) fn serve<R: BufRead, W: Write + Send>(
+    reader: &mut R,
+    writer: W,
+    handler: &dyn Handler,
+    limits: &Limits,
+    http_request: HttpRequest,
+    tracked: &Guard,
+) -> Result<(), Http2Error> {
+    let connection = Connection::new(writer);
+
+    thread::scope(|scope| {
+        let mut session = Session {
+            scope,
+            connection: &connection,
+            handler,
+            limits,
+            tracked,
+            decoder: Decoder::new(HEADER_TABLE_SIZE),
+            incoming: HashMap::new(),
+            pending: None,
+            last_stream_id: 0,
+            going_away: None,
+        };
+        let result = session.run(reader, http_request);
+
+        if let Err(Http2Error::Connection(code, reason)) = &result {
+            eprintln!("HTTP/2 connection error ({code:?}): {reason}");
+            let _ = connection.write_goaway(session.last_stream_id, *code);
+        }
+        // No more WINDOW_UPDATE: responses that still need some can't be sent
+        connection.close();
+        result
+    })
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+/// Reads the next frame. `max_frame_size` is the SETTINGS_MAX_FRAME_SIZE advertised.
+fn read_frame<R: BufRead>(reader: &mut R, max_frame_size: usize) -> Result<Frame, Http2Error> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+
+    let len = usize::from(header[0]) << 16 | usize::from(header[1]) << 8 | usize::from(header[2]);
+    if len > max_frame_size {
+        return Err(Http2Error::Connection(
+            ErrorCode::FrameSizeError,
+            "frame larger than SETTINGS_MAX_FRAME_SIZE",
+        ));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+
+    Ok(Frame {
+        kind: header[3],
+        flags: header[4],
+        stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+        payload,
+    })
+}
+
+fn write_frame<W: Write + ?Sized>(
+    writer: &mut W,
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: &[u8],
+) -> io::Result<()> {
+    // One write per frame: frames of different streams must not interleave
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    frame.extend_from_slice(&[kind, flags]);
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// The payload without its padding, if the frame is padded.
+fn unpadded(payload: &[u8], flags: u8) -> Result<&[u8], Http2Error> {
+    if flags & FLAG_PADDED == 0 {
+        return Ok(payload);
+    }
+    let (&pad_len, rest) = payload
+        .split_first()
+        .ok_or(protocol_error("padded frame without a pad length"))?;
+    rest.len()
+        .checked_sub(usize::from(pad_len))
+        .map(|end| &rest[..end])
+        .ok_or(protocol_error("padding longer than the frame"))
+}
+
+/// The half of the connection shared with the threads answering its streams: the writing side.
+struct Connection<W> {
+    writer: Mutex<W>,
+    send: Mutex<SendWindows>,
+    /// Notified when a window grows, a response gets reset, or the connection is over.
+    send_changed: Condvar,
+}
+
+/// Flow control of what the server sends (section 5.2), and the client settings it goes with.
+struct SendWindows {
+    connection: i64,
+    /// Windows of the streams whose response is underway.
+    streams: HashMap<u32, i64>,
+    initial_window: i64,
+    max_frame_size: usize,
+    /// No more WINDOW_UPDATE can come.
+    closed: bool,
+}
+
+impl<W: Write> Connection<W> {
+    fn new(writer: W) -> Self {
+        Connection {
+            writer: Mutex::new(writer),
+            send: Mutex::new(SendWindows {
+                connection: DEFAULT_WINDOW,
+                streams: HashMap::new(),
+                initial_window: DEFAULT_WINDOW,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                closed: false,
+            }),
+            send_changed: Condvar::new(),
+        }
+    }
+
+    fn write_frame(&self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> io::Result<()> {
+        write_frame(
+            &mut *self.writer.lock().unwrap(),
+            kind,
+            flags,
+            stream_id,
+            payload,
+        )
+    }
+
+    fn write_settings(&self, limits: &Limits) -> io::Result<()> {
+        let mut payload = Vec::new();
+        for (id, value) in [
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, limits.max_header_bytes),
+        ] {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&u32::try_from(value).unwrap_or(u32::MAX).to_be_bytes());
+        }
+        self.write_frame(SETTINGS, 0, 0, &payload)
+    }
+
+    fn write_window_update(&self, stream_id: u32, increment: usize) -> io::Result<()> {
+        let increment = u32::try_from(increment).unwrap_or(u32::MAX);
+        self.write_frame(WINDOW_UPDATE, 0, stream_id, &increment.to_be_bytes())
+    }
+
+    fn write_reset(&self, stream_id: u32, code: ErrorCode) -> io::Result<()> {
+        self.write_frame(RST_STREAM, 0, stream_id, &(code as u32).to_be_bytes())
+    }
+
+    fn write_goaway(&self, last_stream_id: u32, code: ErrorCode) -> io::Result<()> {
+        let mut payload = last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&(code as u32).to_be_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload)
+    }
+
+    /// A header block, in a HEADERS frame and as many CONTINUATION frames as the client's
+    /// frame size requires, back to back: nothing may come in between.
+    fn write_headers(&self, stream_id: u32, block: &[u8], end_stream: bool) -> io::Result<()> {
+        let max_frame_size = self.send.lock().unwrap().max_frame_size;
+        let mut writer = self.writer.lock().unwrap();
+
+        let mut fragments = block.chunks(max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+        while let Some(fragment) = fragments.next() {
+            if fragments.peek().is_none() {
+                flags |= FLAG_END_HEADERS;
+            }
+            write_frame(&mut *writer, kind, flags, stream_id, fragment)?;
+            (kind, flags) = (CONTINUATION, 0);
+        }
+        Ok(())
+    }
+
+    /// Applies the client's SETTINGS parameters.
+    fn apply_settings(&self, payload: &[u8]) -> Result<(), Http2Error> {
+        if payload.len() % 6 != 0 {
+            return Err(Http2Error::Connection(
+                ErrorCode::FrameSizeError,
+                "SETTINGS payload not a multiple of 6 bytes",
+            ));
+        }
+        let mut send = self.send.lock().unwrap();
+        for setting in payload.chunks_exact(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(protocol_error("invalid SETTINGS_ENABLE_PUSH"));
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = i64::from(value);
+                    if value > MAX_WINDOW {
+                        return Err(Http2Error::Connection(
+                            ErrorCode::FlowControlError,
+                            "SETTINGS_INITIAL_WINDOW_SIZE too large",
+                        ));
+                    }
+                    // Applies to the windows of the streams underway too
+                    let delta = value - send.initial_window;
+                    for window in send.streams.values_mut() {
+                        *window += delta;
+                    }
+                    send.initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(16_384..=16_777_215).contains(&value) {
+                        return Err(protocol_error("invalid SETTINGS_MAX_FRAME_SIZE"));
+                    }
+                    send.max_frame_size = value as usize;
+                }
+                // The encoder keeps no dynamic table, and the responses are never pushed
+                _ => {}
+            }
+        }
+        self.send_changed.notify_all();
+        Ok(())
+    }
+
+    /// Adds to a window, `false` if that takes it over the maximum. Windows of streams that
+    /// aren't sending are left alone.
+    fn grow_window(&self, stream_id: u32, increment: i64) -> bool {
+        let mut send = self.send.lock().unwrap();
+        let window = if stream_id == 0 {
+            &mut send.connection
+        } else {
+            match send.streams.get_mut(&stream_id) {
+                Some(window) => window,
+                None => return true,
+            }
+        };
+        *window += increment;
+        if *window > MAX_WINDOW {
+            return false;
+        }
+        self.send_changed.notify_all();
+        true
+    }
+
+    fn open_stream(&self, stream_id: u32) {
+        let mut send = self.send.lock().unwrap();
+        let window = send.initial_window;
+        send.streams.insert(stream_id, window);
+    }
+
+    /// The response is sent, or must stop: reset by the client.
+    fn close_stream(&self, stream_id: u32) {
+        self.send.lock().unwrap().streams.remove(&stream_id);
+        self.send_changed.notify_all();
+    }
+
+    fn close(&self) {
+        self.send.lock().unwrap().closed = true;
+        self.send_changed.notify_all();
+    }
+
+    fn streams_underway(&self) -> usize {
+        self.send.lock().unwrap().streams.len()
+    }
+
+    /// Waits for the stream to be allowed to send, and takes up to `wanted` bytes (and a frame)
+    /// off its windows.
+    fn reserve(&self, stream_id: u32, wanted: usize) -> io::Result<usize> {
+        let mut send = self.send.lock().unwrap();
+        loop {
+            let Some(&stream_window) = send.streams.get(&stream_id) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "stream reset by the client",
+                ));
+            };
+            let window = send.connection.min(stream_window);
+            if window > 0 {
+                let n = wanted
+                    .min(send.max_frame_size)
+                    .min(usize::try_from(window).unwrap_or(usize::MAX));
+                send.connection -= n as i64;
+                send.streams.insert(stream_id, stream_window - n as i64);
+                return Ok(n);
+            }
+            if send.closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection closed while waiting for flow-control window",
+                ));
+            }
+            send = self.send_changed.wait(send).unwrap();
+        }
+    }
+
+    /// Sends the response as HEADERS, DATA and, for trailers, HEADERS again. `reset` ends the
+    /// stream from the server's side too, for a request answered before it was all received.
+    fn send_response(
+        &self,
+        stream_id: u32,
+        mut response: HttpResponse,
+        reset: bool,
+    ) -> io::Result<()> {
+        response.set_protocol_version(HttpVersion::Http2);
+        let has_body = response.body.is_some() && !response.omit_body;
+        let trailers = if has_body {
+            std::mem::take(&mut response.trailers)
+        } else {
+            Headers::new()
+        };
+
+        // Framing is up to DATA frames, and connection-specific fields are forbidden
+        let status = response.status_code.code().to_string();
+        let content_length = response
+            .body_length()
+            .filter(|_| {
+                !matches!(
+                    response.status_code,
+                    StatusCode::NoContent | StatusCode::NotModified
+                )
+            })
+            .map(|length| length.to_string());
+        let mut fields = vec![(":status", status.as_str())];
+        fields.extend(response.headers.iter().filter(|(name, _)| {
+            !is_connection_specific(name) && !matches!(*name, "content-length" | "trailer")
+        }));
+        if let Some(content_length) = &content_length {
+            fields.push(("content-length", content_length));
+        }
+        let end_stream = !has_body && trailers.is_empty();
+        self.write_headers(stream_id, &hpack::encode(fields), end_stream)?;
+
+        if has_body {
+            response.write_body_to(DataWriter {
+                connection: self,
+                stream_id,
+            })?;
+        }
+        if !trailers.is_empty() {
+            self.write_headers(stream_id, &hpack::encode(trailers.iter()), true)?;
+        } else if !end_stream {
+            self.write_frame(DATA, FLAG_END_STREAM, stream_id, &[])?;
+        }
+
+        if reset {
+            self.write_reset(stream_id, ErrorCode::NoError)?;
+        }
+        Ok(())
+    }
+}
+
+/// Body of a response, as DATA frames within the flow-control windows.
+struct DataWriter<'a, W> {
+    connection: &'a Connection<W>,
+    stream_id: u32,
+}
+
+impl<W: Write> Write for DataWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.connection.reserve(self.stream_id, buf.len())?;
+        self.connection
+            .write_frame(DATA, 0, self.stream_id, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fields that only make sense for one HTTP/1 connection (section 8.2.2).
+fn is_connection_specific(name: &str) -> bool {
+    matches!(
+        name,
+        "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade"
+    )
+}
+
+/// A request whose header block came, waiting for the rest of its DATA.
+struct Incoming {
+    request: HttpRequest,
+    body: Option<Vec<u8>>,
+}
+
+/// A header block spread over several frames.
+struct PendingBlock {
+    stream_id: u32,
+    fragment: Vec<u8>,
+    end_stream: bool,
+}
+
+/// Why a request won't be handled: malformed as HTTP/2, and its stream reset, or refused the
+/// way an HTTP/1 request would be.
+enum Rejection {
+    Malformed(&'static str),
+    Request(RequestError),
+}
+
+impl From<RequestError> for Rejection {
+    fn from(e: RequestError) -> Rejection {
+        Rejection::Request(e)
+    }
+}
+
+/// The reading half of the connection, on the connection's own thread.
+struct Session<'scope, 'env, W> {
+    scope: &'scope Scope<'scope, 'env>,
+    connection: &'env Connection<W>,
+    handler: &'env dyn Handler,
+    limits: &'env Limits,
+    tracked: &'env Guard<'env>,
+    decoder: Decoder,
+    incoming: HashMap<u32, Incoming>,
+    pending: Option<PendingBlock>,
+    /// Highest stream the client opened: streams below it are closed, those above idle.
+    last_stream_id: u32,
+    /// The last stream taken, told in the GOAWAY sent on shutdown: those above are ignored.
+    going_away: Option<u32>,
+}
+
+impl<'scope, 'env, W: Write + Send> Session<'scope, 'env, W> {
+    fn run<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        http_request: HttpRequest,
+    ) -> Result<(), Http2Error> {
+        let expected_preface = match upgrade_settings(&http_request) {
+            // Prior knowledge: the rest of the preface follows its request-line
+            None => {
+                self.connection.write_settings(self.limits)?;
+                &PREFACE[PREFACE_HEAD_LEN..]
+            }
+            Some(settings) => {
+                let mut switching = HttpResponse::new_interim(StatusCode::SwitchingProtocols);
+                switching.headers.insert("connection", "upgrade");
+                switching.headers.insert("upgrade", "h2c");
+                switching.write_to(&mut *self.connection.writer.lock().unwrap())?;
+                self.connection.write_settings(self.limits)?;
+
+                // The client's settings are those of HTTP2-Settings, and its request is stream 1
+                self.connection.apply_settings(&settings)?;
+                self.last_stream_id = 1;
+                self.dispatch(1, Ok(http_request), false);
+                PREFACE
+            }
+        };
+
+        let mut preface = vec![0; expected_preface.len()];
+        reader.read_exact(&mut preface)?;
+        if preface != expected_preface {
+            return Err(protocol_error("invalid connection preface"));
+        }
+        // The preface ends with the client's SETTINGS
+        let settings = read_frame(reader, DEFAULT_MAX_FRAME_SIZE)?;
+        if settings.kind != SETTINGS || settings.flags & FLAG_ACK != 0 {
+            return Err(protocol_error("connection preface without SETTINGS"));
+        }
+        self.on_frame(settings)?;
+
+        while self.wait_for_frame(reader)? {
+            let frame = read_frame(reader, DEFAULT_MAX_FRAME_SIZE)?;
+            self.on_frame(frame)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the next frame to start, `false` when the client closed the connection or left
+    /// it idle past the read timeout, or the server shuts down and the requests taken are all
+    /// answered. Responses underway keep the connection from being idle.
+    fn wait_for_frame<R: BufRead>(&mut self, reader: &mut R) -> Result<bool, Http2Error> {
+        loop {
+            let underway = !self.incoming.is_empty() || self.connection.streams_underway() > 0;
+            if !underway {
+                // A shutdown ends the reads of an idle connection, not its GOAWAY
+                if self.going_away.is_some() || !self.tracked.idle_reading() {
+                    self.go_away()?;
+                    return Ok(false);
+                }
+            } else if self.tracked.is_shutting_down() {
+                self.go_away()?;
+            }
+
+            match reader.fill_buf() {
+                Ok([]) if self.tracked.is_shutting_down() => {
+                    self.go_away()?;
+                    return Ok(false);
+                }
+                Ok(buf) => {
+                    let started = !buf.is_empty();
+                    self.tracked.busy();
+                    return Ok(started);
+                }
+                // Clients often reset the connection rather than close it once done
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if self.connection.streams_underway() == 0 {
+                        self.go_away()?;
+                        return Ok(false);
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Tells the client, once, that the streams above the last one opened won't be handled.
+    fn go_away(&mut self) -> Result<(), Http2Error> {
+        if self.going_away.is_none() {
+            self.going_away = Some(self.last_stream_id);
+            self.connection
+                .write_goaway(self.last_stream_id, ErrorCode::NoError)?;
+        }
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<(), Http2Error> {
+        // Nothing may come in the middle of a header block (section 6.10)
+        if let Some(pending) = &mut self.pending {
+            if frame.kind != CONTINUATION || frame.stream_id != pending.stream_id {
+                return Err(protocol_error("header block interrupted"));
+            }
+            pending.fragment.extend_from_slice(&frame.payload);
+            if pending.fragment.len() > self.limits.max_header_bytes {
+                return Err(Http2Error::Connection(
+                    ErrorCode::EnhanceYourCalm,
+                    "header block too large",
+                ));
+            }
+            if frame.flags & FLAG_END_HEADERS != 0 {
+                if let Some(block) = self.pending.take() {
+                    self.on_header_block(block)?;
+                }
+            }
+            return Ok(());
+        }
+
+        match frame.kind {
+            DATA => self.on_data(&frame),
+            HEADERS => self.on_headers(&frame),
+            PRIORITY => self.on_priority(&frame),
+            RST_STREAM => self.on_reset(&frame),
+            SETTINGS => self.on_settings(&frame),
+            PUSH_PROMISE => Err(protocol_error("PUSH_PROMISE from a client")),
+            PING => self.on_ping(&frame),
+            // The client is done: the streams underway still get their responses
+            GOAWAY if frame.stream_id == 0 => Ok(()),
+            GOAWAY => Err(protocol_error("GOAWAY on a stream")),
+            WINDOW_UPDATE => self.on_window_update(&frame),
+            CONTINUATION => Err(protocol_error("CONTINUATION without HEADERS")),
+            // Unknown frame types are ignored (section 5.5)
+            _ => Ok(()),
+        }
+    }
+
+    fn on_headers(&mut self, frame: &Frame) -> Result<(), Http2Error> {
+        let stream_id = frame.stream_id;
+        if stream_id == 0 {
+            return Err(protocol_error("HEADERS on stream 0"));
+        }
+        // Either trailers of a request being received, or a new stream: odd and above the rest
+        if !self.incoming.contains_key(&stream_id) {
+            if stream_id % 2 == 0 || stream_id <= self.last_stream_id {
+                return Err(protocol_error("HEADERS on a closed or server stream"));
+            }
+            self.last_stream_id = stream_id;
+        }
+
+        let mut fragment = unpadded(&frame.payload, frame.flags)?;
+        if frame.flags & FLAG_PRIORITY != 0 {
+            // Priorities are advisory, and not followed
+            fragment = fragment
+                .get(5..)
+                .ok_or(protocol_error("HEADERS too short for its priority"))?;
+        }
+        let block = PendingBlock {
+            stream_id,
+            fragment: fragment.to_vec(),
+            end_stream: frame.flags & FLAG_END_STREAM != 0,
+        };
+        if frame.flags & FLAG_END_HEADERS != 0 {
+            self.on_header_block(block)
+        } else {
+            self.pending = Some(block);
+            Ok(())
+        }
+    }
+
+    fn on_header_block(&mut self, block: PendingBlock) -> Result<(), Http2Error> {
+        let PendingBlock {
+            stream_id,
+            fragment,
+            end_stream,
+        } = block;
+        // Decoded whatever becomes of the stream: the dynamic table must follow the client's
+        let fields = self
+            .decoder
+            .decode(&fragment, self.limits.max_header_bytes)
+            .map_err(|e| {
+                eprintln!("error decoding a header block: {e}");
+                Http2Error::Connection(ErrorCode::CompressionError, "invalid header block")
+            })?;
+
+        if let Some(mut incoming) = self.incoming.remove(&stream_id) {
+            let trailers = match (end_stream, fields) {
+                (false, _) => Err(Rejection::Malformed("trailers without END_STREAM")),
+                (true, None) => Err(Rejection::Request(RequestError::HeaderFieldsTooLarge)),
+                (true, Some(fields)) => trailers_from_fields(fields),
+            };
+            return match trailers {
+                Ok(trailers) => {
+                    incoming.request.trailers = trailers;
+                    self.complete(stream_id, incoming)
+                }
+                Err(rejection) => self.reject(stream_id, rejection, false),
+            };
+        }
+
+        // Decoded all the same, but past the GOAWAY: ignored
+        if self.going_away.is_some_and(|last| stream_id > last) {
+            return Ok(());
+        }
+        if self.incoming.len() + self.connection.streams_underway() >= MAX_CONCURRENT_STREAMS {
+            self.connection
+                .write_reset(stream_id, ErrorCode::RefusedStream)?;
+            return Ok(());
+        }
+        let request = match fields {
+            None => Err(Rejection::Request(RequestError::HeaderFieldsTooLarge)),
+            Some(fields) => request_from_fields(fields, self.limits),
+        };
+        match request {
+            Ok(request) => {
+                let incoming = Incoming {
+                    request,
+                    body: None,
+                };
+                if end_stream {
+                    self.complete(stream_id, incoming)
+                } else {
+                    self.incoming.insert(stream_id, incoming);
+                    Ok(())
+                }
+            }
+            Err(rejection) => self.reject(stream_id, rejection, !end_stream),
+        }
+    }
+
+    fn on_data(&mut self, frame: &Frame) -> Result<(), Http2Error> {
+        let stream_id = frame.stream_id;
+        if stream_id == 0 || stream_id > self.last_stream_id {
+            return Err(protocol_error("DATA on stream 0 or an idle stream"));
+        }
+        let end_stream = frame.flags & FLAG_END_STREAM != 0;
+
+        // What's received is granted back right away, padding included
+        let received = frame.payload.len();
+        if received > 0 {
+            self.connection.write_window_update(0, received)?;
+        }
+        let data = unpadded(&frame.payload, frame.flags)?;
+
+        // A stream closed, reset or answered already: its data is dropped
+        let Some(incoming) = self.incoming.get_mut(&stream_id) else {
+            return Ok(());
+        };
+        let body = incoming.body.get_or_insert_with(Vec::new);
+        if body.len() + data.len() > self.limits.max_body {
+            self.incoming.remove(&stream_id);
+            let rejection = Rejection::Request(RequestError::ContentTooLarge);
+            return self.reject(stream_id, rejection, !end_stream);
+        }
+        body.extend_from_slice(data);
+
+        if end_stream {
+            if let Some(incoming) = self.incoming.remove(&stream_id) {
+                self.complete(stream_id, incoming)?;
+            }
+        } else if received > 0 {
+            self.connection.write_window_update(stream_id, received)?;
+        }
+        Ok(())
+    }
+
+    fn on_priority(&mut self, frame: &Frame) -> Result<(), Http2Error> {
+        if frame.stream_id == 0 {
+            return Err(protocol_error("PRIORITY on stream 0"));
+        }
+        if frame.payload.len() != 5 {
+            self.incoming.remove(&frame.stream_id);
+            self.connection
+                .write_reset(frame.stream_id, ErrorCode::FrameSizeError)?;
+        }
+        Ok(())
+    }
+
+    fn on_reset(&mut self, frame: &Frame) -> Result<(), Http2Error> {
+        if frame.stream_id == 0 || frame.stream_id > self.last_stream_id {
+            return Err(protocol_error("RST_STREAM on stream 0 or an idle stream"));
+        }
+        if frame.payload.len() != 4 {
+            return Err(Http2Error::Connection(
+                ErrorCode::FrameSizeError,
+                "RST_STREAM payload not 4 bytes",
+            ));
+        }
+        self.incoming.remove(&frame.stream_id);
+        self.connection.close_stream(frame.stream_id);
+        Ok(())
+    }
+
+    fn on_settings(&mut self, frame: &Frame) -> Result<(), Http2Error> {
+        if frame.stream_id != 0 {
+            return Err(protocol_error("SETTINGS on a stream"));
+        }
+        if frame.flags & FLAG_ACK != 0 {
+            if !frame.payload.is_empty() {
+                return Err(Http2Error::Connection(
+                    ErrorCode::FrameSizeError,
+                    "SETTINGS acknowledgment with a payload",
+                ));
+            }
+            return Ok(());
+        }
+        self.connection.apply_settings(&frame.payload)?;
+        self.connection.write_frame(SETTINGS, FLAG_ACK, 0, &[])?;
+        Ok(())
+    }
+
+    fn on_ping(&mut self, frame: &Frame) -> Result<(), Http2Error> {
+        if frame.stream_id != 0 {
+            return Err(protocol_error("PING on a stream"));
+        }
+        if frame.payload.len() != 8 {
+            return Err(Http2Error::Connection(
+                ErrorCode::FrameSizeError,
+                "PING payload not 8 bytes",
+            ));
+        }
+        if frame.flags & FLAG_ACK == 0 {
+            self.connection
+                .write_frame(PING, FLAG_ACK, 0, &frame.payload)?;
+        }
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: &Frame) -> Result<(), Http2Error> {
+        let stream_id = frame.stream_id;
+        if frame.payload.len() != 4 {
+            return Err(Http2Error::Connection(
+                ErrorCode::FrameSizeError,
+                "WINDOW_UPDATE payload not 4 bytes",
+            ));
+        }
+        if stream_id > self.last_stream_id {
+            return Err(protocol_error("WINDOW_UPDATE on an idle stream"));
+        }
+        let increment = u32::from_be_bytes([
+            frame.payload[0],
+            frame.payload[1],
+            frame.payload[2],
+            frame.payload[3],
+        ]) & 0x7fff_ffff;
+
+        let error = match (increment, stream_id) {
+            (0, 0) => return Err(protocol_error("WINDOW_UPDATE of 0")),
+            (0, _) => ErrorCode::ProtocolError,
+            _ if self.connection.grow_window(stream_id, i64::from(increment)) => return Ok(()),
+            (_, 0) => {
+                return Err(Http2Error::Connection(
+                    ErrorCode::FlowControlError,
+                    "connection window over 2^31-1",
+                ))
+            }
+            _ => ErrorCode::FlowControlError,
+        };
+        // Errors of one stream only end that stream
+        self.incoming.remove(&stream_id);
+        self.connection.close_stream(stream_id);
+        self.connection.write_reset(stream_id, error)?;
+        Ok(())
+    }
+
+    /// The request is all there: checks its length and hands it over.
+    fn complete(&mut self, stream_id: u32, incoming: Incoming) -> Result<(), Http2Error> {
+        let Incoming { mut request, body } = incoming;
+        // A content-length must match the DATA received (section 8.1.1)
+        if let Some(content_length) = request.headers.get("content-length") {
+            let received = body.as_ref().map_or(0, Vec::len);
+            let length = Some(content_length)
+                .filter(|length| http_request::is_content_length(length))
+                .and_then(|length| length.parse::<usize>().ok());
+            if length != Some(received) {
+                let rejection = Rejection::Malformed("content-length not matching the DATA");
+                return self.reject(stream_id, rejection, false);
+            }
+        }
+        request.body = body.map(Bytes::from);
+        self.dispatch(stream_id, Ok(request), false);
+        Ok(())
+    }
+
+    /// Resets the stream of a malformed request, or answers it like a bad HTTP/1 request.
+    fn reject(
+        &mut self,
+        stream_id: u32,
+        rejection: Rejection,
+        reset: bool,
+    ) -> Result<(), Http2Error> {
+        match rejection {
+            Rejection::Malformed(reason) => {
+                eprintln!("malformed request on stream {stream_id}: {reason}");
+                self.connection
+                    .write_reset(stream_id, ErrorCode::ProtocolError)?;
+            }
+            Rejection::Request(e) => {
+                eprintln!("error parsing the http-request on stream {stream_id}: {e}");
+                let response = HttpResponse::new_from_bad_request(&e);
+                self.dispatch(stream_id, Err(response), reset);
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers the stream from a thread of its own: runs the handler on the request, unless
+    /// it's answered already, and sends the response.
+    fn dispatch(&self, stream_id: u32, request: Result<HttpRequest, HttpResponse>, reset: bool) {
+        let connection = self.connection;
+        let handler = self.handler;
+        connection.open_stream(stream_id);
+
+        self.scope.spawn(move || {
+            let response = match request {
+                Ok(http_request) => {
+                    println!("Parsed http-request on stream {stream_id}: {http_request:?}\n");
+                    server::run_handler(handler, &http_request)
+                }
+                Err(response) => response,
+            };
+            println!(
+                "Built http-response (status code: {}) on stream {stream_id}\n",
+                response.status_code
+            );
+            if let Err(e) = connection.send_response(stream_id, response, reset) {
+                eprintln!("Error sending the response on stream {stream_id}: {e}");
+            }
+            connection.close_stream(stream_id);
+        });
+    }
+}
+
+/// The request of a header block: pseudo-header fields first (section 8.3.1), then the regular
+/// ones, lower-case and without connection-specific fields.
+fn request_from_fields(fields: Vec<Field>, limits: &Limits) -> Result<HttpRequest, Rejection> {
+    let (mut method, mut path, mut scheme, mut authority) = (None, None, None, None);
+    let mut headers = Headers::new();
+
+    for (name, value) in fields {
+        let (Ok(name), Ok(value)) = (String::from_utf8(name), String::from_utf8(value)) else {
+            return Err(Rejection::Malformed("header field not UTF-8"));
+        };
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if !headers.is_empty() {
+                return Err(Rejection::Malformed(
+                    "pseudo-header field after regular ones",
+                ));
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                _ => return Err(Rejection::Malformed("unknown pseudo-header field")),
+            };
+            if slot.replace(value).is_some() {
+                return Err(Rejection::Malformed("repeated pseudo-header field"));
+            }
+            continue;
+        }
+
+        check_field(&name, &value)?;
+        if headers.len() == limits.max_headers {
+            return Err(RequestError::HeaderFieldsTooLarge.into());
+        }
+        headers.append(&name, &value);
+    }
+
+    // CONNECT, the one method without :scheme and :path, isn't implemented
+    let (Some(method), Some(path), Some(_)) = (method, path, scheme) else {
+        return Err(Rejection::Malformed("missing pseudo-header field"));
+    };
+    // :authority stands for Host
+    if let Some(authority) = authority {
+        if !headers.contains("host") {
+            headers.insert("host", &authority);
+        }
+    }
+
+    let method = method.parse().map_err(RequestError::from)?;
+    let mut http_request = HttpRequest::new(method, &path)?;
+    http_request.protocol_version = HttpVersion::Http2;
+    http_request.headers = headers;
+    Ok(http_request)
+}
+
+fn trailers_from_fields(fields: Vec<Field>) -> Result<Headers, Rejection> {
+    let mut trailers = Headers::new();
+    for (name, value) in fields {
+        let (Ok(name), Ok(value)) = (String::from_utf8(name), String::from_utf8(value)) else {
+            return Err(Rejection::Malformed("trailer field not UTF-8"));
+        };
+        if name.starts_with(':') {
+            return Err(Rejection::Malformed("pseudo-header field in trailers"));
+        }
+        check_field(&name, &value)?;
+        trailers.append(&name, &value);
+    }
+    Ok(trailers)
+}
+
+/// A regular field name is a lower-case token, and HTTP/1 connection management stays out.
+fn check_field(name: &str, value: &str) -> Result<(), Rejection> {
+    if name.is_empty()
+        || !name.bytes().all(chunked::is_tchar)
+        || name.bytes().any(|b| b.is_ascii_uppercase())
+    {
+        return Err(Rejection::Malformed("invalid field name"));
+    }
+    if is_connection_specific(name) || (name == "te" && !value.eq_ignore_ascii_case("trailers")) {
+        return Err(Rejection::Malformed("connection-specific field"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_request::HttpMethod;
+    use crate::http_response::{Buildable, Builder};
+    use crate::router::Router;
+    use crate::shutdown::{ServerHandle, Shutdown};
+    use std::io::Cursor;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, kind, flags, stream_id, payload).unwrap();
+        frame
+    }
+
+    fn request_block(method: &str, path: &str) -> Vec<u8> {
+        hpack::encode([
+            (":method", method),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", "localhost"),
+        ])
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/echo/:word", |req| {
+            let mut builder = HttpResponse::builder();
+            builder.with_body(req.path_params.get("word").unwrap_or_default().as_bytes());
+            builder.build()
+        });
+        router.post("/length", |req| {
+            let mut builder = HttpResponse::builder();
+            let length = req.body.as_ref().map_or(0, Bytes::len);
+            builder.with_body(length.to_string().as_bytes());
+            if let Some(checksum) = req.trailers.get("x-checksum") {
+                builder.with_trailer("x-checksum", checksum);
+            }
+            builder.build()
+        });
+        router
+    }
+
+    /// A connection tracked by `shutdown`, standing for the one the bytes come from.
+    fn tracked(shutdown: &Shutdown) -> Guard<'_> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        shutdown.track(&stream).unwrap()
+    }
+
+    /// Runs a connection (started with prior knowledge) on the client's bytes, returning the
+    /// result and the frames sent back.
+    fn exchange(client: &[u8]) -> (Result<(), Http2Error>, Vec<Frame>) {
+        let preface_line = "PRI * HTTP/2.0\r\n\r\n";
+        let http_request =
+            HttpRequest::read_head(&mut Cursor::new(preface_line), &Limits::default()).unwrap();
+        assert!(switches(&http_request));
+
+        let mut input = PREFACE[PREFACE_HEAD_LEN..].to_vec();
+        input.extend_from_slice(client);
+        let mut output = Vec::new();
+        let shutdown = Shutdown::default();
+        let result = serve(
+            &mut Cursor::new(input),
+            &mut output,
+            &router(),
+            &Limits::default(),
+            http_request,
+            &tracked(&shutdown),
+        );
+        (result, frames(&output))
+    }
+
+    fn frames(mut output: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while !output.is_empty() {
+            frames.push(read_frame(&mut output, usize::MAX).unwrap());
+        }
+        frames
+    }
+
+    /// Status, header fields and body of the response on the stream.
+    fn response(frames: &[Frame], stream_id: u32) -> (String, Vec<(String, String)>, Vec<u8>) {
+        let mut decoder = Decoder::new(HEADER_TABLE_SIZE);
+        let mut fields = Vec::new();
+        let mut body = Vec::new();
+        for frame in frames.iter().filter(|f| f.stream_id == stream_id) {
+            match frame.kind {
+                HEADERS | CONTINUATION => {
+                    let block = decoder.decode(&frame.payload, usize::MAX).unwrap().unwrap();
+                    fields.extend(block.into_iter().map(|(n, v)| {
+                        (String::from_utf8(n).unwrap(), String::from_utf8(v).unwrap())
+                    }));
+                }
+                DATA => body.extend_from_slice(&frame.payload),
+                _ => {}
+            }
+        }
+        let status = fields.remove(0).1;
+        (status, fields, body)
+    }
+
+    fn settings(pairs: &[(u16, u32)]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for (id, value) in pairs {
+            payload.extend_from_slice(&id.to_be_bytes());
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        frame(SETTINGS, 0, 0, &payload)
+    }
+
+    #[test]
+    fn test_prior_knowledge() {
+        let mut client = settings(&[]);
+        client.extend(frame(
+            HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            1,
+            &request_block("GET", "/echo/hi"),
+        ));
+        client.extend(frame(PING, 0, 0, b"12345678"));
+        let (result, frames) = exchange(&client);
+        result.unwrap();
+
+        // Settings first, then the acknowledgment of the client's
+        assert_eq!((frames[0].kind, frames[0].flags), (SETTINGS, 0));
+        assert_eq!((frames[1].kind, frames[1].flags), (SETTINGS, FLAG_ACK));
+        assert!(frames
+            .iter()
+            .any(|f| f.kind == PING && f.flags == FLAG_ACK && f.payload == b"12345678"));
+
+        let (status, fields, body) = response(&frames, 1);
+        assert_eq!(status, "200");
+        assert!(fields.contains(&("content-length".into(), "2".into())));
+        assert_eq!(body, b"hi");
+        let last = frames.iter().rfind(|f| f.stream_id == 1).unwrap();
+        assert_eq!((last.kind, last.flags), (DATA, FLAG_END_STREAM));
+    }
+
+    #[test]
+    fn test_multiplexed_streams() {
+        let mut client = settings(&[]);
+        // Stream 1: a body in two DATA frames, the first padded, and trailers
+        client.extend(frame(
+            HEADERS,
+            FLAG_END_HEADERS,
+            1,
+            &request_block("POST", "/length"),
+        ));
+        // Stream 3: a header block split over a CONTINUATION, while 1 is still open
+        let block = request_block("GET", "/echo/three");
+        client.extend(frame(HEADERS, FLAG_END_STREAM, 3, &block[..4]));
+        client.extend(frame(CONTINUATION, FLAG_END_HEADERS, 3, &block[4..]));
+        client.extend(frame(DATA, FLAG_PADDED, 1, b"\x03abcd\0\0\0"));
+        client.extend(frame(DATA, 0, 1, b"efg"));
+        client.extend(frame(
+            HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            1,
+            &hpack::encode([("x-checksum", "abc")]),
+        ));
+        let (result, frames) = exchange(&client);
+        result.unwrap();
+
+        let (status, _, body) = response(&frames, 3);
+        assert_eq!(
+            (status.as_str(), body.as_slice()),
+            ("200", b"three".as_slice())
+        );
+        let (status, fields, body) = response(&frames, 1);
+        assert_eq!((status.as_str(), body.as_slice()), ("200", b"7".as_slice()));
+        assert!(fields.contains(&("x-checksum".into(), "abc".into())));
+
+        // Received DATA is granted back, padding included
+        let granted: Vec<(u32, u32)> = frames
+            .iter()
+            .filter(|f| f.kind == WINDOW_UPDATE)
+            .map(|f| {
+                (
+                    f.stream_id,
+                    u32::from_be_bytes(f.payload[..4].try_into().unwrap()),
+                )
+            })
+            .collect();
+        assert_eq!(granted, [(0, 8), (1, 8), (0, 3), (1, 3)]);
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let body = "x".repeat(30);
+        let path = format!("/echo/{body}");
+        let data = |frames: &[Frame]| -> usize {
+            frames
+                .iter()
+                .filter(|f| f.kind == DATA && f.stream_id == 1)
+                .map(|f| f.payload.len())
+                .sum()
+        };
+
+        // A 10-byte window: the rest of the body waits for a WINDOW_UPDATE that never comes
+        let mut client = settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 10)]);
+        client.extend(frame(
+            HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            1,
+            &request_block("GET", &path),
+        ));
+        let (_, frames) = exchange(&client);
+        assert_eq!(data(&frames), 10);
+        assert!(!frames
+            .iter()
+            .any(|f| f.stream_id == 1 && f.flags & FLAG_END_STREAM != 0));
+
+        // Once it does, the body goes on
+        client.extend(frame(WINDOW_UPDATE, 0, 1, &20u32.to_be_bytes()));
+        let (_, frames) = exchange(&client);
+        assert_eq!(data(&frames), 30);
+        assert_eq!(response(&frames, 1).2, body.as_bytes());
+    }
+
+    #[test]
+    fn test_concurrent_streams() {
+        let (_, frames) = exchange(&settings(&[]));
+        let mut advertised = frames[0].payload.chunks(6).map(|setting| {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            (id, u32::from_be_bytes(setting[2..].try_into().unwrap()))
+        });
+        assert!(advertised.any(|setting| setting == (SETTINGS_MAX_CONCURRENT_STREAMS, 8)));
+
+        // Requests still being received count: the stream past the cap is refused
+        let mut client = settings(&[]);
+        for stream_id in (1..=2 * MAX_CONCURRENT_STREAMS as u32 + 1).step_by(2) {
+            client.extend(frame(
+                HEADERS,
+                FLAG_END_HEADERS,
+                stream_id,
+                &request_block("POST", "/length"),
+            ));
+        }
+        let (result, frames) = exchange(&client);
+        result.unwrap();
+        let resets: Vec<(u32, &[u8])> = frames
+            .iter()
+            .filter(|f| f.kind == RST_STREAM)
+            .map(|f| (f.stream_id, f.payload.as_slice()))
+            .collect();
+        let refused = (ErrorCode::RefusedStream as u32).to_be_bytes();
+        assert_eq!(resets, [(17, refused.as_slice())]);
+    }
+
+    #[test]
+    fn test_rejected_requests() {
+        let mut client = settings(&[]);
+        // Uppercase field name: malformed, the stream is reset
+        let mut block = request_block("GET", "/echo/a");
+        block.extend(hpack::encode([("X-Upper", "1")]));
+        client.extend(frame(
+            HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            1,
+            &block,
+        ));
+        // Connection-specific field: malformed too
+        let mut block = request_block("GET", "/echo/a");
+        block.extend(hpack::encode([("connection", "keep-alive")]));
+        client.extend(frame(
+            HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            3,
+            &block,
+        ));
+        // Invalid target: a 400, as in HTTP/1
+        client.extend(frame(
+            HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            5,
+            &request_block("GET", "/echo/%zz"),
+        ));
+        // Content-length not matching the DATA
+        let mut block = request_block("POST", "/length");
+        block.extend(hpack::encode([("content-length", "5")]));
+        client.extend(frame(HEADERS, FLAG_END_HEADERS, 7, &block));
+        client.extend(frame(DATA, FLAG_END_STREAM, 7, b"abc"));
+        // A signed content-length, though its number matches
+        let mut block = request_block("POST", "/length");
+        block.extend(hpack::encode([("content-length", "+3")]));
+        client.extend(frame(HEADERS, FLAG_END_HEADERS, 9, &block));
+        client.extend(frame(DATA, FLAG_END_STREAM, 9, b"abc"));
+        let (result, frames) = exchange(&client);
+        result.unwrap();
+
+        let resets: Vec<u32> = frames
+            .iter()
+            .filter(|f| f.kind == RST_STREAM)
+            .map(|f| f.stream_id)
+            .collect();
+        assert_eq!(resets, [1, 3, 7, 9]);
+        assert_eq!(response(&frames, 5).0, "400");
+    }
+
+    #[test]
+    fn test_connection_errors() {
+        // An even stream id is the server's to open
+        let mut client = settings(&[]);
+        client.extend(frame(
+            HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            2,
+            &request_block("GET", "/echo/a"),
+        ));
+        let (result, frames) = exchange(&client);
+        assert!(matches!(
+            result,
+            Err(Http2Error::Connection(ErrorCode::ProtocolError, _))
+        ));
+        let goaway = frames.last().unwrap();
+        assert_eq!(goaway.kind, GOAWAY);
+        assert_eq!(goaway.payload, [0, 0, 0, 0, 0, 0, 0, 1]);
+
+        // The preface must be followed by SETTINGS
+        let (result, _) = exchange(&frame(PING, 0, 0, b"12345678"));
+        assert!(matches!(
+            result,
+            Err(Http2Error::Connection(ErrorCode::ProtocolError, _))
+        ));
+
+        // An undecodable header block breaks the HPACK state of the connection
+        let mut client = settings(&[]);
+        client.extend(frame(HEADERS, FLAG_END_HEADERS, 1, &[0xff]));
+        let (result, _) = exchange(&client);
+        assert!(matches!(
+            result,
+            Err(Http2Error::Connection(ErrorCode::CompressionError, _))
+        ));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut http_request = HttpRequest::new(HttpMethod::Get, "/echo/up").unwrap();
+        http_request
+            .headers
+            .append("connection", "Upgrade, HTTP2-Settings");
+        http_request.headers.append("upgrade", "h2c");
+        // SETTINGS_MAX_CONCURRENT_STREAMS = 100, SETTINGS_INITIAL_WINDOW_SIZE = 4
+        http_request
+            .headers
+            .append("http2-settings", "AAMAAABkAAQAAAAE");
+        assert!(switches(&http_request));
+        assert_eq!(
+            upgrade_settings(&http_request).unwrap(),
+            [0, 3, 0, 0, 0, 100, 0, 4, 0, 0, 0, 4]
+        );
+
+        let mut input = PREFACE.to_vec();
+        input.extend(settings(&[]));
+        input.extend(frame(WINDOW_UPDATE, 0, 1, &100u32.to_be_bytes()));
+        let mut output = Vec::new();
+        let shutdown = Shutdown::default();
+        serve(
+            &mut Cursor::new(input),
+            &mut output,
+            &router(),
+            &Limits::default(),
+            http_request,
+            &tracked(&shutdown),
+        )
+        .unwrap();
+
+        let switching =
+            b"HTTP/1.1 101 Switching Protocols\r\nconnection: upgrade\r\nupgrade: h2c\r\n\r\n";
+        assert!(output.starts_with(switching));
+        let frames = frames(&output[switching.len()..]);
+        assert_eq!(response(&frames, 1).2, b"up");
+
+        // Not an upgrade without its settings
+        let mut http_request = HttpRequest::new(HttpMethod::Get, "/echo/up").unwrap();
+        http_request.headers.append("connection", "Upgrade");
+        http_request.headers.append("upgrade", "h2c");
+        assert!(!switches(&http_request));
+    }
+
+    #[test]
+    fn test_goaway_on_shutdown() {
+        let shutdown = Arc::new(Shutdown::default());
+        ServerHandle::new(&shutdown).shutdown();
+
+        // The upgrade request is stream 1, taken before the shutdown shows: it's answered, and
+        // stream 3 ignored
+        let mut http_request = HttpRequest::new(HttpMethod::Get, "/echo/up").unwrap();
+        http_request
+            .headers
+            .append("connection", "Upgrade, HTTP2-Settings");
+        http_request.headers.append("upgrade", "h2c");
+        http_request.headers.append("http2-settings", "");
+        let mut input = PREFACE.to_vec();
+        input.extend(settings(&[]));
+        input.extend(frame(
+            HEADERS,
+            FLAG_END_HEADERS | FLAG_END_STREAM,
+            3,
+            &request_block("GET", "/echo/three"),
+        ));
+        let mut output = Vec::new();
+        serve(
+            &mut Cursor::new(input),
+            &mut output,
+            &router(),
+            &Limits::default(),
+            http_request,
+            &tracked(&shutdown),
+        )
+        .unwrap();
+
+        let switching =
+            b"HTTP/1.1 101 Switching Protocols\r\nconnection: upgrade\r\nupgrade: h2c\r\n\r\n";
+        let frames = frames(&output[switching.len()..]);
+        let goaways: Vec<&[u8]> = frames
+            .iter()
+            .filter(|f| f.kind == GOAWAY)
+            .map(|f| f.payload.as_slice())
+            .collect();
+        assert_eq!(goaways, [[0, 0, 0, 1, 0, 0, 0, 0]]);
+        assert_eq!(response(&frames, 1).2, b"up");
+        assert!(!frames.iter().any(|f| f.stream_id == 3));
+    }
+}
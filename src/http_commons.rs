@@ -1,5 +1,6 @@
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum HttpVersion {
+    Http10,
     Http11,
     Http2,
     // Http3,
@@ -15,8 +16,10 @@ impl std::str::FromStr for HttpVersion {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
+            "HTTP/1.0" => Ok(Self::Http10),
             "HTTP/1.1" => Ok(Self::Http11),
-            "HTTP/2" => Ok(Self::Http2),
+            // The request-line of the HTTP/2 connection preface says `HTTP/2.0`
+            "HTTP/2" | "HTTP/2.0" => Ok(Self::Http2),
             _ => Err(HttpVersionParseError { found: s.into() }),
         }
     }
@@ -25,6 +28,7 @@ impl std::str::FromStr for HttpVersion {
 impl std::fmt::Display for HttpVersion {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            HttpVersion::Http10 => write!(f, "HTTP/1.0"),
             HttpVersion::Http11 => write!(f, "HTTP/1.1"),
             HttpVersion::Http2 => write!(f, "HTTP/2"),
         }
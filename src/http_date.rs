@@ -0,0 +1,156 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// HTTP-date (RFC 9110, section 5.6.7). Always sent as IMF-fixdate:
+//
+//   Sun, 06 Nov 1994 08:49:37 GMT    ; IMF-fixdate
+//   Sunday, 06-Nov-94 08:49:37 GMT   ; obsolete RFC 850 format
+//   Sun Nov  6 08:49:37 1994         ; ANSI C's asctime() format
+//
+// Recipients must accept all three.
+
+const DAY_NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Formats the time as an IMF-fixdate, truncated to the second. Times before the epoch are
+/// clamped to it.
+pub fn format(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    let days = secs / SECS_PER_DAY;
+    let secs_of_day = secs % SECS_PER_DAY;
+
+    let (year, month, day) = civil_from_days(days);
+    let weekday = (days + 4) % 7; // 1970-01-01 was a Thursday
+
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        DAY_NAMES[weekday as usize],
+        MONTH_NAMES[month as usize - 1],
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Parses an HTTP-date in any of its three formats. The day name is not checked against the date.
+pub fn parse(s: &str) -> Option<SystemTime> {
+    let tokens: Vec<&str> = s.split_whitespace().collect();
+
+    let (year, month, day, time) = match tokens[..] {
+        // IMF-fixdate
+        [day_name, day, month, year, time, "GMT"] if day_name.ends_with(',') => {
+            (year.parse().ok()?, month, day, time)
+        }
+        // RFC 850
+        [day_name, date, time, "GMT"] if day_name.ends_with(',') => {
+            let [day, month, year]: [&str; 3] =
+                date.split('-').collect::<Vec<_>>().try_into().ok()?;
+            if year.len() != 2 {
+                return None;
+            }
+            let year: u64 = year.parse().ok()?;
+            let year = if year < 70 { 2000 + year } else { 1900 + year };
+            (year, month, day, time)
+        }
+        // asctime
+        [_day_name, month, day, time, year] => (year.parse().ok()?, month, day, time),
+        _ => return None,
+    };
+
+    let month = MONTH_NAMES.iter().position(|m| *m == month)? as u64 + 1;
+    let day: u64 = day.parse().ok()?;
+
+    let [hours, minutes, seconds]: [u64; 3] = time
+        .split(':')
+        .map(|t| t.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?
+        .try_into()
+        .ok()?;
+
+    if year < 1970 || day == 0 || day > 31 || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    let secs =
+        days_from_civil(year, month, day) * SECS_PER_DAY + hours * 3600 + minutes * 60 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Conversions between days since the epoch and the (proleptic Gregorian) calendar, from Howard
+// Hinnant's `chrono`-compatible date algorithms. Years before 1970 are not needed here.
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_imf_fixdate() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(format(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+
+        assert_eq!(format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_164_800);
+        assert_eq!(format(leap_day), "Thu, 29 Feb 2024 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_parse_all_formats() {
+        let expected = UNIX_EPOCH + Duration::from_secs(784_111_777);
+
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(expected));
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), Some(expected));
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), Some(expected));
+    }
+
+    #[test]
+    fn test_parse_rejects_garbage() {
+        assert!(parse("").is_none());
+        assert!(parse("yesterday").is_none());
+        assert!(parse("Sun, 06 Foo 1994 08:49:37 GMT").is_none());
+        assert!(parse("Sun, 06 Nov 1994 25:49:37 GMT").is_none());
+        assert!(parse("Sun, 06 Nov 1994 08:49:37 CET").is_none());
+        assert!(parse("\"etag-value\"").is_none());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for secs in [0, 951_782_400, 1_700_000_000, 4_102_444_800] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(parse(&format(time)), Some(time));
+        }
+    }
+}
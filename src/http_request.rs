@@ -1,18 +1,35 @@
+use crate::chunked::{self, ChunkedError};
+use crate::headers::Headers;
 use crate::http_commons::{HttpVersion, HttpVersionParseError};
+use crate::http_response::StatusCode;
+use crate::router::PathParams;
+use crate::uri::{self, QueryParams, RequestTarget, RequestTargetParseError};
 
-use std::collections::HashMap;
+use bytes::Bytes;
+use std::borrow::Cow;
 use std::fmt;
-use std::io::{BufRead, BufReader, Read};
-use std::net::TcpStream;
+use std::io::{BufRead, Read};
 use std::num::ParseIntError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HttpRequest {
     pub http_method: HttpMethod,
+    /// The request-target as sent, see `path` and `query` for its parts.
     pub request_target: String,
+    /// Percent-decoded path of the target.
+    pub path: String,
+    /// Raw query of the target, without the `?`.
+    pub query: Option<String>,
+    /// Raw fragment of the target, without the `#`.
+    pub fragment: Option<String>,
+    /// Decoded parameters of the query.
+    pub query_params: QueryParams,
     pub protocol_version: HttpVersion,
-    pub headers: HashMap<String, String>,
-    pub body: Option<String>,
+    pub headers: Headers,
+    pub body: Option<Bytes>,
+    pub trailers: Headers,
+    /// Parameters captured by the route pattern, set by the `Router`.
+    pub path_params: PathParams,
 }
 
 #[derive(Debug)]
@@ -20,15 +37,53 @@ pub enum RequestError {
     Io(std::io::Error),
     RequestLine(String),
     Method(String),
+    RequestTarget(String),
     ProtocolVersion(String),
+    VersionNotSupported(String),
     Header(String),
-    BodyUtf8(std::string::FromUtf8Error),
     BodyContentLength(ParseIntError),
+    BodyChunked(ChunkedError),
+    TransferEncoding(String),
+    ConflictingFraming,
+    UriTooLong,
+    HeaderFieldsTooLarge,
+    ContentTooLarge,
+    ExpectationFailed(String),
+    /// The client took too long to send the request.
+    Timeout,
+}
+
+/// Bounds on what a client may send, so that one request can't exhaust the server's memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Longest request-line, CRLF included (414 beyond).
+    pub max_request_line: usize,
+    /// Most header fields in a request (431 beyond).
+    pub max_headers: usize,
+    /// Largest header section, CRLFs included (431 beyond).
+    pub max_header_bytes: usize,
+    /// Largest body, once de-chunked (413 beyond).
+    pub max_body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_request_line: 8 * 1024,
+            max_headers: 100,
+            max_header_bytes: 64 * 1024,
+            max_body: 16 * 1024 * 1024,
+        }
+    }
 }
 
 impl From<std::io::Error> for RequestError {
     fn from(e: std::io::Error) -> RequestError {
-        RequestError::Io(e)
+        match e.kind() {
+            // A read timeout, as a blocking socket reports it
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => RequestError::Timeout,
+            _ => RequestError::Io(e),
+        }
     }
 }
 
@@ -37,9 +92,34 @@ impl From<HttpMethodParseError> for RequestError {
         RequestError::Method(e.found)
     }
 }
+impl From<RequestTargetParseError> for RequestError {
+    fn from(e: RequestTargetParseError) -> RequestError {
+        RequestError::RequestTarget(e.found)
+    }
+}
 impl From<HttpVersionParseError> for RequestError {
     fn from(e: HttpVersionParseError) -> RequestError {
-        RequestError::ProtocolVersion(e.found)
+        // A well-formed `HTTP/x.y` we don't speak is a 505, anything else a 400
+        let well_formed = e.found.strip_prefix("HTTP/").is_some_and(|version| {
+            matches!(version.as_bytes(), [major, b'.', minor]
+                if major.is_ascii_digit() && minor.is_ascii_digit())
+        });
+        if well_formed {
+            RequestError::VersionNotSupported(e.found)
+        } else {
+            RequestError::ProtocolVersion(e.found)
+        }
+    }
+}
+
+impl From<ChunkedError> for RequestError {
+    fn from(e: ChunkedError) -> RequestError {
+        match e {
+            ChunkedError::TooLarge => RequestError::ContentTooLarge,
+            ChunkedError::TrailersTooLarge => RequestError::HeaderFieldsTooLarge,
+            ChunkedError::Io(e) => e.into(),
+            e => RequestError::BodyChunked(e),
+        }
     }
 }
 
@@ -48,18 +128,51 @@ impl fmt::Display for RequestError {
         match self {
             RequestError::RequestLine(s) => write!(f, "malformed request line: {s}"),
             RequestError::Method(m) => write!(f, "unsupported HTTP method: {m}"),
-            RequestError::ProtocolVersion(v) => write!(f, "unsupported HTTP protocol version: {v}"),
+            RequestError::RequestTarget(t) => write!(f, "invalid request-target: {t}"),
+            RequestError::ProtocolVersion(v) => write!(f, "invalid HTTP protocol version: {v}"),
+            RequestError::VersionNotSupported(v) => {
+                write!(f, "unsupported HTTP protocol version: {v}")
+            }
             RequestError::Header(h) => write!(f, "invalid header: {h}"),
-            RequestError::BodyUtf8(b) => write!(f, "body is not valid UTF-8: {b}"),
             RequestError::BodyContentLength(l) => {
                 write!(f, "error parsing the body length: {l}")
             }
+            RequestError::BodyChunked(e) => write!(f, "error decoding the chunked body: {e}"),
+            RequestError::TransferEncoding(t) => {
+                write!(f, "unsupported transfer-encoding: {t}")
+            }
+            RequestError::ConflictingFraming => {
+                write!(
+                    f,
+                    "both content-length and transfer-encoding headers are present"
+                )
+            }
+            RequestError::UriTooLong => write!(f, "request-line too long"),
+            RequestError::HeaderFieldsTooLarge => write!(f, "too many or too large header fields"),
+            RequestError::ContentTooLarge => write!(f, "body too large"),
+            RequestError::ExpectationFailed(e) => write!(f, "unsupported expectation: {e}"),
             RequestError::Io(e) => write!(f, "I/O while reading request: {e}"),
+            RequestError::Timeout => write!(f, "timed out reading the request"),
         }
     }
 }
 impl std::error::Error for RequestError {}
 
+impl RequestError {
+    /// Status of the response sent when a request can't be parsed.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RequestError::UriTooLong => StatusCode::UriTooLong,
+            RequestError::HeaderFieldsTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            RequestError::ContentTooLarge => StatusCode::ContentTooLarge,
+            RequestError::ExpectationFailed(_) => StatusCode::ExpectationFailed,
+            RequestError::VersionNotSupported(_) => StatusCode::HttpVersionNotSupported,
+            RequestError::Timeout => StatusCode::RequestTimeout,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
 #[allow(clippy::module_name_repetitions)]
 pub struct HttpRequestBuilder {
     http_request: HttpRequest,
@@ -77,9 +190,15 @@ impl Builder<HttpRequest> for HttpRequestBuilder {
             http_request: HttpRequest {
                 http_method: HttpMethod::Get,
                 request_target: String::new(),
+                path: String::new(),
+                query: None,
+                fragment: None,
+                query_params: QueryParams::default(),
                 protocol_version: HttpVersion::Http11,
-                headers: HashMap::new(),
+                headers: Headers::new(),
                 body: None,
+                trailers: Headers::new(),
+                path_params: PathParams::default(),
             },
         }
     }
@@ -95,18 +214,27 @@ impl HttpRequestBuilder {
     }
 
     // NOTE: could automate this through a macro
-    fn with_target(&mut self, request_target: &str) {
+    fn with_target(&mut self, request_target: &str) -> Result<(), RequestError> {
+        let RequestTarget {
+            path,
+            query,
+            fragment,
+        } = request_target.parse()?;
         self.http_request.request_target = request_target.to_string();
+        self.http_request.path = path;
+        self.http_request.query_params = query
+            .as_deref()
+            .map(QueryParams::from_query)
+            .unwrap_or_default();
+        self.http_request.query = query;
+        self.http_request.fragment = fragment;
+        Ok(())
     }
     fn with_protocol_version(&mut self, protocol_version: HttpVersion) {
         self.http_request.protocol_version = protocol_version;
     }
-    fn with_headers(&mut self, headers: &HashMap<String, String>) {
-        // TODO: possible to get rid of this clone ? How bad is this, design&perf wise ?
-        self.http_request.headers.clone_from(headers);
-    }
-    fn with_body(&mut self, body: &str) {
-        self.http_request.body = Some(body.to_string());
+    fn with_headers(&mut self, headers: Headers) {
+        self.http_request.headers = headers;
     }
 }
 
@@ -123,17 +251,67 @@ impl Buildable<HttpRequest, HttpRequestBuilder> for HttpRequest {
 }
 
 impl HttpRequest {
+    /// The path as sent, still percent-encoded: what routes match against, segment by segment.
+    /// A `path` rewritten since (by a middleware) is taken as decoded: its `%` are escaped.
+    pub(crate) fn raw_path(&self) -> Cow<'_, str> {
+        match uri::raw_path(&self.request_target) {
+            Some(raw_path) if uri::decode_path(raw_path).as_deref() == Some(&self.path) => {
+                Cow::Borrowed(raw_path)
+            }
+            _ => Cow::Owned(self.path.replace('%', "%25")),
+        }
+    }
+
+    /// A bodiless HTTP/1.1 request, e.g. to call a handler directly in tests.
+    /// # Errors
+    /// Returns `RequestError::RequestTarget` if the target isn't a valid request-target.
+    pub fn new(http_method: HttpMethod, request_target: &str) -> Result<HttpRequest, RequestError> {
+        let mut builder = HttpRequest::builder();
+        builder.with_method(http_method);
+        builder.with_target(request_target)?;
+        Ok(builder.build())
+    }
+
     /// Builds a HTTP request from a parsing an incoming stream of bytes, that should
-    /// corresponds to a valid HTTP request.
+    /// corresponds to a valid HTTP request, within the default `Limits`.
+    ///
+    /// The reader should live as long as the connection: bytes buffered past the end of this
+    /// request belong to the next (pipelined) one.
     /// # Errors
     /// Returns a `RequestError` variant
-    pub fn build_from_stream(stream: &mut TcpStream) -> Result<HttpRequest, RequestError> {
+    pub fn build_from_stream<R: BufRead>(reader: &mut R) -> Result<HttpRequest, RequestError> {
+        HttpRequest::build_from_stream_with_limits(reader, &Limits::default())
+    }
+
+    /// Same as `build_from_stream`, failing as soon as the request goes over one of `limits`:
+    /// nothing past the limit is read or allocated.
+    /// # Errors
+    /// Returns a `RequestError` variant
+    pub fn build_from_stream_with_limits<R: BufRead>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<HttpRequest, RequestError> {
+        let mut http_request = HttpRequest::read_head(reader, limits)?;
+        http_request.read_body(reader, limits)?;
+        Ok(http_request)
+    }
+
+    /// Reads the request-line and the header section, and stops there: the body, if any, is
+    /// left in the reader for `read_body`. Its framing is checked already, so a body that's
+    /// too large or can't be delimited is refused before a byte of it is read.
+    /// # Errors
+    /// Returns a `RequestError` variant
+    pub fn read_head<R: BufRead>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<HttpRequest, RequestError> {
         let mut builder = HttpRequest::builder();
-        let mut reader = BufReader::new(&mut *stream);
 
         // Read the *request-line*
         let mut request_line = String::new();
-        reader.read_line(&mut request_line)?;
+        if !read_line_within(reader, &mut request_line, limits.max_request_line)? {
+            return Err(RequestError::UriTooLong);
+        }
         println!("Success reading the *request-line*: {request_line}");
 
         // Parse the *request-line*
@@ -146,59 +324,213 @@ impl HttpRequest {
 
         let http_method = http_method.parse::<HttpMethod>()?; // turbofish yeah + shadowing + ?
         let protocol_version = protocol_version.parse::<HttpVersion>()?;
+        // HTTP/2 isn't text: the one request-line claiming it opens the connection preface, for
+        // the server to take the connection over from there
+        let is_preface =
+            matches!(&http_method, HttpMethod::Extension(m) if m == "PRI") && request_target == "*";
+        if protocol_version == HttpVersion::Http2 && !is_preface {
+            return Err(RequestError::VersionNotSupported(
+                protocol_version.to_string(),
+            ));
+        }
 
         builder.with_method(http_method);
-        builder.with_target(request_target);
+        builder.with_target(request_target)?;
         builder.with_protocol_version(protocol_version);
 
         // Read eventual *headers*
-        let mut headers: HashMap<String, String> = HashMap::new();
+        // Repeated fields are all kept, in order: `Headers` lowercases the names
+        let mut headers = Headers::new();
         let mut header_line = String::new();
+        let mut header_bytes = 0;
         loop {
-            reader.read_line(&mut header_line)?;
+            let max_line = limits.max_header_bytes - header_bytes;
+            if !read_line_within(reader, &mut header_line, max_line)? {
+                return Err(RequestError::HeaderFieldsTooLarge);
+            }
+            header_bytes += header_line.len();
             if header_line == "\r\n" {
                 break;
             }
+            if headers.len() == limits.max_headers {
+                return Err(RequestError::HeaderFieldsTooLarge);
+            }
             let (header_name, header_value) = header_line
                 .split_once(':')
                 .ok_or(RequestError::Header(header_line.to_string()))?;
-            headers.insert(
-                header_name.to_lowercase().to_string(), // header names are case-insensitives
-                header_value.trim().to_string(),
-            );
+            // No whitespace allowed between the name and the colon (RFC 9112, section 5.1)
+            if header_name.is_empty() || !header_name.bytes().all(chunked::is_tchar) {
+                return Err(RequestError::Header(header_line.to_string()));
+            }
+            headers.append(header_name, header_value);
             header_line.clear();
         }
 
-        builder.with_headers(&headers);
+        // 100-continue is the only expectation defined (RFC 9110, section 10.1.1)
+        if let Some(expect) = headers.get_joined("expect") {
+            if !expect.eq_ignore_ascii_case("100-continue") {
+                return Err(RequestError::ExpectationFailed(expect));
+            }
+        }
+
+        builder.with_headers(headers);
+        let http_request = builder.build();
+        http_request.framing(limits)?;
 
-        // Read the *body* if any
-        if let Some(n_bytes_str) = headers.get("content-length") {
-            let n_bytes = n_bytes_str
-                .parse::<usize>()
-                .map_err(RequestError::BodyContentLength)?;
+        Ok(http_request)
+    }
 
-            let mut body_buf = vec![0; n_bytes];
-            reader.read_exact(&mut body_buf)?;
+    /// Reads the body announced by the header section read by `read_head`.
+    /// # Errors
+    /// Returns a `RequestError` variant
+    pub fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<(), RequestError> {
+        match self.framing(limits)? {
+            Framing::Chunked => {
+                let chunked_body = chunked::read_chunked_body(reader, limits)?;
 
-            let body = String::from_utf8(body_buf).map_err(RequestError::BodyUtf8)?;
+                self.body = Some(Bytes::from(chunked_body.data));
+                self.trailers = chunked_body.trailers;
+            }
+            Framing::Length(n_bytes) => {
+                let mut body_buf = vec![0; n_bytes];
+                reader.read_exact(&mut body_buf)?;
 
-            builder.with_body(&body);
+                self.body = Some(Bytes::from(body_buf));
+            }
+            Framing::None => {}
         };
+        Ok(())
+    }
 
-        Ok(builder.build())
+    /// How the body is delimited, according to the header section.
+    pub(crate) fn framing(&self, limits: &Limits) -> Result<Framing, RequestError> {
+        match (
+            self.headers.get_joined("transfer-encoding"),
+            self.headers.get_joined("content-length"),
+        ) {
+            // Transfer-Encoding came with HTTP/1.1: in a 1.0 request, the framing is faulty
+            (Some(transfer_encoding), _) if self.protocol_version == HttpVersion::Http10 => {
+                Err(RequestError::TransferEncoding(transfer_encoding))
+            }
+            // Two framings for one message is how request smuggling works: refuse it
+            (Some(_), Some(_)) => Err(RequestError::ConflictingFraming),
+            (Some(transfer_encoding), None) => {
+                // Chunked must be the final (and here, only) transfer-coding
+                if !transfer_encoding.eq_ignore_ascii_case("chunked") {
+                    return Err(RequestError::TransferEncoding(transfer_encoding));
+                }
+                Ok(Framing::Chunked)
+            }
+            (None, Some(n_bytes_list)) => {
+                // Repeated (or listed) lengths are fine as long as they all agree
+                let mut n_bytes_strs = n_bytes_list.split(',').map(str::trim);
+                let n_bytes_str = n_bytes_strs.next().unwrap_or_default();
+                if n_bytes_strs.any(|other| other != n_bytes_str) {
+                    return Err(RequestError::Header(format!(
+                        "content-length: {n_bytes_list}"
+                    )));
+                }
+                // Digits only (`1*DIGIT`): `parse` would take a sign, which a proxy in front may
+                // read differently
+                if !is_content_length(n_bytes_str) {
+                    return Err(RequestError::Header(format!(
+                        "content-length: {n_bytes_list}"
+                    )));
+                }
+                let n_bytes = n_bytes_str
+                    .parse::<usize>()
+                    .map_err(RequestError::BodyContentLength)?;
+                // Checked before allocating the buffer
+                if n_bytes > limits.max_body {
+                    return Err(RequestError::ContentTooLarge);
+                }
+                Ok(Framing::Length(n_bytes))
+            }
+            (None, None) => Ok(Framing::None),
+        }
     }
+
+    /// Whether the client waits for a `100 Continue` interim response before sending the body.
+    /// HTTP/1.0 clients don't know about interim responses: their expectation is ignored.
+    pub fn expects_continue(&self) -> bool {
+        if self.protocol_version == HttpVersion::Http10 {
+            return false;
+        }
+        let has_body = self.headers.contains("transfer-encoding")
+            || self
+                .headers
+                .get("content-length")
+                .is_some_and(|length| length != "0");
+        has_body
+            && self
+                .headers
+                .get("expect")
+                .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    }
+    /// The body as text, for handlers that need UTF-8. `Ok(None)` when there is no body.
+    /// # Errors
+    /// Returns the UTF-8 error if the body is binary data.
+    pub fn body_text(&self) -> Result<Option<&str>, std::str::Utf8Error> {
+        self.body.as_deref().map(std::str::from_utf8).transpose()
+    }
+    /// Whether the client wants the connection kept open. HTTP/1.1 connections persist unless
+    /// `close` is among the `Connection` options; HTTP/1.0 ones only with `keep-alive`.
     pub fn keep_alive(&self) -> bool {
-        match self.headers.get("connection") {
-            Some(s) if s == "close" => false,
-            Some(_) | None => true,
+        let has_option = |name: &str| {
+            self.headers
+                .get_all("connection")
+                .flat_map(|options| options.split(','))
+                .any(|option| option.trim().eq_ignore_ascii_case(name))
+        };
+        match self.protocol_version {
+            HttpVersion::Http10 => has_option("keep-alive") && !has_option("close"),
+            HttpVersion::Http11 | HttpVersion::Http2 => !has_option("close"),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+/// How a request body is delimited (RFC 9112, section 6.3).
+pub(crate) enum Framing {
+    None,
+    Length(usize),
+    Chunked,
+}
+
+/// Whether a `Content-Length` value is well-formed: digits only, at least one.
+pub(crate) fn is_content_length(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Reads a line into `line`, reading no more than `max_len` bytes. `false` if the line goes on
+/// past that; a line cut short by the end of the stream is left for the caller to reject.
+pub(crate) fn read_line_within<R: BufRead>(
+    reader: &mut R,
+    line: &mut String,
+    max_len: usize,
+) -> std::io::Result<bool> {
+    let max = u64::try_from(max_len).unwrap_or(u64::MAX);
+    let n_read = reader.by_ref().take(max).read_line(line)?;
+    Ok(n_read < max_len || line.ends_with('\n'))
+}
+
+/// Request methods of RFC 9110 (section 9) and PATCH (RFC 5789). Any other method is kept as an
+/// extension token: whether it is implemented is up to the endpoints.
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum HttpMethod {
     Get,
+    Head,
     Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Extension(String),
 }
 
 #[derive(Debug)]
@@ -209,11 +541,336 @@ pub struct HttpMethodParseError {
 impl std::str::FromStr for HttpMethod {
     type Err = HttpMethodParseError; // NOTE: what/why ?
 
+    // Methods are case-sensitive: `get` is an extension method, not GET
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "GET" => Ok(Self::Get),
+            "HEAD" => Ok(Self::Head),
             "POST" => Ok(Self::Post),
+            "PUT" => Ok(Self::Put),
+            "DELETE" => Ok(Self::Delete),
+            "CONNECT" => Ok(Self::Connect),
+            "OPTIONS" => Ok(Self::Options),
+            "TRACE" => Ok(Self::Trace),
+            "PATCH" => Ok(Self::Patch),
+            // method = token
+            _ if !s.is_empty() && s.bytes().all(chunked::is_tchar) => Ok(Self::Extension(s.into())),
             _ => Err(HttpMethodParseError { found: s.into() }),
         }
     }
 }
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpMethod::Get => write!(f, "GET"),
+            HttpMethod::Head => write!(f, "HEAD"),
+            HttpMethod::Post => write!(f, "POST"),
+            HttpMethod::Put => write!(f, "PUT"),
+            HttpMethod::Delete => write!(f, "DELETE"),
+            HttpMethod::Connect => write!(f, "CONNECT"),
+            HttpMethod::Options => write!(f, "OPTIONS"),
+            HttpMethod::Trace => write!(f, "TRACE"),
+            HttpMethod::Patch => write!(f, "PATCH"),
+            HttpMethod::Extension(method) => write!(f, "{method}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_response::HttpResponse;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_chunked_body_same_as_content_length_body() {
+        let mut chunked = Cursor::new(
+            "POST /files/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        );
+        let mut sized =
+            Cursor::new("POST /files/a HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world");
+
+        let chunked_request = HttpRequest::build_from_stream(&mut chunked).unwrap();
+        let sized_request = HttpRequest::build_from_stream(&mut sized).unwrap();
+
+        assert_eq!(chunked_request.body, sized_request.body);
+        assert_eq!(chunked_request.body.unwrap(), "hello world");
+    }
+
+    #[test]
+    fn test_chunked_body_then_pipelined_request() {
+        let mut reader = Cursor::new(
+            "POST /files/a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;ext=1\r\nabc\r\n0\r\nX-Trailer: yes\r\n\r\nGET /echo/next HTTP/1.1\r\n\r\n",
+        );
+
+        let first = HttpRequest::build_from_stream(&mut reader).unwrap();
+        assert_eq!(first.body.unwrap(), "abc");
+        assert_eq!(first.trailers.get("x-trailer").unwrap(), "yes");
+
+        let second = HttpRequest::build_from_stream(&mut reader).unwrap();
+        assert_eq!(second.http_method, HttpMethod::Get);
+        assert_eq!(second.request_target, "/echo/next");
+    }
+
+    #[test]
+    fn test_request_target_parts() {
+        let mut reader = Cursor::new("GET /files/a%20b.txt?foo=bar&n=1+2#frag HTTP/1.1\r\n\r\n");
+        let request = HttpRequest::build_from_stream(&mut reader).unwrap();
+
+        assert_eq!(request.path, "/files/a b.txt");
+        assert_eq!(request.query.as_deref(), Some("foo=bar&n=1+2"));
+        assert_eq!(request.fragment.as_deref(), Some("frag"));
+        assert_eq!(request.query_params.get("foo"), Some("bar"));
+        assert_eq!(request.query_params.get("n"), Some("1 2"));
+
+        let mut reader = Cursor::new("GET /files/%zz HTTP/1.1\r\n\r\n");
+        assert!(matches!(
+            HttpRequest::build_from_stream(&mut reader),
+            Err(RequestError::RequestTarget(_))
+        ));
+    }
+
+    #[test]
+    fn test_repeated_headers() {
+        let mut reader = Cursor::new(
+            "GET /echo/a HTTP/1.1\r\nAccept: text/html\r\nX-Forwarded-For: 10.0.0.1\r\nCookie: a=1\r\naccept: text/plain\r\nX-Forwarded-For: 10.0.0.2\r\nConnection: Keep-Alive, Close\r\n\r\n",
+        );
+        let request = HttpRequest::build_from_stream(&mut reader).unwrap();
+
+        assert_eq!(request.headers.get("ACCEPT"), Some("text/html"));
+        assert_eq!(
+            request.headers.get_joined("accept").unwrap(),
+            "text/html, text/plain"
+        );
+        assert_eq!(
+            request
+                .headers
+                .get_all("x-forwarded-for")
+                .collect::<Vec<_>>(),
+            ["10.0.0.1", "10.0.0.2"]
+        );
+        assert_eq!(
+            request
+                .headers
+                .iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>(),
+            [
+                "accept",
+                "x-forwarded-for",
+                "cookie",
+                "accept",
+                "x-forwarded-for",
+                "connection"
+            ]
+        );
+        assert!(!request.keep_alive());
+    }
+
+    #[test]
+    fn test_content_length_lists() {
+        let mut reader = Cursor::new(
+            "POST /files/a HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc",
+        );
+        let request = HttpRequest::build_from_stream(&mut reader).unwrap();
+        assert_eq!(request.body.unwrap(), "abc");
+
+        for conflicting in [
+            "Content-Length: 3\r\nContent-Length: 4",
+            "Content-Length: 3, 4",
+        ] {
+            let mut reader = Cursor::new(format!(
+                "POST /files/a HTTP/1.1\r\n{conflicting}\r\n\r\nabcd"
+            ));
+            assert!(matches!(
+                HttpRequest::build_from_stream(&mut reader),
+                Err(RequestError::Header(_))
+            ));
+        }
+
+        for signed in ["+5", "-5", "5.0", ""] {
+            let mut reader = Cursor::new(format!(
+                "POST /files/a HTTP/1.1\r\nContent-Length:{signed}\r\n\r\nabcde"
+            ));
+            let e = HttpRequest::build_from_stream(&mut reader).unwrap_err();
+            assert!(matches!(e, RequestError::Header(_)));
+            let response = HttpResponse::new_from_bad_request(&e);
+            assert!(matches!(response.status_code, StatusCode::BadRequest));
+        }
+
+        let mut reader = Cursor::new("GET /echo/a HTTP/1.1\r\nHost : x\r\n\r\n");
+        assert!(matches!(
+            HttpRequest::build_from_stream(&mut reader),
+            Err(RequestError::Header(_))
+        ));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_request_line: 32,
+            max_headers: 2,
+            max_header_bytes: 40,
+            max_body: 4,
+        };
+        let parse = |request: &str| {
+            HttpRequest::build_from_stream_with_limits(&mut Cursor::new(request), &limits)
+        };
+
+        assert!(parse("POST /a HTTP/1.1\r\nA: 1\r\nContent-Length: 4\r\n\r\nabcd").is_ok());
+
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(32));
+        assert!(matches!(parse(&long_target), Err(RequestError::UriTooLong)));
+        let many_headers = "GET /a HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert!(matches!(
+            parse(many_headers),
+            Err(RequestError::HeaderFieldsTooLarge)
+        ));
+        let large_header = format!("GET /a HTTP/1.1\r\nA: {}\r\n\r\n", "x".repeat(40));
+        assert!(matches!(
+            parse(&large_header),
+            Err(RequestError::HeaderFieldsTooLarge)
+        ));
+        let large_body = "POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde";
+        assert!(matches!(
+            parse(large_body),
+            Err(RequestError::ContentTooLarge)
+        ));
+        let large_chunked =
+            "POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        assert!(matches!(
+            parse(large_chunked),
+            Err(RequestError::ContentTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_head_then_body() {
+        let mut reader = Cursor::new(
+            "PUT /files/a HTTP/1.1\r\nExpect: 100-Continue\r\nContent-Length: 5\r\n\r\nhello",
+        );
+        let limits = Limits::default();
+
+        let mut request = HttpRequest::read_head(&mut reader, &limits).unwrap();
+        assert!(request.expects_continue());
+        assert!(request.body.is_none());
+
+        request.read_body(&mut reader, &limits).unwrap();
+        assert_eq!(request.body.unwrap(), "hello");
+
+        // Refused before the body is read
+        let too_large =
+            "PUT /files/a HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n";
+        let limits = Limits {
+            max_body: 4,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            HttpRequest::read_head(&mut Cursor::new(too_large), &limits),
+            Err(RequestError::ContentTooLarge)
+        ));
+
+        let unknown = "PUT /files/a HTTP/1.1\r\nExpect: 200-ok\r\nContent-Length: 5\r\n\r\n";
+        let error = HttpRequest::read_head(&mut Cursor::new(unknown), &limits).unwrap_err();
+        assert!(matches!(error.status_code(), StatusCode::ExpectationFailed));
+    }
+
+    #[test]
+    fn test_http_versions() {
+        let parse = |request: &str| HttpRequest::build_from_stream(&mut Cursor::new(request));
+
+        let request = parse("GET /echo/a HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(request.protocol_version, HttpVersion::Http10);
+        assert!(!request.keep_alive());
+        let request = parse("GET /echo/a HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap();
+        assert!(request.keep_alive());
+        let request = parse("GET /echo/a HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.keep_alive());
+        let request = parse("GET /echo/a HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        assert!(!request.keep_alive());
+
+        let request =
+            parse("PUT /files/a HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 1\r\n\r\na")
+                .unwrap();
+        assert!(!request.expects_continue());
+        assert!(matches!(
+            parse("POST /files/a HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"),
+            Err(RequestError::TransferEncoding(_))
+        ));
+
+        for (version, status_code) in [
+            ("HTTP/3.0", StatusCode::HttpVersionNotSupported),
+            ("HTTP/2", StatusCode::HttpVersionNotSupported),
+            ("HTTP/2.0", StatusCode::HttpVersionNotSupported),
+            ("HTTP/1.2", StatusCode::HttpVersionNotSupported),
+            ("HTTP/1", StatusCode::BadRequest),
+            ("HTTP/one", StatusCode::BadRequest),
+        ] {
+            let error = parse(&format!("GET /echo/a {version}\r\n\r\n")).unwrap_err();
+            assert_eq!(error.status_code(), status_code, "{version}");
+        }
+
+        // Except for the HTTP/2 connection preface
+        let preface = parse("PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").unwrap();
+        assert_eq!(preface.protocol_version, HttpVersion::Http2);
+    }
+
+    #[test]
+    fn test_binary_body() {
+        let mut upload = b"POST /files/a.png HTTP/1.1\r\nContent-Length: 4\r\n\r\n".to_vec();
+        upload.extend_from_slice(&[0x89, 0x50, 0xff, 0x00]);
+
+        let request = HttpRequest::build_from_stream(&mut Cursor::new(upload)).unwrap();
+
+        assert_eq!(request.body.as_deref().unwrap(), &[0x89, 0x50, 0xff, 0x00]);
+        assert!(request.body_text().is_err());
+    }
+
+    #[test]
+    fn test_reject_content_length_with_transfer_encoding() {
+        let mut reader = Cursor::new(
+            "POST /files/a HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+        );
+
+        assert!(matches!(
+            HttpRequest::build_from_stream(&mut reader),
+            Err(RequestError::ConflictingFraming)
+        ));
+    }
+
+    #[test]
+    fn test_reject_unsupported_transfer_encoding() {
+        let mut reader =
+            Cursor::new("POST /files/a HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+
+        assert!(matches!(
+            HttpRequest::build_from_stream(&mut reader),
+            Err(RequestError::TransferEncoding(_))
+        ));
+    }
+
+    #[test]
+    fn test_methods() {
+        for (method, expected) in [
+            ("HEAD", HttpMethod::Head),
+            ("PUT", HttpMethod::Put),
+            ("DELETE", HttpMethod::Delete),
+            ("OPTIONS", HttpMethod::Options),
+            ("PATCH", HttpMethod::Patch),
+            ("PROPFIND", HttpMethod::Extension("PROPFIND".into())),
+            ("get", HttpMethod::Extension("get".into())),
+        ] {
+            let mut reader = Cursor::new(format!("{method} /files/a HTTP/1.1\r\n\r\n"));
+            let request = HttpRequest::build_from_stream(&mut reader).unwrap();
+            assert_eq!(request.http_method, expected);
+            assert_eq!(request.http_method.to_string(), method);
+        }
+
+        let mut reader = Cursor::new("GE(T /files/a HTTP/1.1\r\n\r\n");
+        assert!(matches!(
+            HttpRequest::build_from_stream(&mut reader),
+            Err(RequestError::Method(_))
+        ));
+    }
+}
@@ -1,12 +1,15 @@
+use crate::chunked::ChunkedWriter;
 use crate::encoding::ContentEncoding;
-use crate::endpoints::{EndpointError, Endpoints};
+use crate::endpoints::EndpointError;
+use crate::headers::Headers;
 use crate::http_commons::HttpVersion;
-use crate::http_request::{HttpRequest, RequestError};
+use crate::http_date;
+use crate::http_request::{HttpMethod, RequestError};
 
 use std::fmt::{self, Display};
-use std::io::Write;
-use std::path::Path;
+use std::io::{Read, Write};
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 //TODO:
 // 1. use combinator to reduce explicit matching
@@ -16,32 +19,135 @@ use std::str::FromStr;
 pub struct HttpResponse {
     pub protocol_version: HttpVersion,
     pub status_code: StatusCode,
-    pub content_type: ContentType,
-    pub content_length: usize,
-    pub content_encoding: Option<ContentEncoding>,
-    pub conn_close: bool,
-    pub body: Option<Vec<u8>>,
+    pub headers: Headers,
+    pub content_length: Option<usize>,
+    pub chunked: bool,
+    pub trailers: Headers,
+    pub body: Option<Body>,
+    /// Answer to a HEAD request: the header section is written as for GET, the body is not.
+    pub omit_body: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Response body: either fully in memory, or a source read (and sent) block by block.
+pub enum Body {
+    Full(Vec<u8>),
+    Stream(Box<dyn Read + Send>),
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Full(bytes) => f.debug_tuple("Full").field(bytes).finish(),
+            Body::Stream(_) => write!(f, "Stream(..)"),
+        }
+    }
+}
+
+impl PartialEq<[u8]> for Body {
+    fn eq(&self, other: &[u8]) -> bool {
+        match self {
+            Body::Full(bytes) => bytes == other,
+            Body::Stream(_) => false, // can't tell without consuming it
+        }
+    }
+}
+
+impl PartialEq<&[u8]> for Body {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self == *other
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StatusCode {
+    Continue,
+    SwitchingProtocols,
     Ok,
     NotFound,
     Created,
+    NoContent,
+    PartialContent,
+    NotModified,
     NotImplemented,
+    ServiceUnavailable,
+    MethodNotAllowed,
     InternalServerError,
     BadRequest,
+    Unauthorized,
+    PreconditionFailed,
+    RequestTimeout,
+    ContentTooLarge,
+    UriTooLong,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    RequestHeaderFieldsTooLarge,
+    HttpVersionNotSupported,
+}
+
+impl StatusCode {
+    /// 1xx: an interim response, sent ahead of the final one.
+    #[must_use]
+    pub fn is_interim(self) -> bool {
+        matches!(self, StatusCode::Continue | StatusCode::SwitchingProtocols)
+    }
+
+    /// The three-digit code alone, e.g. for the HTTP/2 `:status` field.
+    #[must_use]
+    pub fn code(self) -> u16 {
+        match self {
+            StatusCode::Continue => 100,
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::NoContent => 204,
+            StatusCode::PartialContent => 206,
+            StatusCode::NotModified => 304,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::PreconditionFailed => 412,
+            StatusCode::ContentTooLarge => 413,
+            StatusCode::UriTooLong => 414,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::ExpectationFailed => 417,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::HttpVersionNotSupported => 505,
+        }
+    }
 }
 
 impl std::fmt::Display for StatusCode {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
+            StatusCode::Continue => write!(f, "100 Continue"),
+            StatusCode::SwitchingProtocols => write!(f, "101 Switching Protocols"),
             StatusCode::Ok => write!(f, "200 OK"),
             StatusCode::NotFound => write!(f, "404 Not Found"),
             StatusCode::Created => write!(f, "201 Created"),
+            StatusCode::NoContent => write!(f, "204 No Content"),
+            StatusCode::PartialContent => write!(f, "206 Partial Content"),
+            StatusCode::NotModified => write!(f, "304 Not Modified"),
             StatusCode::NotImplemented => write!(f, "501 Not Implemented"),
+            StatusCode::ServiceUnavailable => write!(f, "503 Service Unavailable"),
+            StatusCode::MethodNotAllowed => write!(f, "405 Method Not Allowed"),
             StatusCode::InternalServerError => write!(f, "500 Internal Server Error"),
             StatusCode::BadRequest => write!(f, "400 Bad Request"),
+            StatusCode::Unauthorized => write!(f, "401 Unauthorized"),
+            StatusCode::RequestTimeout => write!(f, "408 Request Timeout"),
+            StatusCode::PreconditionFailed => write!(f, "412 Precondition Failed"),
+            StatusCode::ContentTooLarge => write!(f, "413 Content Too Large"),
+            StatusCode::UriTooLong => write!(f, "414 URI Too Long"),
+            StatusCode::RangeNotSatisfiable => write!(f, "416 Range Not Satisfiable"),
+            StatusCode::ExpectationFailed => write!(f, "417 Expectation Failed"),
+            StatusCode::RequestHeaderFieldsTooLarge => {
+                write!(f, "431 Request Header Fields Too Large")
+            }
+            StatusCode::HttpVersionNotSupported => write!(f, "505 HTTP Version Not Supported"),
         }
     }
 }
@@ -58,7 +164,8 @@ pub enum ContentType {
     Svg,
     PlainText,
     Pdf,
-    OctetStream, // default
+    OctetStream,              // default
+    MultipartByteRanges(u64), // boundary
 }
 
 impl FromStr for ContentType {
@@ -95,11 +202,39 @@ impl fmt::Display for ContentType {
             ContentType::PlainText => "text/plain",
             ContentType::Pdf => "application/pdf",
             ContentType::OctetStream => "application/octet-stream",
+            ContentType::MultipartByteRanges(boundary) => {
+                return write!(f, "multipart/byteranges; boundary={boundary:016x}");
+            }
         };
         write!(f, "{mime}")
     }
 }
 
+impl ContentType {
+    /// Parses back a `content-type` header value written from a `ContentType`.
+    pub fn from_mime(mime: &str) -> Option<ContentType> {
+        let (essence, parameters) = mime.split_once(';').unwrap_or((mime, ""));
+        Some(match essence.trim() {
+            "text/html" => ContentType::Html,
+            "text/css" => ContentType::Css,
+            "application/javascript" => ContentType::JavaScript,
+            "application/json" => ContentType::Json,
+            "image/png" => ContentType::Png,
+            "image/jpeg" => ContentType::Jpeg,
+            "image/gif" => ContentType::Gif,
+            "image/svg+xml" => ContentType::Svg,
+            "text/plain" => ContentType::PlainText,
+            "application/pdf" => ContentType::Pdf,
+            "application/octet-stream" => ContentType::OctetStream,
+            "multipart/byteranges" => {
+                let boundary = parameters.trim().strip_prefix("boundary=")?;
+                ContentType::MultipartByteRanges(u64::from_str_radix(boundary, 16).ok()?)
+            }
+            _ => return None,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum ResponseError {
     Endpoint(EndpointError),
@@ -134,19 +269,23 @@ pub trait Builder<T> {
 impl Builder<HttpResponse> for HttpResponseBuilder {
     fn new() -> Self {
         // response with default values
-        Self {
+        let mut builder = Self {
             http_response: HttpResponse {
                 protocol_version: HttpVersion::Http11,
                 status_code: StatusCode::Ok,
-                content_type: ContentType::PlainText,
-                content_length: 0,
-                content_encoding: None,
-                conn_close: false,
+                headers: Headers::new(),
+                content_length: None,
+                chunked: false,
+                trailers: Headers::new(),
                 body: None,
+                omit_body: false,
             },
-        }
+        };
+        builder.with_content_type(ContentType::PlainText);
+        builder
     }
-    fn build(self) -> HttpResponse {
+    fn build(mut self) -> HttpResponse {
+        self.http_response.update_framing();
         self.http_response
     }
 }
@@ -161,23 +300,77 @@ impl HttpResponseBuilder {
     }
 
     pub fn with_content_type(&mut self, content_type: ContentType) {
-        self.http_response.content_type = content_type;
+        self.http_response
+            .headers
+            .insert("content-type", &content_type.to_string());
     }
 
     pub fn with_content_length(&mut self, content_length: usize) {
-        self.http_response.content_length = content_length;
+        self.http_response.content_length = Some(content_length);
     }
 
     pub fn with_content_encoding(&mut self, content_encoding: Option<ContentEncoding>) {
-        self.http_response.content_encoding = content_encoding;
+        match content_encoding {
+            Some(encoding) => self
+                .http_response
+                .headers
+                .insert("content-encoding", &encoding.to_string()),
+            None => self.http_response.headers.remove("content-encoding"),
+        }
     }
 
-    pub fn with_conn_close(&mut self, conn_close: bool) {
-        self.http_response.conn_close = conn_close;
+    pub fn with_content_range(&mut self, content_range: &str) {
+        self.http_response
+            .headers
+            .insert("content-range", content_range);
+    }
+
+    pub fn with_accept_ranges(&mut self, accept_ranges: bool) {
+        if accept_ranges {
+            self.http_response.headers.insert("accept-ranges", "bytes");
+        } else {
+            self.http_response.headers.remove("accept-ranges");
+        }
+    }
+
+    pub fn with_etag(&mut self, etag: &str) {
+        self.http_response.headers.insert("etag", etag);
+    }
+
+    pub fn with_last_modified(&mut self, last_modified: SystemTime) {
+        self.http_response
+            .headers
+            .insert("last-modified", &http_date::format(last_modified));
+    }
+
+    /// `Allow` header: the methods the target resource supports.
+    pub fn with_allow(&mut self, methods: &[HttpMethod]) {
+        let methods: Vec<String> = methods.iter().map(HttpMethod::to_string).collect();
+        self.http_response
+            .headers
+            .insert("allow", &methods.join(", "));
+    }
+
+    /// Adds a header field, keeping previous values of the same name (e.g. several
+    /// `set-cookie`). Framing fields (`content-length`, `transfer-encoding`, `trailer`) are
+    /// managed by the response itself and never rendered from here.
+    pub fn with_header(&mut self, name: &str, value: &str) {
+        self.http_response.headers.append(name, value);
     }
 
     pub fn with_body(&mut self, body: &[u8]) {
-        self.http_response.body = Some(body.into());
+        self.http_response.body = Some(Body::Full(body.into()));
+    }
+
+    /// Body read from `reader` while the response is written. Without a content length, the
+    /// response is sent chunked.
+    pub fn with_body_stream<R: Read + Send + 'static>(&mut self, reader: R) {
+        self.http_response.body = Some(Body::Stream(Box::new(reader)));
+    }
+
+    /// Trailer field sent after a chunked body (HTTP/1.1 only, dropped otherwise).
+    pub fn with_trailer(&mut self, name: &str, value: &str) {
+        self.http_response.trailers.append(name, value);
     }
 }
 
@@ -195,30 +388,30 @@ impl Buildable<HttpResponse, HttpResponseBuilder> for HttpResponse {
 
 // Public API
 impl HttpResponse {
-    /// Builds a HTTP response based on an HTTP request
-    /// # Errors
-    /// Endpoints can return errors.
-    pub fn new_from_request(http_request: &HttpRequest, data_dir: &Path) -> HttpResponse {
-        if let Ok(endpoint_requested) = &http_request.request_target.parse::<Endpoints>() {
-            match endpoint_requested.handle_request(http_request, data_dir) {
-                Ok(response) => response,
-                Err(e) => {
-                    eprintln!("Internal error: {e}");
-                    let mut builder = HttpResponse::builder();
-                    builder.with_status_code(StatusCode::InternalServerError);
-                    builder.build()
-                }
-            }
-        } else {
-            let mut builder = HttpResponse::builder();
-            builder.with_status_code(StatusCode::NotImplemented);
-            builder.build()
+    /// Interim (1xx) response, e.g. `100 Continue`: a status line and header fields only, no
+    /// body nor framing, written before the final response to the same request.
+    #[must_use]
+    pub fn new_interim(status_code: StatusCode) -> HttpResponse {
+        debug_assert!(status_code.is_interim());
+        HttpResponse {
+            protocol_version: HttpVersion::Http11,
+            status_code,
+            headers: Headers::new(),
+            content_length: None,
+            chunked: false,
+            trailers: Headers::new(),
+            body: None,
+            omit_body: true,
         }
     }
+
+    /// Response to a request that couldn't be parsed (or went over a limit). The rest of the
+    /// connection can't be trusted to start at a request boundary, so it gets closed.
     pub fn new_from_bad_request(error: &RequestError) -> HttpResponse {
         let mut builder = HttpResponse::builder();
 
-        builder.with_status_code(StatusCode::BadRequest);
+        builder.with_status_code(error.status_code());
+        builder.with_header("connection", "close");
 
         let body_str = error.to_string();
         let body = body_str.as_bytes();
@@ -228,63 +421,297 @@ impl HttpResponse {
         builder.build()
     }
 
+    /// Response to a request the server has no room for right now: worth retrying after
+    /// `retry_after` (whole seconds). The connection gets closed.
+    #[must_use]
+    pub fn new_service_unavailable(retry_after: Duration) -> HttpResponse {
+        let mut builder = HttpResponse::builder();
+
+        builder.with_status_code(StatusCode::ServiceUnavailable);
+        builder.with_header("retry-after", &retry_after.as_secs().to_string());
+        builder.with_header("connection", "close");
+
+        let body = b"Server overloaded, retry later";
+        builder.with_body(body);
+        builder.with_content_length(body.len());
+
+        builder.build()
+    }
+
+    /// Typed view of the `content-type` header, if it holds a known type.
+    #[must_use]
+    pub fn content_type(&self) -> Option<ContentType> {
+        self.headers
+            .get("content-type")
+            .and_then(ContentType::from_mime)
+    }
+
+    /// Sets the coding applied to the body when it's written, e.g. from a middleware once the
+    /// response is built. The framing is picked again accordingly.
+    pub fn set_content_encoding(&mut self, content_encoding: Option<ContentEncoding>) {
+        match content_encoding {
+            Some(encoding) => self
+                .headers
+                .insert("content-encoding", &encoding.to_string()),
+            None => self.headers.remove("content-encoding"),
+        }
+        self.update_framing();
+    }
+
+    /// Typed view of the `content-encoding` header: the coding applied when the body is written.
+    #[must_use]
+    pub fn content_encoding(&self) -> Option<ContentEncoding> {
+        self.headers
+            .get("content-encoding")
+            .and_then(|encoding| encoding.parse().ok())
+    }
+
+    /// Sets the version the response is written in, normally the request's: the framing is
+    /// picked again accordingly (no chunked for HTTP/1.0).
+    pub fn set_protocol_version(&mut self, protocol_version: HttpVersion) {
+        self.protocol_version = protocol_version;
+        self.update_framing();
+    }
+
+    /// Whether the response carries `connection: close`.
+    #[must_use]
+    pub fn conn_close(&self) -> bool {
+        self.headers
+            .get_all("connection")
+            .flat_map(|value| value.split(','))
+            .any(|option| option.trim().eq_ignore_ascii_case("close"))
+    }
+
+    fn update_framing(&mut self) {
+        // Nothing to encode without a body
+        if self.body.is_none() {
+            self.headers.remove("content-encoding");
+        }
+
+        // Pick the message framing: the length of a streamed body is unknown up front when none
+        // was given, or when it gets encoded on the fly
+        let length_unknown = matches!(self.body, Some(Body::Stream(_)))
+            && (self.content_length.is_none() || self.content_encoding().is_some());
+
+        match self.protocol_version {
+            // No chunked: a body of unknown length ends with the connection
+            HttpVersion::Http10 => {
+                self.chunked = false;
+                if self.body.is_some() && length_unknown {
+                    self.headers.insert("connection", "close");
+                }
+            }
+            HttpVersion::Http11 => {
+                self.chunked = self.body.is_some() && (length_unknown || !self.trailers.is_empty());
+            }
+            HttpVersion::Http2 => {} // DATA frames carry their own length
+        }
+    }
+
+    /// Status line and header fields, minus the framing fields which depend on how the body is
+    /// eventually written.
+    fn head(&self) -> String {
+        let mut head = format!("{} {}\r\n", self.protocol_version, self.status_code);
+        for (name, value) in self.headers.iter() {
+            if !matches!(name, "content-length" | "transfer-encoding" | "trailer") {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+        head
+    }
+
     /// Write HTTP response
     /// # Errors
     /// Some write steps may return an error.
-    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        // Status line
-        write!(writer, "{} {}\r\n", self.protocol_version, self.status_code)?;
-
-        // Content-type
-        write!(writer, "content-type: {}\r\n", self.content_type)?;
+    pub fn write_to<W: Write>(self, writer: &mut W) -> std::io::Result<()> {
+        // Status line and header fields
+        write!(writer, "{}", self.head())?;
+        let content_encoding = self.content_encoding();
+
+        let Some(body) = self.body else {
+            // no body, the end. A 1xx, 204 or 304 has no content at all, not even an empty one
+            if !self.status_code.is_interim()
+                && !matches!(
+                    self.status_code,
+                    StatusCode::NoContent | StatusCode::NotModified
+                )
+            {
+                write!(writer, "content-length: 0\r\n")?;
+            }
+            write!(writer, "\r\n")?;
+            return Ok(());
+        };
 
-        // Close connection
-        if self.conn_close {
-            write!(writer, "connection: close\r\n")?;
-        }
+        if self.chunked {
+            write!(writer, "transfer-encoding: chunked\r\n")?;
+            if !self.trailers.is_empty() {
+                // Each name once, however its fields are spread
+                let mut names: Vec<&str> = Vec::new();
+                for (name, _) in self.trailers.iter() {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+                write!(writer, "trailer: {}\r\n", names.join(", "))?;
+            }
+            write!(writer, "\r\n")?;
+            if self.omit_body {
+                return Ok(());
+            }
 
-        // Body if any
-        if let Some(body) = &self.body {
-            let encoded_body_bytes = if let Some(encoding) = &self.content_encoding {
-                write!(writer, "content-encoding: {encoding}\r\n")?;
-                encoding.encode_body(body)?
-            } else {
-                body.clone()
+            let chunked_writer = ChunkedWriter::new(&mut *writer);
+            let chunked_writer = match body {
+                Body::Full(bytes) => {
+                    write_encoded(&mut bytes.as_slice(), chunked_writer, content_encoding)?
+                }
+                Body::Stream(mut reader) => {
+                    write_encoded(&mut reader, chunked_writer, content_encoding)?
+                }
             };
+            chunked_writer.finish(&self.trailers)?;
+            return Ok(());
+        }
 
-            write!(
-                writer,
-                "content-length: {}\r\n\r\n",
-                encoded_body_bytes.len()
-            )?;
-            writer.write_all(&encoded_body_bytes)?;
-        } else {
-            // no body, the end
-            write!(writer, "\r\n")?;
+        match body {
+            Body::Full(bytes) => {
+                let encoded_body_bytes = if let Some(encoding) = content_encoding {
+                    encoding.encode_body(&bytes)?
+                } else {
+                    bytes
+                };
+
+                write!(
+                    writer,
+                    "content-length: {}\r\n\r\n",
+                    encoded_body_bytes.len()
+                )?;
+                if !self.omit_body {
+                    writer.write_all(&encoded_body_bytes)?;
+                }
+            }
+            Body::Stream(reader) => match (self.content_length, content_encoding) {
+                (Some(content_length), None) => {
+                    write!(writer, "content-length: {content_length}\r\n\r\n")?;
+                    if !self.omit_body {
+                        copy_exact(reader, writer, content_length)?;
+                    }
+                }
+                // Length unknown and chunked not available: the end of the body is the end of the
+                // connection
+                (_, content_encoding) => {
+                    write!(writer, "\r\n")?;
+                    if !self.omit_body {
+                        write_encoded(&mut { reader }, &mut *writer, content_encoding)?;
+                    }
+                }
+            },
         }
         Ok(())
     }
+
+    /// Length of the body as written, when it's known before writing it: not for a body
+    /// content-coded on the fly, nor one streamed without a length.
+    pub(crate) fn body_length(&self) -> Option<usize> {
+        match (&self.body, self.content_encoding()) {
+            (Some(Body::Full(bytes)), None) => Some(bytes.len()),
+            (Some(Body::Stream(_)), None) => self.content_length,
+            _ => None,
+        }
+    }
+
+    /// Writes the body alone, content-coded but not framed: for HTTP/2, whose DATA frames carry
+    /// their own lengths. Nothing is written for an answer to HEAD.
+    pub(crate) fn write_body_to<W: Write>(self, mut writer: W) -> std::io::Result<W> {
+        let content_encoding = self.content_encoding();
+        match self.body {
+            None => Ok(writer),
+            Some(_) if self.omit_body => Ok(writer),
+            Some(Body::Full(bytes)) => {
+                write_encoded(&mut bytes.as_slice(), writer, content_encoding)
+            }
+            Some(Body::Stream(reader)) => match (self.content_length, content_encoding) {
+                (Some(content_length), None) => {
+                    copy_exact(reader, &mut writer, content_length)?;
+                    Ok(writer)
+                }
+                (_, content_encoding) => write_encoded(&mut { reader }, writer, content_encoding),
+            },
+        }
+    }
+}
+
+/// Size of the blocks a streamed, identity-encoded body is read (and, when chunked, sent) in.
+/// Memory used to send a body stays bounded by this, whatever the body size.
+const STREAM_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Copies the body into `writer`, content-encoding it on the fly if needed.
+fn write_encoded<R: Read + ?Sized, W: Write>(
+    reader: &mut R,
+    mut writer: W,
+    content_encoding: Option<ContentEncoding>,
+) -> std::io::Result<W> {
+    match content_encoding {
+        Some(encoding) => encoding.encode_stream(reader, writer),
+        None => {
+            copy_in_blocks(reader, &mut writer)?;
+            Ok(writer)
+        }
+    }
+}
+
+/// Copies the `content_length` bytes of a body whose length was announced. A source ending short
+/// fails with `UnexpectedEof`: the length is sent already, so the connection can't go on.
+fn copy_exact<R: Read, W: Write + ?Sized>(
+    reader: R,
+    writer: &mut W,
+    content_length: usize,
+) -> std::io::Result<()> {
+    let copied = copy_in_blocks(&mut reader.take(content_length as u64), writer)?;
+    if copied < content_length as u64 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("body ended after {copied} of its {content_length} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+fn copy_in_blocks<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+) -> std::io::Result<u64> {
+    let mut block = vec![0; STREAM_BLOCK_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut block) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&block[..n])?;
+        copied += n as u64;
+    }
 }
 
 impl Display for HttpResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // Status line
-        write!(f, "{} {}\r\n", self.protocol_version, self.status_code,)?;
+        // Status line and header fields
+        write!(f, "{}", self.head())?;
 
-        // Content-type
-        write!(f, "Content-Type: {}\r\n", self.content_type)?;
-
-        // Close connection
-        if self.conn_close {
-            write!(f, "Connection: close\r\n")?;
+        if self.chunked {
+            write!(f, "transfer-encoding: chunked\r\n")?;
         }
 
-        // Body if any
-        if let Some(body) = &self.body {
+        // Body if any (a streamed body can't be displayed without consuming it)
+        if let Some(Body::Full(body)) = &self.body {
             // TODO: why borrowing self.body is needed here ? same
-            write!(f, "Content-Length: {}", body.len())?; // TODO: why putting self.body.len() does
-                                                          // not work ? ('fn is private')
-            if self.content_type == ContentType::PlainText {
+            if !self.chunked {
+                write!(f, "content-length: {}\r\n", body.len())?; // TODO: why putting self.body.len() does
+                                                                  // not work ? ('fn is private')
+            }
+            write!(f, "\r\n")?;
+            if self.content_type() == Some(ContentType::PlainText) {
                 match String::from_utf8(body.clone()) {
                     Ok(body_str) => write!(f, "{body_str}")?,
                     Err(e) => {
@@ -303,70 +730,75 @@ impl Display for HttpResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::http_commons::HttpVersion;
+    use crate::endpoints;
+    use crate::handler::Handler;
     use crate::http_request::HttpMethod;
-    use std::collections::HashMap;
+    use crate::http_request::HttpRequest;
+    use crate::middleware::Compression;
+    use crate::router::Router;
+    use std::path::Path;
 
     use crate::encoding::ContentEncoding;
 
+    fn handle(request: &HttpRequest, data_dir: &Path) -> HttpResponse {
+        let mut router = Router::new();
+        endpoints::register(&mut router, data_dir);
+        router.wrap(Compression);
+        router.handle(request)
+    }
+
     fn create_test_request(path: &str) -> HttpRequest {
-        HttpRequest {
-            http_method: HttpMethod::Get,
-            request_target: path.to_string(),
-            protocol_version: HttpVersion::Http11,
-            headers: HashMap::new(),
-            body: None,
-        }
+        HttpRequest::new(HttpMethod::Get, path).unwrap()
     }
 
     #[test]
     fn test_echo_endpoint_basic() {
         let request = create_test_request("/echo/hello");
-        let response = HttpResponse::new_from_request(&request, &Path::new(""));
+        let response = handle(&request, Path::new(""));
 
         assert!(matches!(response.status_code, StatusCode::Ok));
-        assert_eq!(response.content_type, ContentType::PlainText);
-        assert_eq!(response.content_length, 5);
+        assert_eq!(response.content_type(), Some(ContentType::PlainText));
+        assert_eq!(response.content_length, Some(5));
         assert_eq!(response.body.unwrap(), "hello".as_bytes());
     }
 
     #[test]
     fn test_echo_endpoint_empty() {
         let request = create_test_request("/echo/");
-        let response = HttpResponse::new_from_request(&request, &Path::new(""));
+        let response = handle(&request, Path::new(""));
 
         assert!(matches!(response.status_code, StatusCode::Ok));
-        assert_eq!(response.content_type, ContentType::PlainText);
-        assert_eq!(response.content_length, 0);
+        assert_eq!(response.content_type(), Some(ContentType::PlainText));
+        assert_eq!(response.content_length, Some(0));
         assert_eq!(response.body.unwrap(), "".as_bytes());
     }
 
     #[test]
     fn test_echo_endpoint_with_spaces() {
-        let request = create_test_request("/echo/hello world");
-        let response = HttpResponse::new_from_request(&request, &Path::new(""));
+        let request = create_test_request("/echo/hello%20world");
+        let response = handle(&request, Path::new(""));
 
         assert!(matches!(response.status_code, StatusCode::Ok));
-        assert_eq!(response.content_type, ContentType::PlainText);
-        assert_eq!(response.content_length, 11);
+        assert_eq!(response.content_type(), Some(ContentType::PlainText));
+        assert_eq!(response.content_length, Some(11));
         assert_eq!(response.body.unwrap(), "hello world".as_bytes());
     }
 
     #[test]
     fn test_echo_endpoint_special_chars() {
-        let request = create_test_request("/echo/hello!@#$%");
-        let response = HttpResponse::new_from_request(&request, &Path::new(""));
+        let request = create_test_request("/echo/hello!@%23$%25");
+        let response = handle(&request, Path::new(""));
 
         assert!(matches!(response.status_code, StatusCode::Ok));
-        assert_eq!(response.content_type, ContentType::PlainText);
-        assert_eq!(response.content_length, 10);
+        assert_eq!(response.content_type(), Some(ContentType::PlainText));
+        assert_eq!(response.content_length, Some(10));
         assert_eq!(response.body.unwrap(), "hello!@#$%".as_bytes());
     }
 
     #[test]
     fn test_response_write_to() {
         let request = create_test_request("/echo/test");
-        let response = HttpResponse::new_from_request(&request, &Path::new(""));
+        let response = handle(&request, Path::new(""));
         let mut output = Vec::new();
 
         response.write_to(&mut output).unwrap();
@@ -381,17 +813,15 @@ mod tests {
     #[test]
     fn test_echo_endpoint_with_gzip_encoding() {
         let mut request = create_test_request("/echo/compressed_content");
-        request
-            .headers
-            .insert("accept-encoding".to_string(), "gzip".to_string());
+        request.headers.insert("accept-encoding", "gzip");
 
-        let response = HttpResponse::new_from_request(&request, &Path::new(""));
+        let response = handle(&request, Path::new(""));
 
         assert!(matches!(response.status_code, StatusCode::Ok));
-        assert_eq!(response.content_type, ContentType::PlainText);
-        assert_eq!(response.content_encoding, Some(ContentEncoding::GZip));
+        assert_eq!(response.content_type(), Some(ContentType::PlainText));
+        assert_eq!(response.content_encoding(), Some(ContentEncoding::GZip));
 
-        assert!(response.content_length > 0);
+        assert!(response.content_length > Some(0));
 
         // NOTE: body gets compressed when response is written as bytes
         // --> the body should not be compressed yet
@@ -404,41 +834,36 @@ mod tests {
     #[test]
     fn test_echo_endpoint_with_multiple_encodings() {
         let mut request = create_test_request("/echo/hello");
-        request.headers.insert(
-            "accept-encoding".to_string(),
-            "deflate, gzip, br".to_string(),
-        );
+        request
+            .headers
+            .insert("accept-encoding", "deflate, gzip, br");
 
-        let response = HttpResponse::new_from_request(&request, Path::new(""));
+        let response = handle(&request, Path::new(""));
 
         assert!(matches!(response.status_code, StatusCode::Ok));
         // Should choose gzip as it's supported and within the list of proposed encoding schemes
-        assert_eq!(response.content_encoding, Some(ContentEncoding::GZip));
+        assert_eq!(response.content_encoding(), Some(ContentEncoding::GZip));
     }
 
     #[test]
     fn test_echo_endpoint_with_unsupported_encoding() {
         let mut request = create_test_request("/echo/hello");
-        request
-            .headers
-            .insert("accept-encoding".to_string(), "deflate, br".to_string());
+        request.headers.insert("accept-encoding", "deflate, br");
 
-        let response = HttpResponse::new_from_request(&request, &Path::new(""));
+        let response = handle(&request, Path::new(""));
 
         assert!(matches!(response.status_code, StatusCode::Ok));
         // Should not have Content-Encoding header as no supported encoding was requested
-        assert!(response.content_encoding.is_none());
+        assert!(response.content_encoding().is_none());
         assert_eq!(response.body.unwrap(), "hello".as_bytes());
     }
 
     #[test]
     fn test_response_write_to_with_gzip() {
         let mut request = create_test_request("/echo/test_compressed");
-        request
-            .headers
-            .insert("accept-encoding".to_string(), "gzip".to_string());
+        request.headers.insert("accept-encoding", "gzip");
 
-        let response = HttpResponse::new_from_request(&request, &Path::new(""));
+        let response = handle(&request, Path::new(""));
         let mut rcv_buff = Vec::new();
         response.write_to(&mut rcv_buff).unwrap();
 
@@ -448,4 +873,403 @@ mod tests {
         assert!(headers_str.contains("content-type: text/plain"));
         assert!(headers_str.contains("content-encoding: gzip"));
     }
+
+    #[test]
+    fn test_gzip_then_if_range() {
+        let data_dir = std::env::temp_dir().canonicalize().unwrap();
+        let filename = format!("flyweight-if-range-{}.txt", std::process::id());
+        std::fs::write(data_dir.join(&filename), "some text to resume").unwrap();
+        let request = |if_range: Option<&str>| {
+            let mut request = create_test_request(&format!("/files/{filename}"));
+            request.headers.insert("accept-encoding", "gzip");
+            if let Some(if_range) = if_range {
+                request.headers.insert("range", "bytes=5-");
+                request.headers.insert("if-range", if_range);
+            }
+            handle(&request, &data_dir)
+        };
+
+        // The gzip representation gets a weak tag of its own...
+        let gzipped = request(None);
+        assert_eq!(gzipped.content_encoding(), Some(ContentEncoding::GZip));
+        let etag = gzipped.headers.get("etag").unwrap().to_string();
+        assert!(etag.starts_with("W/\""));
+
+        // ...so resuming from a gzip prefix gets the whole representation, not identity bytes
+        let resumed = request(Some(&etag));
+        assert!(matches!(resumed.status_code, StatusCode::Ok));
+        assert_eq!(resumed.content_encoding(), Some(ContentEncoding::GZip));
+
+        // The identity tag still resumes
+        let resumed = request(Some(etag.trim_start_matches("W/")));
+        std::fs::remove_file(data_dir.join(&filename)).unwrap();
+        assert!(matches!(resumed.status_code, StatusCode::PartialContent));
+        assert_eq!(resumed.content_encoding(), None);
+        let mut output = Vec::new();
+        resumed.write_to(&mut output).unwrap();
+        assert!(output.ends_with(b"\r\n\r\ntext to resume"));
+    }
+
+    #[test]
+    fn test_stream_body_without_length_is_chunked() {
+        let mut builder = HttpResponse::builder();
+        builder.with_body_stream(std::io::Cursor::new(b"streamed body".to_vec()));
+        let response = builder.build();
+        assert!(response.chunked);
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        let response_str = String::from_utf8(output).unwrap();
+        assert!(response_str.contains("transfer-encoding: chunked\r\n"));
+        assert!(!response_str.contains("content-length"));
+        assert!(response_str.ends_with("\r\n\r\nd\r\nstreamed body\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_http10_body_without_length_ends_with_connection() {
+        let mut builder = HttpResponse::builder();
+        builder.with_body_stream(std::io::Cursor::new(b"streamed body".to_vec()));
+        builder.with_trailer("x-checksum", "42");
+        let mut response = builder.build();
+        response.set_protocol_version(HttpVersion::Http10);
+        assert!(!response.chunked);
+        assert!(response.conn_close());
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        let response_str = String::from_utf8(output).unwrap();
+        assert!(response_str.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(!response_str.contains("transfer-encoding"));
+        assert!(!response_str.contains("x-checksum"));
+        assert!(response_str.ends_with("\r\n\r\nstreamed body"));
+    }
+
+    #[test]
+    fn test_stream_body_with_length() {
+        let mut builder = HttpResponse::builder();
+        builder.with_content_length(6);
+        builder.with_body_stream(std::io::Cursor::new(b"sized!".to_vec()));
+        let response = builder.build();
+        assert!(!response.chunked);
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        let response_str = String::from_utf8(output).unwrap();
+        assert!(response_str.contains("content-length: 6\r\n\r\nsized!"));
+        assert!(!response_str.contains("transfer-encoding"));
+    }
+
+    #[test]
+    fn test_gzip_stream_body_is_chunked() {
+        use flate2::read::GzDecoder;
+
+        let mut builder = HttpResponse::builder();
+        builder.with_content_length(16);
+        builder.with_content_encoding(Some(ContentEncoding::GZip));
+        builder.with_body_stream(std::io::Cursor::new(b"compress me, ok?".to_vec()));
+        let response = builder.build();
+        assert!(response.chunked); // encoded length isn't known up front
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        let body_start = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let headers_str = String::from_utf8_lossy(&output[..body_start]);
+        assert!(headers_str.contains("content-encoding: gzip"));
+        assert!(headers_str.contains("transfer-encoding: chunked"));
+
+        let chunked_body =
+            crate::chunked::read_chunked_body(&mut &output[body_start..], &Default::default())
+                .unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(chunked_body.data.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "compress me, ok?");
+    }
+
+    #[test]
+    fn test_trailers_force_chunked() {
+        let mut builder = HttpResponse::builder();
+        builder.with_body(b"hello");
+        builder.with_trailer("X-Checksum", "1234");
+        let response = builder.build();
+        assert!(response.chunked);
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        let response_str = String::from_utf8(output).unwrap();
+        assert!(response_str.contains("trailer: x-checksum\r\n"));
+        assert!(response_str.ends_with("5\r\nhello\r\n0\r\nx-checksum: 1234\r\n\r\n"));
+
+        // A name repeated apart is announced once
+        let mut builder = HttpResponse::builder();
+        builder.with_body(b"hello");
+        builder.with_trailer("X-A", "1");
+        builder.with_trailer("X-B", "2");
+        builder.with_trailer("X-A", "3");
+        let mut output = Vec::new();
+        builder.build().write_to(&mut output).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("trailer: x-a, x-b\r\n"));
+    }
+
+    #[test]
+    fn test_interim_response() {
+        let mut output = Vec::new();
+        HttpResponse::new_interim(StatusCode::Continue)
+            .write_to(&mut output)
+            .unwrap();
+
+        assert_eq!(output, b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    #[test]
+    fn test_arbitrary_headers() {
+        let mut builder = HttpResponse::builder();
+        builder.with_header("Set-Cookie", "a=1");
+        builder.with_header("Location", "/next\r\nx-evil: 1");
+        builder.with_header("set-cookie", "b=2");
+        builder.with_header("Connection", "close");
+        let response = builder.build();
+        assert!(response.conn_close());
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        let response_str = String::from_utf8(output).unwrap();
+        assert!(response_str.contains(
+            "set-cookie: a=1\r\nlocation: /next  x-evil: 1\r\nset-cookie: b=2\r\nconnection: close\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_file_endpoint_streams_file() {
+        let data_dir = std::env::temp_dir().canonicalize().unwrap();
+        let filename = format!("flyweight-stream-{}.bin", std::process::id());
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(data_dir.join(&filename), &content).unwrap();
+
+        let request = create_test_request(&format!("/files/{filename}"));
+        let response = handle(&request, &data_dir);
+
+        assert!(matches!(response.status_code, StatusCode::Ok));
+        assert_eq!(response.content_type(), Some(ContentType::OctetStream));
+        assert_eq!(response.content_length, Some(content.len()));
+        assert!(matches!(response.body, Some(Body::Stream(_))));
+        assert!(!response.chunked);
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+        std::fs::remove_file(data_dir.join(&filename)).unwrap();
+
+        assert!(output.ends_with(&content));
+        let headers_str = String::from_utf8_lossy(&output[..output.len() - content.len()]);
+        assert!(headers_str.contains("content-length: 200000\r\n"));
+    }
+
+    #[test]
+    fn test_stream_shorter_than_its_length() {
+        let response = || {
+            let mut builder = HttpResponse::builder();
+            builder.with_body_stream(&b"short"[..]);
+            builder.with_content_length(10);
+            builder.build()
+        };
+
+        let error = response().write_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        let error = response().write_body_to(Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_head_drops_body_keeps_length() {
+        let mut request = create_test_request("/echo/hello");
+        request.http_method = HttpMethod::Head;
+        let response = handle(&request, Path::new(""));
+
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+
+        let response_str = String::from_utf8(output).unwrap();
+        assert!(response_str.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response_str.ends_with("content-length: 5\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_options_lists_allowed_methods() {
+        let mut request = create_test_request("/files/a");
+        request.http_method = HttpMethod::Options;
+        let response = handle(&request, Path::new(""));
+
+        assert!(matches!(response.status_code, StatusCode::Ok));
+        assert_eq!(
+            response.headers.get("allow"),
+            Some("GET, HEAD, POST, PUT, DELETE, OPTIONS")
+        );
+        assert!(response.body.is_none());
+
+        // Files at the root are read and written only
+        let request = HttpRequest::new(HttpMethod::Options, "/a").unwrap();
+        let response = handle(&request, Path::new(""));
+        assert_eq!(
+            response.headers.get("allow"),
+            Some("GET, HEAD, POST, OPTIONS")
+        );
+
+        let request = HttpRequest::new(HttpMethod::Options, "*").unwrap();
+        let response = handle(&request, Path::new(""));
+        assert_eq!(
+            response.headers.get("allow"),
+            Some("GET, HEAD, OPTIONS, POST, PUT, DELETE")
+        );
+    }
+
+    #[test]
+    fn test_method_not_allowed_or_not_implemented() {
+        // What `/echo` leaves out doesn't fall through to the files
+        let mut request = create_test_request("/echo/hello");
+        request.http_method = HttpMethod::Delete;
+        let response = handle(&request, Path::new(""));
+        assert!(matches!(response.status_code, StatusCode::MethodNotAllowed));
+        assert_eq!(response.headers.get("allow"), Some("GET, HEAD, OPTIONS"));
+
+        request.http_method = HttpMethod::Extension("PROPFIND".into());
+        let response = handle(&request, Path::new(""));
+        assert!(matches!(response.status_code, StatusCode::NotImplemented));
+    }
+
+    #[test]
+    fn test_built_in_paths_are_not_files() {
+        let data_dir = std::env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("flyweight-not-files-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        std::fs::write(data_dir.join("sleep"), "kept").unwrap();
+
+        for method in [HttpMethod::Delete, HttpMethod::Put, HttpMethod::Post] {
+            let mut request = create_test_request("/sleep");
+            request.http_method = method;
+            request.body = Some(bytes::Bytes::from("overwritten"));
+            let response = handle(&request, &data_dir);
+            assert!(matches!(response.status_code, StatusCode::MethodNotAllowed));
+            assert_eq!(response.headers.get("allow"), Some("GET, HEAD, OPTIONS"));
+        }
+        assert_eq!(
+            std::fs::read_to_string(data_dir.join("sleep")).unwrap(),
+            "kept"
+        );
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_user_route_beside_built_in_methods() {
+        let data_dir = std::env::temp_dir().canonicalize().unwrap();
+        let filename = format!("flyweight-beside-{}.txt", std::process::id());
+        let mut router = Router::new();
+        router.get("/files/*path", |_| {
+            let mut builder = HttpResponse::builder();
+            builder.with_body(b"mine");
+            builder.build()
+        });
+        endpoints::register(&mut router, &data_dir);
+
+        let response = router.handle(&create_test_request(&format!("/files/{filename}")));
+        assert_eq!(response.body.unwrap(), b"mine"[..]);
+
+        // The user's GET doesn't hide the built-in POST on the same pattern
+        let mut request = create_test_request(&format!("/files/{filename}"));
+        request.http_method = HttpMethod::Post;
+        request.body = Some(bytes::Bytes::from("posted"));
+        let response = router.handle(&request);
+        assert!(matches!(response.status_code, StatusCode::Created));
+        assert_eq!(
+            std::fs::read_to_string(data_dir.join(&filename)).unwrap(),
+            "posted"
+        );
+        std::fs::remove_file(data_dir.join(&filename)).unwrap();
+    }
+
+    #[test]
+    fn test_put_then_delete_file() {
+        let data_dir = std::env::temp_dir().canonicalize().unwrap();
+        let filename = format!("flyweight-put-{}.txt", std::process::id());
+        let file_path = data_dir.join(&filename);
+        let request = |method: HttpMethod, body: Option<&'static str>| {
+            let mut request = create_test_request(&format!("/files/{filename}"));
+            request.http_method = method;
+            request.body = body.map(bytes::Bytes::from);
+            handle(&request, &data_dir).status_code
+        };
+
+        let created = request(HttpMethod::Put, Some("first"));
+        assert!(matches!(created, StatusCode::Created));
+        let replaced = request(HttpMethod::Put, Some("second"));
+        assert!(matches!(replaced, StatusCode::NoContent));
+        assert_eq!(std::fs::read_to_string(&file_path).unwrap(), "second");
+
+        let deleted = request(HttpMethod::Delete, None);
+        assert!(matches!(deleted, StatusCode::NoContent));
+        assert!(!file_path.exists());
+        let not_found = request(HttpMethod::Delete, None);
+        assert!(matches!(not_found, StatusCode::NotFound));
+    }
+
+    #[test]
+    fn test_file_targets_are_decoded() {
+        let data_dir = std::env::temp_dir().canonicalize().unwrap();
+        let filename = format!("flyweight decoded {}.txt", std::process::id());
+        std::fs::write(data_dir.join(&filename), "spaced out").unwrap();
+
+        let encoded = filename.replace(' ', "%20");
+        let response = handle(
+            &create_test_request(&format!("/files/{encoded}?foo=bar#top")),
+            &data_dir,
+        );
+        std::fs::remove_file(data_dir.join(&filename)).unwrap();
+
+        assert!(matches!(response.status_code, StatusCode::Ok));
+        assert_eq!(response.content_length, Some(10));
+
+        // Traversal hidden behind escapes is caught once decoded
+        for target in ["/files/%2e%2e/etc/passwd", "/files/..%2Fetc%2Fpasswd"] {
+            let mut request = create_test_request(target);
+            let response = handle(&request, &data_dir);
+            assert!(
+                matches!(response.status_code, StatusCode::NotFound),
+                "{target}"
+            );
+
+            request.http_method = HttpMethod::Put;
+            request.body = Some(bytes::Bytes::from_static(b"x"));
+            let response = handle(&request, &data_dir);
+            assert!(
+                matches!(response.status_code, StatusCode::BadRequest),
+                "{target}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_traversal_rejected_for_writes() {
+        let data_dir = std::env::temp_dir().canonicalize().unwrap();
+        for method in [HttpMethod::Post, HttpMethod::Put, HttpMethod::Delete] {
+            let mut request = create_test_request("/files/../etc/passwd");
+            request.http_method = method;
+            request.body = Some(bytes::Bytes::from_static(b"x"));
+            let response = handle(&request, &data_dir);
+            assert!(
+                matches!(response.status_code, StatusCode::BadRequest),
+                "{}",
+                request.http_method
+            );
+        }
+    }
 }
@@ -1,12 +1,82 @@
+//! A small, multi-threaded HTTP/1.1 server, which also speaks HTTP/2 over cleartext (h2c).
+//!
+//! Register your own endpoints on a [`Router`]; the built-in ones (echo, user-agent, sleep and
+//! the files of the data directory) are added to it after yours. The most specific pattern wins,
+//! whoever registered it: yours take precedence for the same pattern and method only, so a
+//! catch-all of yours like `/*path` leaves `/echo/...` or `/files/...` to the built-in ones:
+//!
+//! ```no_run
+//! use flyweight_http_server::{Buildable, HttpResponse, ResponseBuilder, Router, Server};
+//! use std::path::Path;
+//!
+//! let mut router = Router::new();
+//! router.get("/users/:id", |req| {
+//!     let mut builder = HttpResponse::builder();
+//!     match req.path_params.parse::<u64>("id") {
+//!         Ok(id) => builder.with_body(format!("user {id}").as_bytes()),
+//!         Err(e) => builder.with_body(e.to_string().as_bytes()),
+//!     }
+//!     builder.build()
+//! });
+//!
+//! let server = Server::new(&"127.0.0.1:4221".parse().unwrap(), 4, Path::new("."), router);
+//! server.run().unwrap();
+//! ```
+//!
+//! Any [`Handler`] can also take every request itself, with [`Server::with_handler`].
+//!
+//! With the `tokio` feature, [`AsyncServer`] serves the same endpoints on a tokio runtime, and
+//! takes async handlers too: see [`AsyncRouter`].
+
+#[cfg(feature = "tokio")]
+mod async_handler;
+#[cfg(feature = "tokio")]
+mod async_server;
+mod chunked;
+mod conditional;
 mod encoding;
 mod endpoints;
+#[cfg(target_os = "linux")]
+mod event_loop;
+mod handler;
+mod headers;
+mod hpack;
+mod http2;
 mod http_commons;
+mod http_date;
 mod http_request;
 mod http_response;
+mod middleware;
+#[cfg(any(target_os = "linux", feature = "tokio"))]
+mod outgoing;
+mod range;
+#[cfg(any(target_os = "linux", feature = "tokio"))]
+mod request_parser;
+mod router;
+mod shutdown;
 mod thread_pool;
+mod uri;
 
 mod config;
 mod server;
 
+#[cfg(feature = "tokio")]
+pub use async_handler::{AsyncHandler, AsyncRouter, HandlerFuture};
+#[cfg(feature = "tokio")]
+pub use async_server::AsyncServer;
 pub use config::Builder;
-pub use server::Server;
+pub use encoding::ContentEncoding;
+pub use handler::Handler;
+pub use headers::Headers;
+pub use http_commons::HttpVersion;
+pub use http_request::{HttpMethod, HttpRequest, Limits, RequestError};
+pub use http_response::Builder as ResponseBuilder;
+pub use http_response::{
+    Body, Buildable, ContentType, HttpResponse, HttpResponseBuilder, ResponseError, StatusCode,
+};
+pub use middleware::{Compression, Middleware, Next};
+pub use router::{ParamError, PathParamError, PathParams, Router};
+pub use server::{ConnectionLimits, Engine, EngineParseError, Server};
+pub use shutdown::ServerHandle;
+pub use thread_pool::{PoolSizing, PoolStats, QueuePolicy, QueuePolicyParseError, ThreadPool};
+pub use uri::QueryParams;
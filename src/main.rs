@@ -2,6 +2,7 @@ use std::env;
 use std::error::Error;
 
 use flyweight_http_server::Builder;
+use flyweight_http_server::Router;
 use flyweight_http_server::Server;
 
 //TODO:
@@ -19,7 +20,22 @@ pub fn main() -> Result<(), Box<dyn Error>> {
 
     println!("Config: {cfg:?}");
 
-    let server = Server::new(&cfg.server_addr, cfg.pool_size, &cfg.data_dir);
+    let mut server = Server::new(
+        &cfg.server_addr,
+        cfg.pool_size,
+        &cfg.data_dir,
+        Router::new(),
+    );
+    server.limits = cfg.limits;
+    server.connection_limits = cfg.connection_limits;
+    server.engine = cfg.engine;
+    server.thread_pool.set_sizing(cfg.pool_sizing);
+    server.thread_pool.set_queue_capacity(cfg.queue_capacity);
+    server.queue_policy = cfg.queue_policy;
+    server.shutdown_timeout = cfg.shutdown_timeout;
+
+    #[cfg(unix)]
+    server.shutdown_on_signals()?;
 
     server.run()?;
 
@@ -0,0 +1,213 @@
+use crate::encoding::ContentEncoding;
+use crate::handler::Handler;
+use crate::http_request::HttpRequest;
+use crate::http_response::{HttpResponse, StatusCode};
+
+// Middlewares wrap request handling, onion-like. With middlewares A then B added to a router:
+//
+//   request  -> A -> B -> handler
+//   response <- A <- B <-
+//
+// Each one may look at (or replace) the request before calling `next`, answer by itself without
+// calling `next` at all, and post-process the response on the way out.
+
+/// A layer around request handling.
+pub trait Middleware: Send + Sync {
+    /// Handles the request, usually by passing it (or a modified copy) on with `next.run`.
+    fn handle(&self, http_request: &HttpRequest, next: Next<'_>) -> HttpResponse;
+
+    /// Same as `Handler::reject_before_body`, e.g. to answer 401 to an upload without
+    /// credentials before it is sent. Middlewares are asked in order, then the handler.
+    fn reject_before_body(&self, _http_request: &HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&HttpRequest, Next<'_>) -> HttpResponse + Send + Sync,
+{
+    fn handle(&self, http_request: &HttpRequest, next: Next<'_>) -> HttpResponse {
+        self(http_request, next)
+    }
+}
+
+/// The rest of the chain: the remaining middlewares, then the handler.
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(middlewares: &'a [Box<dyn Middleware>], handler: &'a dyn Handler) -> Self {
+        Next {
+            middlewares,
+            handler,
+        }
+    }
+
+    pub fn run(self, http_request: &HttpRequest) -> HttpResponse {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => {
+                middleware.handle(http_request, Next::new(rest, self.handler))
+            }
+            None => self.handler.handle(http_request),
+        }
+    }
+}
+
+/// Encodes response bodies with a content-coding the client accepts (`Accept-Encoding`).
+///
+/// Responses that already carry a `content-encoding`, have no body, or are partial (ranges
+/// refer to the identity representation) are left alone.
+pub struct Compression;
+
+impl Middleware for Compression {
+    fn handle(&self, http_request: &HttpRequest, next: Next<'_>) -> HttpResponse {
+        let mut http_response = next.run(http_request);
+
+        if http_response.body.is_none()
+            || http_response.headers.contains("content-encoding")
+            || matches!(http_response.status_code, StatusCode::PartialContent)
+        {
+            return http_response;
+        }
+
+        // The representation now depends on the request's `Accept-Encoding`, unless the handler
+        // said so already
+        let varies = http_response
+            .headers
+            .get_all("vary")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .any(|name| name == "*" || name.eq_ignore_ascii_case("accept-encoding"));
+        if !varies {
+            http_response.headers.append("vary", "accept-encoding");
+        }
+
+        let content_encoding =
+            ContentEncoding::from_header(http_request.headers.get_all("accept-encoding"));
+        http_response.set_content_encoding(content_encoding);
+
+        // A strong entity-tag names the identity bytes: the coded ones only get a weak one, so
+        // that a client resuming with `If-Range` isn't sent identity bytes to append to coded
+        // ones (If-Range compares strongly), while `If-None-Match` still revalidates
+        if content_encoding.is_some() {
+            if let Some(etag) = http_response
+                .headers
+                .get("etag")
+                .filter(|etag| !etag.starts_with("W/"))
+            {
+                let weak = format!("W/{etag}");
+                http_response.headers.insert("etag", &weak);
+            }
+        }
+
+        http_response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_request::HttpMethod;
+    use crate::http_response::{Body, Buildable, Builder};
+    use crate::router::Router;
+    use std::sync::{Arc, Mutex};
+
+    fn request(target: &str) -> HttpRequest {
+        let mut http_request = HttpRequest::new(HttpMethod::Get, target).unwrap();
+        http_request.headers.append("accept-encoding", "gzip");
+        http_request
+    }
+
+    /// Middleware recording its name in `log` on the way in and out.
+    fn tracing(name: &'static str, log: &Arc<Mutex<Vec<String>>>) -> impl Middleware {
+        let log = Arc::clone(log);
+        move |http_request: &HttpRequest, next: Next<'_>| {
+            log.lock().unwrap().push(format!("{name} in"));
+            let http_response = next.run(http_request);
+            log.lock().unwrap().push(format!("{name} out"));
+            http_response
+        }
+    }
+
+    fn text_router() -> Router {
+        let mut router = Router::new();
+        router.get("/text", |_| {
+            let mut builder = HttpResponse::builder();
+            builder.with_body(b"some text");
+            builder.build()
+        });
+        router
+    }
+
+    #[test]
+    fn test_middlewares_run_in_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut router = text_router();
+        router.wrap(tracing("a", &log)).wrap(tracing("b", &log));
+
+        let _ = router.handle(&request("/text"));
+
+        assert_eq!(*log.lock().unwrap(), ["a in", "b in", "b out", "a out"]);
+    }
+
+    #[test]
+    fn test_middleware_short_circuits_and_rewrites() {
+        let mut router = text_router();
+        router.wrap(|http_request: &HttpRequest, next: Next<'_>| {
+            if http_request.headers.contains("authorization") {
+                next.run(http_request)
+            } else {
+                let mut builder = HttpResponse::builder();
+                builder.with_status_code(StatusCode::BadRequest);
+                builder.build()
+            }
+        });
+        router.wrap(|http_request: &HttpRequest, next: Next<'_>| {
+            let mut rewritten = http_request.clone();
+            rewritten.path = "/text".into();
+            next.run(&rewritten)
+        });
+
+        let response = router.handle(&request("/old-text"));
+        assert!(matches!(response.status_code, StatusCode::BadRequest));
+
+        let mut authorized = request("/old-text");
+        authorized.headers.append("authorization", "secret");
+        let response = router.handle(&authorized);
+        assert!(matches!(response.status_code, StatusCode::Ok));
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut router = text_router();
+        router.get("/varied", |_| {
+            let mut builder = HttpResponse::builder();
+            builder.with_header("vary", "Accept-Encoding, Origin");
+            builder.with_body(b"some text");
+            builder.build()
+        });
+        router.wrap(Compression);
+
+        let response = router.handle(&request("/text"));
+        assert_eq!(response.content_encoding(), Some(ContentEncoding::GZip));
+        assert_eq!(response.headers.get("vary"), Some("accept-encoding"));
+        assert!(matches!(response.body, Some(Body::Full(_))));
+
+        let mut identity = request("/text");
+        identity.headers.remove("accept-encoding");
+        let response = router.handle(&identity);
+        assert_eq!(response.content_encoding(), None);
+
+        // Nothing to compress
+        let response = router.handle(&request("/missing"));
+        assert_eq!(response.content_encoding(), None);
+
+        // Varying on the coding already: not listed twice
+        let response = router.handle(&request("/varied"));
+        assert_eq!(response.content_encoding(), Some(ContentEncoding::GZip));
+        assert_eq!(response.headers.get_all("vary").count(), 1);
+    }
+}
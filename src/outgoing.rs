@@ -0,0 +1,44 @@
+use crate::http_response::HttpResponse;
+
+use std::io::{self, Write};
+
+// Responses on their way out of the engines that don't write to the socket from where the
+// handler runs (the epoll loop, the tokio server): they're written into a bounded channel, in
+// chunks, by whichever thread streams the body, and the connection's owner sends them on.
+
+/// Response chunks in flight between the thread writing a response and the connection.
+pub(crate) const CHANNEL_BOUND: usize = 4;
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The response in bytes, for responses small enough to be written at once.
+pub(crate) fn to_bytes(http_response: HttpResponse) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    // Writing to a Vec doesn't fail
+    let _ = http_response.write_to(&mut bytes);
+    bytes
+}
+
+/// Where a response gets written: into the channel to the connection. `send` passes a chunk on,
+/// `false` once nobody receives them anymore.
+pub(crate) struct ChannelWriter<F> {
+    send: F,
+}
+
+impl<F: FnMut(Vec<u8>) -> bool> ChannelWriter<F> {
+    pub(crate) fn new(send: F) -> ChannelWriter<F> {
+        ChannelWriter { send }
+    }
+}
+
+impl<F: FnMut(Vec<u8>) -> bool> Write for ChannelWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !(self.send)(buf.to_vec()) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
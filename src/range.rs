@@ -0,0 +1,360 @@
+use crate::conditional::Validators;
+use crate::http_date;
+use crate::http_response::ContentType;
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+
+// Range requests (RFC 9110, section 14):
+//
+//   Range          = ranges-specifier
+//   ranges-specifier = range-unit "=" range-set
+//   range-set      = 1#range-spec
+//   range-spec     = int-range / suffix-range
+//   int-range      = first-pos "-" [ last-pos ]
+//   suffix-range   = "-" suffix-length
+
+/// More ranges than this in one request and the header is ignored: serving many tiny (possibly
+/// overlapping) ranges is a well-known amplification trick.
+const MAX_RANGES: usize = 32;
+
+/// An inclusive byte range, already clamped to the representation length.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ByteRange {
+    pub first: u64,
+    pub last: u64,
+}
+
+impl ByteRange {
+    pub fn len(self) -> u64 {
+        self.last - self.first + 1
+    }
+
+    /// `Content-Range` value for this range of a representation of `complete_len` bytes.
+    pub fn content_range(self, complete_len: u64) -> String {
+        format!("bytes {}-{}/{complete_len}", self.first, self.last)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// No (usable) `Range` header: serve the whole representation.
+    Full,
+    Satisfiable(Vec<ByteRange>),
+    NotSatisfiable,
+}
+
+/// `Content-Range` value of a `416 Range Not Satisfiable` response.
+pub fn unsatisfied_content_range(complete_len: u64) -> String {
+    format!("bytes */{complete_len}")
+}
+
+/// Evaluates a `Range` header against a representation of `complete_len` bytes.
+///
+/// Headers we don't understand (other units, bad syntax, too many ranges) are ignored, as the
+/// RFC asks, which means serving the full representation.
+pub fn evaluate(range_header: Option<&str>, complete_len: u64) -> RangeRequest {
+    let Some(range_set) = range_header.and_then(|r| r.trim().strip_prefix("bytes=")) else {
+        return RangeRequest::Full;
+    };
+
+    let mut ranges = Vec::new();
+    for range_spec in range_set.split(',').map(str::trim) {
+        if range_spec.is_empty() {
+            continue; // empty list elements are allowed
+        }
+        let Some(range) = parse_range_spec(range_spec) else {
+            return RangeRequest::Full;
+        };
+        ranges.push(range);
+    }
+    if ranges.is_empty() || ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    let mut satisfiable: Vec<ByteRange> = ranges
+        .into_iter()
+        .filter_map(|range| range.clamp(complete_len))
+        .collect();
+    if satisfiable.is_empty() {
+        return RangeRequest::NotSatisfiable;
+    }
+
+    // Coalesce overlapping or adjacent ranges
+    satisfiable.sort_by_key(|range| range.first);
+    let mut coalesced: Vec<ByteRange> = Vec::with_capacity(satisfiable.len());
+    for range in satisfiable {
+        match coalesced.last_mut() {
+            Some(previous) if range.first <= previous.last + 1 => {
+                previous.last = previous.last.max(range.last);
+            }
+            _ => coalesced.push(range),
+        }
+    }
+
+    RangeRequest::Satisfiable(coalesced)
+}
+
+/// Whether the `If-Range` precondition holds, i.e. whether the `Range` header should be honored.
+/// Either a strong entity-tag match, or a date that is exactly the last modification time.
+pub fn if_range_matches(if_range: &str, validators: &Validators) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return !if_range.starts_with("W/") && if_range == validators.etag;
+    }
+
+    http_date::parse(if_range).is_some_and(|date| date == validators.last_modified)
+}
+
+enum RangeSpec {
+    Int { first: u64, last: Option<u64> },
+    Suffix(u64),
+}
+
+impl RangeSpec {
+    fn clamp(self, complete_len: u64) -> Option<ByteRange> {
+        match self {
+            RangeSpec::Int { first, last } if first < complete_len => Some(ByteRange {
+                first,
+                last: last.map_or(complete_len - 1, |last| last.min(complete_len - 1)),
+            }),
+            RangeSpec::Suffix(suffix_len) if suffix_len > 0 && complete_len > 0 => {
+                Some(ByteRange {
+                    first: complete_len.saturating_sub(suffix_len),
+                    last: complete_len - 1,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse_range_spec(range_spec: &str) -> Option<RangeSpec> {
+    let (first, last) = range_spec.split_once('-')?;
+    let parse_pos = |pos: &str| -> Option<u64> {
+        if pos.is_empty() || !pos.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        pos.parse().ok()
+    };
+
+    if first.is_empty() {
+        return Some(RangeSpec::Suffix(parse_pos(last)?));
+    }
+
+    let first = parse_pos(first)?;
+    let last = if last.is_empty() {
+        None
+    } else {
+        Some(parse_pos(last)?)
+    };
+    match last {
+        Some(last) if last < first => None,
+        _ => Some(RangeSpec::Int { first, last }),
+    }
+}
+
+/// `multipart/byteranges` body over several ranges of a file, read part by part.
+pub struct MultipartRanges {
+    file: fs::File,
+    segments: Vec<Segment>,
+    current: usize,
+    offset: u64, // within the current segment
+}
+
+enum Segment {
+    Delimiter(Vec<u8>),
+    FileRange(ByteRange),
+}
+
+impl MultipartRanges {
+    pub fn new(
+        file: fs::File,
+        ranges: &[ByteRange],
+        complete_len: u64,
+        content_type: ContentType,
+        boundary: &str,
+    ) -> MultipartRanges {
+        let mut segments = Vec::with_capacity(2 * ranges.len() + 1);
+        for range in ranges {
+            let part_headers = format!(
+                "\r\n--{boundary}\r\ncontent-type: {content_type}\r\ncontent-range: {}\r\n\r\n",
+                range.content_range(complete_len)
+            );
+            segments.push(Segment::Delimiter(part_headers.into_bytes()));
+            segments.push(Segment::FileRange(*range));
+        }
+        segments.push(Segment::Delimiter(
+            format!("\r\n--{boundary}--\r\n").into_bytes(),
+        ));
+
+        MultipartRanges {
+            file,
+            segments,
+            current: 0,
+            offset: 0,
+        }
+    }
+
+    /// Exact body length, so the response can carry a `content-length`.
+    pub fn len(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Delimiter(bytes) => bytes.len() as u64,
+                Segment::FileRange(range) => range.len(),
+            })
+            .sum()
+    }
+}
+
+impl Read for MultipartRanges {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while let Some(segment) = self.segments.get(self.current) {
+            let n = match segment {
+                Segment::Delimiter(bytes) => {
+                    let remaining = &bytes[usize::try_from(self.offset).unwrap_or(usize::MAX)..];
+                    let n = buf.len().min(remaining.len());
+                    buf[..n].copy_from_slice(&remaining[..n]);
+                    n
+                }
+                Segment::FileRange(range) => {
+                    if self.offset == 0 {
+                        self.file.seek(SeekFrom::Start(range.first))?;
+                    }
+                    let remaining = range.len() - self.offset;
+                    let max = buf
+                        .len()
+                        .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+                    let n = self.file.read(&mut buf[..max])?;
+                    if n == 0 && max > 0 {
+                        // File truncated while being served
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    n
+                }
+            };
+
+            if n == 0 {
+                self.current += 1;
+                self.offset = 0;
+                continue;
+            }
+            self.offset += n as u64;
+            return Ok(n);
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(first: u64, last: u64) -> ByteRange {
+        ByteRange { first, last }
+    }
+
+    #[test]
+    fn test_single_ranges() {
+        assert_eq!(
+            evaluate(Some("bytes=0-499"), 10_000),
+            RangeRequest::Satisfiable(vec![range(0, 499)])
+        );
+        assert_eq!(
+            evaluate(Some("bytes=9500-"), 10_000),
+            RangeRequest::Satisfiable(vec![range(9500, 9999)])
+        );
+        assert_eq!(
+            evaluate(Some("bytes=-500"), 10_000),
+            RangeRequest::Satisfiable(vec![range(9500, 9999)])
+        );
+        // last-pos past the end is clamped, suffix longer than the file means the whole file
+        assert_eq!(
+            evaluate(Some("bytes=9000-20000"), 10_000),
+            RangeRequest::Satisfiable(vec![range(9000, 9999)])
+        );
+        assert_eq!(
+            evaluate(Some("bytes=-20000"), 10_000),
+            RangeRequest::Satisfiable(vec![range(0, 9999)])
+        );
+    }
+
+    #[test]
+    fn test_multiple_ranges_are_coalesced() {
+        assert_eq!(
+            evaluate(Some("bytes=500-600, 0-99, 601-999"), 10_000),
+            RangeRequest::Satisfiable(vec![range(0, 99), range(500, 999)])
+        );
+        assert_eq!(
+            evaluate(Some("bytes=0-0,-1"), 10_000),
+            RangeRequest::Satisfiable(vec![range(0, 0), range(9999, 9999)])
+        );
+    }
+
+    #[test]
+    fn test_unsatisfiable_ranges() {
+        assert_eq!(
+            evaluate(Some("bytes=10000-"), 10_000),
+            RangeRequest::NotSatisfiable
+        );
+        assert_eq!(
+            evaluate(Some("bytes=-0"), 10_000),
+            RangeRequest::NotSatisfiable
+        );
+        assert_eq!(
+            evaluate(Some("bytes=0-10"), 0),
+            RangeRequest::NotSatisfiable
+        );
+    }
+
+    #[test]
+    fn test_ignored_range_headers() {
+        for header in [
+            "items=0-10",
+            "bytes=10-5",
+            "bytes=a-b",
+            "bytes=",
+            "bytes=--5",
+            "bytes=+1-2",
+        ] {
+            assert_eq!(
+                evaluate(Some(header), 10_000),
+                RangeRequest::Full,
+                "{header}"
+            );
+        }
+        assert_eq!(evaluate(None, 10_000), RangeRequest::Full);
+
+        let too_many: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{i}-{i}")).collect();
+        let header = format!("bytes={}", too_many.join(","));
+        assert_eq!(evaluate(Some(&header), 10_000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn test_multipart_body() {
+        let path = std::env::temp_dir().join(format!("flyweight-ranges-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789abcdef").unwrap();
+        let file = fs::File::open(&path).unwrap();
+
+        let mut multipart = MultipartRanges::new(
+            file,
+            &[range(0, 3), range(10, 15)],
+            16,
+            ContentType::PlainText,
+            "BOUNDARY",
+        );
+        let announced_len = multipart.len();
+        let mut body = String::new();
+        multipart.read_to_string(&mut body).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(body.len() as u64, announced_len);
+        assert_eq!(
+            body,
+            "\r\n--BOUNDARY\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-3/16\r\n\r\n0123\
+             \r\n--BOUNDARY\r\ncontent-type: text/plain\r\ncontent-range: bytes 10-15/16\r\n\r\nabcdef\
+             \r\n--BOUNDARY--\r\n"
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use crate::http_request::{Framing, HttpRequest, Limits, RequestError};
+
+use std::io::{BufReader, Chain, Cursor, Read};
+
+// Incremental request parsing, for connections that can't block on a read: bytes are fed as they
+// arrive, and `parse` tells whether a whole request is there yet. Nothing is parsed twice over:
+// once a request is known to be complete, `read_head` and `read_body` take it from the buffer,
+// so the syntax and the limits are exactly those of the blocking engine.
+
+/// Request parser fed by the bytes of a connection, as they come.
+#[derive(Default)]
+pub(crate) struct RequestParser {
+    buf: Vec<u8>,
+    /// How far the end of the header section was looked for.
+    searched: usize,
+    head: Option<Head>,
+}
+
+/// Header section of the request underway, waiting for its body.
+struct Head {
+    request: HttpRequest,
+    len: usize,
+    framing: Framing,
+    chunks: ChunkScan,
+}
+
+impl RequestParser {
+    pub(crate) fn new() -> RequestParser {
+        RequestParser::default()
+    }
+
+    pub(crate) fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Header section of the request underway, once it is complete but its body isn't.
+    pub(crate) fn head(&self) -> Option<&HttpRequest> {
+        self.head.as_ref().map(|head| &head.request)
+    }
+
+    /// Whether nothing of a next request was received yet.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Bytes received past the requests parsed so far.
+    pub(crate) fn into_buffered(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// The connection read from a blocking thread, once it's switched to HTTP/2: what the parser
+    /// holds already comes first, the end of the preface, maybe more.
+    pub(crate) fn into_reader<R: Read>(self, rest: R) -> BufReader<Chain<Cursor<Vec<u8>>, R>> {
+        BufReader::new(Cursor::new(self.into_buffered()).chain(rest))
+    }
+
+    /// The next request, once all of it has been fed. What follows it stays in the buffer: it
+    /// is the start of a pipelined request.
+    /// # Errors
+    /// Returns the `RequestError` of `read_head` or `read_body`, as soon as the bytes fed make
+    /// it certain.
+    pub(crate) fn parse(&mut self, limits: &Limits) -> Result<Option<HttpRequest>, RequestError> {
+        if self.head.is_none() && !self.parse_head(limits)? {
+            return Ok(None);
+        }
+        let Some(head) = self.head.as_mut() else {
+            return Ok(None);
+        };
+
+        let body = &self.buf[head.len..];
+        let complete = match head.framing {
+            Framing::None => true,
+            Framing::Length(n_bytes) => body.len() >= n_bytes,
+            Framing::Chunked => head.chunks.done(body, limits),
+        };
+        if !complete {
+            return Ok(None);
+        }
+
+        let Some(Head {
+            mut request, len, ..
+        }) = self.head.take()
+        else {
+            return Ok(None);
+        };
+        let mut rest = &self.buf[len..];
+        request.read_body(&mut rest, limits)?;
+        let consumed = self.buf.len() - rest.len();
+        self.buf.drain(..consumed);
+        self.searched = 0;
+        Ok(Some(request))
+    }
+
+    /// Parses the header section if it is all there. `false` while it isn't.
+    fn parse_head(&mut self, limits: &Limits) -> Result<bool, RequestError> {
+        let from = self.searched.saturating_sub(2);
+        let Some(end) = find(&self.buf[from..], b"\n\r\n") else {
+            self.searched = self.buf.len();
+            // Too long already? Reading what there is gives the error (414 or 431)
+            let bound = if self.buf.contains(&b'\n') {
+                limits.max_request_line + limits.max_header_bytes
+            } else {
+                limits.max_request_line
+            };
+            if self.buf.len() >= bound {
+                let error = HttpRequest::read_head(&mut &self.buf[..], limits).err();
+                return Err(error.unwrap_or(RequestError::HeaderFieldsTooLarge));
+            }
+            return Ok(false);
+        };
+
+        let mut rest = &self.buf[..from + end + 3];
+        let request = HttpRequest::read_head(&mut rest, limits)?;
+        let len = from + end + 3 - rest.len();
+        let framing = request.framing(limits)?;
+        self.head = Some(Head {
+            request,
+            len,
+            framing,
+            chunks: ChunkScan::default(),
+        });
+        Ok(true)
+    }
+}
+
+/// Progress through a chunked body, to know where it ends without decoding it.
+#[derive(Default)]
+struct ChunkScan {
+    /// Start of the next line, from the start of the body.
+    pos: usize,
+    data_len: usize,
+    /// Size of the trailer section so far, once past the last chunk.
+    trailer_bytes: Option<usize>,
+}
+
+impl ChunkScan {
+    /// Goes over the chunks received so far. `true` once the body is complete, or is sure to be
+    /// refused: `read_body` tells which.
+    fn done(&mut self, body: &[u8], limits: &Limits) -> bool {
+        loop {
+            let Some(rest) = body.get(self.pos..) else {
+                return false; // within chunk data
+            };
+            let budget = match self.trailer_bytes {
+                Some(bytes) => limits.max_header_bytes.saturating_sub(bytes),
+                None => limits.max_header_bytes,
+            };
+            let Some(line_len) = rest.iter().position(|&b| b == b'\n').map(|i| i + 1) else {
+                return rest.len() >= budget;
+            };
+            if line_len > budget {
+                return true;
+            }
+            let line = &rest[..line_len];
+            self.pos += line_len;
+
+            if let Some(bytes) = self.trailer_bytes.as_mut() {
+                if line == b"\r\n" || line == b"\n" {
+                    return true;
+                }
+                *bytes += line_len;
+                continue;
+            }
+
+            let digits = line.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+            let chunk_size = std::str::from_utf8(&line[..digits])
+                .ok()
+                .and_then(|size| usize::from_str_radix(size, 16).ok());
+            match chunk_size {
+                Some(0) => self.trailer_bytes = Some(0),
+                Some(size) if size <= limits.max_body - self.data_len => {
+                    self.data_len += size;
+                    self.pos += size + 2; // chunk data and its CRLF
+                }
+                _ => return true, // malformed or too large
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `input` one byte at a time, returning the requests parsed along the way.
+    fn parse_bytewise(input: &[u8], limits: &Limits) -> Result<Vec<HttpRequest>, RequestError> {
+        let mut parser = RequestParser::new();
+        let mut requests = Vec::new();
+        for byte in input {
+            parser.feed(std::slice::from_ref(byte));
+            if let Some(request) = parser.parse(limits)? {
+                requests.push(request);
+            }
+        }
+        Ok(requests)
+    }
+
+    #[test]
+    fn test_bytewise_and_pipelined() {
+        let input = b"GET /echo/abc HTTP/1.1\r\nHost: x\r\n\r\n\
+            POST /files/a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+            GET / HTTP/1.1\r\n\r\n";
+        let requests = parse_bytewise(input, &Limits::default()).unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].path, "/echo/abc");
+        assert_eq!(requests[0].headers.get("host"), Some("x"));
+        assert_eq!(requests[1].body.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(requests[2].path, "/");
+
+        // All at once: one request per call, the rest kept
+        let mut parser = RequestParser::new();
+        parser.feed(input);
+        let limits = Limits::default();
+        assert_eq!(parser.parse(&limits).unwrap().unwrap().path, "/echo/abc");
+        assert_eq!(parser.parse(&limits).unwrap().unwrap().path, "/files/a");
+        assert_eq!(parser.parse(&limits).unwrap().unwrap().path, "/");
+        assert!(parser.parse(&limits).unwrap().is_none());
+        assert!(parser.into_buffered().is_empty());
+    }
+
+    #[test]
+    fn test_head_before_body() {
+        let limits = Limits::default();
+        let mut parser = RequestParser::new();
+        parser.feed(b"PUT /up HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n");
+        assert!(parser.parse(&limits).unwrap().is_none());
+        assert!(parser.head().unwrap().expects_continue());
+
+        parser.feed(b"o");
+        assert!(parser.parse(&limits).unwrap().is_none());
+        parser.feed(b"k");
+        let request = parser.parse(&limits).unwrap().unwrap();
+        assert_eq!(request.body.as_deref(), Some(&b"ok"[..]));
+        assert!(parser.head().is_none());
+    }
+
+    #[test]
+    fn test_chunked() {
+        let input = b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nChecksum: abc\r\n\r\n\
+            GET / HTTP/1.1\r\n\r\n";
+        let requests = parse_bytewise(input, &Limits::default()).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body.as_deref(), Some(&b"hello world"[..]));
+        assert_eq!(requests[0].trailers.get("checksum"), Some("abc"));
+        assert_eq!(requests[1].path, "/");
+
+        // Chunk data may hold what looks like a chunk-size line
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n0\r\n\r\n0\r\n\r\n";
+        let requests = parse_bytewise(input, &Limits::default()).unwrap();
+        assert_eq!(requests[0].body.as_deref(), Some(&b"0\r\n"[..]));
+
+        let input = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        assert!(matches!(
+            parse_bytewise(input, &Limits::default()),
+            Err(RequestError::BodyChunked(_))
+        ));
+    }
+
+    #[test]
+    fn test_limits_before_the_end() {
+        let limits = Limits {
+            max_request_line: 32,
+            max_headers: 2,
+            max_header_bytes: 64,
+            max_body: 8,
+        };
+        let error = |input: &[u8]| {
+            let mut parser = RequestParser::new();
+            parser.feed(input);
+            parser.parse(&limits).unwrap_err().status_code()
+        };
+
+        // Refused without waiting for the end of the line or of the section
+        let long_target = format!("GET /{} HTTP/1.1", "a".repeat(40));
+        assert_eq!(error(long_target.as_bytes()).code(), 414);
+        let long_field = format!("GET / HTTP/1.1\r\nX: {}", "a".repeat(80));
+        assert_eq!(error(long_field.as_bytes()).code(), 431);
+        assert_eq!(
+            error(b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n").code(),
+            413
+        );
+        assert_eq!(
+            error(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n5\r\n")
+                .code(),
+            413
+        );
+    }
+
+    #[test]
+    fn test_preface_leaves_the_rest() {
+        let mut parser = RequestParser::new();
+        parser.feed(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0");
+        let request = parser.parse(&Limits::default()).unwrap().unwrap();
+        assert_eq!(request.request_target, "*");
+        assert_eq!(parser.into_buffered(), b"SM\r\n\r\n\0\0");
+    }
+}
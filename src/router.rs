@@ -0,0 +1,666 @@
+use crate::handler::Handler;
+use crate::http_request::{HttpMethod, HttpRequest};
+use crate::http_response::{Buildable, Builder, HttpResponse, HttpResponseBuilder, StatusCode};
+use crate::middleware::{Middleware, Next};
+use crate::uri;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+// Routes are patterns of `/`-separated segments:
+//
+//   /user-agent        static segments must match exactly
+//   /users/:id         `:name` captures one non-empty segment
+//   /static/*path      `*name` captures the rest of the path, slashes included (last segment only)
+//
+// Patterns match the raw path, split on `/` before each segment is percent-decoded: `%2F` is part
+// of a segment, so `/users/a%2Fb` gives `:id` the value `a/b`.
+//
+// When several patterns match a path, the most specific one with a handler for the method wins:
+// segments are compared left to right, static before parameter before wildcard. So a catch-all
+// like `/*path` never steals a request from a more specific route, but still takes the methods
+// that route leaves out. 405 is for a method no matching route has, and `Allow` lists the
+// methods of them all. Fallback routes (the built-in files at the root) are the exception: they
+// only take the paths no other route matches, whatever the method.
+
+/// Dispatches requests to handlers registered by method and path pattern.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+struct Route {
+    pattern: Pattern,
+    handlers: Vec<(HttpMethod, Box<dyn Handler>)>,
+    /// Only for the paths no other route matches.
+    fallback: bool,
+}
+
+impl Router {
+    #[must_use]
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Registers `handler` for requests with `method` on paths matching `pattern`. Closures
+    /// passed here need their argument typed (`|req: &HttpRequest| ...`), unlike with the
+    /// `get`, `post`, `put` and `delete` shorthands.
+    ///
+    /// GET handlers also answer HEAD requests (the body is left out), and OPTIONS is answered
+    /// from the registered methods, unless handlers are registered for those explicitly. When a
+    /// pattern and method pair is registered twice, the first handler is kept: routes added to a
+    /// router before it's handed to the server take precedence over the built-in ones.
+    ///
+    /// # Panics
+    /// Panics if the pattern is malformed: not starting with `/`, an unnamed parameter or
+    /// wildcard, or a wildcard that isn't the last segment.
+    pub fn route<H>(&mut self, method: HttpMethod, pattern: &str, handler: H) -> &mut Router
+    where
+        H: Handler + 'static,
+    {
+        self.add(method, pattern, handler, false)
+    }
+
+    /// Same as `route`, for a route that only takes the paths no other route matches: the
+    /// methods other routes leave out get 405 rather than reaching it. A pattern registered with
+    /// `route` already stays a regular route.
+    pub(crate) fn fallback<H>(
+        &mut self,
+        method: HttpMethod,
+        pattern: &str,
+        handler: H,
+    ) -> &mut Router
+    where
+        H: Handler + 'static,
+    {
+        self.add(method, pattern, handler, true)
+    }
+
+    fn add<H>(
+        &mut self,
+        method: HttpMethod,
+        pattern: &str,
+        handler: H,
+        fallback: bool,
+    ) -> &mut Router
+    where
+        H: Handler + 'static,
+    {
+        let pattern = match pattern.parse::<Pattern>() {
+            Ok(pattern) => pattern,
+            Err(e) => panic!("{e}"),
+        };
+
+        let route_index = match self.routes.iter().position(|r| r.pattern == pattern) {
+            Some(index) => index,
+            None => {
+                self.routes.push(Route {
+                    pattern,
+                    handlers: Vec::new(),
+                    fallback,
+                });
+                self.routes.len() - 1
+            }
+        };
+        let route = &mut self.routes[route_index];
+        if !route.handlers.iter().any(|(m, _)| *m == method) {
+            route.handlers.push((method, Box::new(handler)));
+        }
+        self
+    }
+
+    // The shorthands take closures rather than any `Handler`: that way the argument type of the
+    // closure is inferred
+
+    pub fn get<H>(&mut self, pattern: &str, handler: H) -> &mut Router
+    where
+        H: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.route(HttpMethod::Get, pattern, handler)
+    }
+
+    pub fn post<H>(&mut self, pattern: &str, handler: H) -> &mut Router
+    where
+        H: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.route(HttpMethod::Post, pattern, handler)
+    }
+
+    pub fn put<H>(&mut self, pattern: &str, handler: H) -> &mut Router
+    where
+        H: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.route(HttpMethod::Put, pattern, handler)
+    }
+
+    pub fn delete<H>(&mut self, pattern: &str, handler: H) -> &mut Router
+    where
+        H: Fn(&HttpRequest) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.route(HttpMethod::Delete, pattern, handler)
+    }
+
+    /// Adds a middleware around the routes. Middlewares run in the order they were added on the
+    /// way in (the first one sees the request first), and in reverse order on the way out. They
+    /// also wrap the router's own answers: 404, 405, OPTIONS.
+    pub fn wrap<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Router {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Routes the request to its handler.
+    fn dispatch(&self, http_request: &HttpRequest) -> HttpResponse {
+        let (handler, routed_request) = match self.resolve(http_request) {
+            Ok(resolved) => resolved,
+            Err(http_response) => return http_response,
+        };
+
+        let mut http_response = handler.handle(&routed_request);
+        if http_request.http_method == HttpMethod::Head {
+            http_response.omit_body = true;
+        }
+        http_response
+    }
+
+    /// The handler for the request, and the request as it sees it: with the captured path
+    /// parameters. `Err` is the router's own answer: `OPTIONS`, 404, 405 or 501.
+    fn resolve<'a>(
+        &'a self,
+        http_request: &'a HttpRequest,
+    ) -> Result<(&'a dyn Handler, Cow<'a, HttpRequest>), HttpResponse> {
+        let method = &http_request.http_method;
+
+        // `OPTIONS *` asks about the server as a whole rather than a resource
+        if *method == HttpMethod::Options && http_request.path == "*" {
+            let mut builder = Self::builder_for(http_request);
+            builder.with_allow(&self.server_methods());
+            return Err(builder.build());
+        }
+
+        let raw_path = http_request.raw_path();
+        let routes = self.matching_routes(&raw_path);
+        if routes.is_empty() {
+            let mut builder = Self::builder_for(http_request);
+            builder.with_status_code(StatusCode::NotFound);
+            return Err(builder.build());
+        }
+
+        let handler = routes.into_iter().find_map(|(route, path_params)| {
+            let handler = match route.handler(method) {
+                Some(handler) => Some(handler),
+                None if *method == HttpMethod::Head => route.handler(&HttpMethod::Get),
+                None => None,
+            };
+            handler.map(|handler| (handler, path_params))
+        });
+        let Some((handler, path_params)) = handler else {
+            let mut builder = Self::builder_for(http_request);
+            if *method != HttpMethod::Options {
+                // 405 if another route implements the method, 501 if none does
+                if self.server_methods().contains(method) {
+                    builder.with_status_code(StatusCode::MethodNotAllowed);
+                } else {
+                    builder.with_status_code(StatusCode::NotImplemented);
+                }
+            }
+            builder.with_allow(&self.path_methods(&raw_path));
+            return Err(builder.build());
+        };
+
+        if path_params.is_empty() {
+            Ok((handler, Cow::Borrowed(http_request)))
+        } else {
+            let mut routed_request = http_request.clone();
+            routed_request.path_params = path_params;
+            Ok((handler, Cow::Owned(routed_request)))
+        }
+    }
+
+    /// The routes matching the raw path, most specific first, and the parameters each
+    /// captures. On a tie, the first registered route comes first. Fallback routes only if
+    /// nothing else matches.
+    fn matching_routes(&self, path: &str) -> Vec<(&Route, PathParams)> {
+        let mut routes: Vec<(&Route, PathParams)> = self
+            .routes
+            .iter()
+            .filter_map(|route| Some((route, route.pattern.matches(path)?)))
+            .collect();
+        if routes.iter().any(|(route, _)| !route.fallback) {
+            routes.retain(|(route, _)| !route.fallback);
+        }
+        // A stable sort keeps the registration order of equally specific routes
+        routes.sort_by_key(|(route, _)| route.pattern.rank());
+        routes
+    }
+
+    /// Methods implemented by at least one route matching the path.
+    fn path_methods(&self, path: &str) -> Vec<HttpMethod> {
+        let routes = self.matching_routes(path);
+        dedup_methods(routes.iter().flat_map(|(route, _)| route.allowed_methods()))
+    }
+
+    /// Methods implemented by at least one route.
+    fn server_methods(&self) -> Vec<HttpMethod> {
+        dedup_methods(self.routes.iter().flat_map(Route::allowed_methods))
+    }
+
+    fn builder_for(http_request: &HttpRequest) -> HttpResponseBuilder {
+        let mut builder = HttpResponse::builder();
+        builder.with_protocol_version(http_request.protocol_version);
+        builder
+    }
+}
+
+impl Handler for Router {
+    /// Runs the request through the middlewares, then routes it to its handler. Handlers see the
+    /// captured parameters in `HttpRequest::path_params`.
+    fn handle(&self, http_request: &HttpRequest) -> HttpResponse {
+        let dispatch = |http_request: &HttpRequest| self.dispatch(http_request);
+        Next::new(&self.middlewares, &dispatch).run(http_request)
+    }
+
+    /// Asks the middlewares, then the route's handler. Requests the router itself would turn
+    /// down (404, 405, 501) are refused too: no point receiving their body. Its other answers,
+    /// to OPTIONS, wait for the body like any response.
+    fn reject_before_body(&self, http_request: &HttpRequest) -> Option<HttpResponse> {
+        if let Some(rejection) = self
+            .middlewares
+            .iter()
+            .find_map(|middleware| middleware.reject_before_body(http_request))
+        {
+            return Some(rejection);
+        }
+        match self.resolve(http_request) {
+            Ok((handler, routed_request)) => handler.reject_before_body(&routed_request),
+            Err(http_response) => matches!(
+                http_response.status_code,
+                StatusCode::NotFound | StatusCode::MethodNotAllowed | StatusCode::NotImplemented
+            )
+            .then_some(http_response),
+        }
+    }
+}
+
+impl Route {
+    fn handler(&self, method: &HttpMethod) -> Option<&dyn Handler> {
+        self.handlers
+            .iter()
+            .find(|(m, _)| m == method)
+            .map(|(_, handler)| handler.as_ref())
+    }
+
+    /// Methods listed in `Allow`: the registered ones, plus HEAD and OPTIONS which come for free.
+    fn allowed_methods(&self) -> Vec<HttpMethod> {
+        let mut methods: Vec<HttpMethod> = Vec::new();
+        for (method, _) in &self.handlers {
+            methods.push(method.clone());
+            if *method == HttpMethod::Get {
+                methods.push(HttpMethod::Head);
+            }
+        }
+        methods.push(HttpMethod::Options);
+        dedup_methods(methods)
+    }
+}
+
+/// The methods, each once, in order of first appearance.
+fn dedup_methods(methods: impl IntoIterator<Item = HttpMethod>) -> Vec<HttpMethod> {
+    let mut deduped: Vec<HttpMethod> = Vec::new();
+    for method in methods {
+        if !deduped.contains(&method) {
+            deduped.push(method);
+        }
+    }
+    deduped
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Static(String),
+    Param(String),
+    Wildcard(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct Pattern {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug)]
+pub struct PatternParseError {
+    pub pattern: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for PatternParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid route pattern {:?}: {}",
+            self.pattern, self.reason
+        )
+    }
+}
+
+impl std::error::Error for PatternParseError {}
+
+impl FromStr for Pattern {
+    type Err = PatternParseError;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason| PatternParseError {
+            pattern: pattern.into(),
+            reason,
+        };
+
+        let path = pattern
+            .strip_prefix('/')
+            .ok_or_else(|| invalid("must start with '/'"))?;
+        let raw_segments: Vec<&str> = path.split('/').collect();
+
+        let mut segments = Vec::with_capacity(raw_segments.len());
+        for (i, raw) in raw_segments.iter().enumerate() {
+            let segment = if let Some(name) = raw.strip_prefix(':') {
+                if name.is_empty() {
+                    return Err(invalid("unnamed parameter"));
+                }
+                Segment::Param(name.into())
+            } else if let Some(name) = raw.strip_prefix('*') {
+                if name.is_empty() {
+                    return Err(invalid("unnamed wildcard"));
+                }
+                if i + 1 != raw_segments.len() {
+                    return Err(invalid("a wildcard must be the last segment"));
+                }
+                Segment::Wildcard(name.into())
+            } else {
+                Segment::Static((*raw).into())
+            };
+            segments.push(segment);
+        }
+
+        Ok(Pattern { segments })
+    }
+}
+
+impl Pattern {
+    /// Parameters captured from the raw (percent-encoded) `path`, decoded, if it matches.
+    pub(crate) fn matches(&self, path: &str) -> Option<PathParams> {
+        let mut rest = Some(path.strip_prefix('/')?);
+        let mut path_params = PathParams::default();
+
+        for segment in &self.segments {
+            let remaining = rest?;
+            let (current, next) = match remaining.split_once('/') {
+                Some((current, next)) => (current, Some(next)),
+                None => (remaining, None),
+            };
+            match segment {
+                Segment::Static(s) if *s == uri::decode_path(current)? => {}
+                Segment::Param(name) if !current.is_empty() => {
+                    path_params.push(name, &uri::decode_path(current)?);
+                }
+                Segment::Wildcard(name) => {
+                    path_params.push(name, &uri::decode_path(remaining)?);
+                    return Some(path_params);
+                }
+                _ => return None,
+            }
+            rest = next;
+        }
+
+        // The whole path must be consumed
+        rest.is_none().then_some(path_params)
+    }
+
+    /// Sort key: lower is more specific.
+    pub(crate) fn rank(&self) -> Vec<u8> {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Static(_) => 0,
+                Segment::Param(_) => 1,
+                Segment::Wildcard(_) => 2,
+            })
+            .collect()
+    }
+}
+
+/// Parameters captured by the route pattern, in pattern order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PathParams {
+    params: Vec<(String, String)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParamError {
+    Missing(String),
+    Invalid { name: String, value: String },
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::Missing(name) => write!(f, "no parameter named {name:?}"),
+            ParamError::Invalid { name, value } => {
+                write!(f, "invalid value for parameter {name:?}: {value:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+/// The name `ParamError` had before query parameters shared it.
+pub type PathParamError = ParamError;
+
+impl PathParams {
+    fn push(&mut self, name: &str, value: &str) {
+        self.params.push((name.into(), value.into()));
+    }
+
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The parameter parsed as a `T`, e.g. `path_params.parse::<u64>("id")`.
+    /// # Errors
+    /// Returns a `ParamError` if there is no such parameter or its value doesn't parse.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T, ParamError> {
+        let value = self
+            .get(name)
+            .ok_or_else(|| ParamError::Missing(name.into()))?;
+        value.parse().map_err(|_| ParamError::Invalid {
+            name: name.into(),
+            value: value.into(),
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: HttpMethod, target: &str) -> HttpRequest {
+        HttpRequest::new(method, target).unwrap()
+    }
+
+    fn text_response(text: &str) -> HttpResponse {
+        let mut builder = HttpResponse::builder();
+        builder.with_content_length(text.len());
+        builder.with_body(text.as_bytes());
+        builder.build()
+    }
+
+    fn body_of(response: HttpResponse) -> String {
+        match response.body {
+            Some(crate::http_response::Body::Full(bytes)) => String::from_utf8(bytes).unwrap(),
+            _ => String::new(),
+        }
+    }
+
+    #[test]
+    fn test_pattern_matching() {
+        let pattern: Pattern = "/users/:id/posts/:post".parse().unwrap();
+        let params = pattern.matches("/users/42/posts/7").unwrap();
+        assert_eq!(params.get("id"), Some("42"));
+        assert_eq!(params.parse::<u32>("post"), Ok(7));
+        assert!(pattern.matches("/users/42/posts").is_none());
+        assert!(pattern.matches("/users//posts/7").is_none());
+        assert!(pattern.matches("/users/42/posts/7/8").is_none());
+
+        let wildcard: Pattern = "/static/*path".parse().unwrap();
+        let params = wildcard.matches("/static/css/site.css").unwrap();
+        assert_eq!(params.get("path"), Some("css/site.css"));
+        assert_eq!(wildcard.matches("/static/").unwrap().get("path"), Some(""));
+        assert!(wildcard.matches("/static").is_none());
+
+        // Segments are split before they're decoded
+        let params = pattern.matches("/users/a%2Fb/posts/%37").unwrap();
+        assert_eq!(params.get("id"), Some("a/b"));
+        assert_eq!(params.get("post"), Some("7"));
+        let params = wildcard.matches("/static/a%2Fb/c%20d").unwrap();
+        assert_eq!(params.get("path"), Some("a/b/c d"));
+        let user_agent: Pattern = "/user-agent".parse().unwrap();
+        assert!(user_agent.matches("/user%2Dagent").is_some());
+
+        let root: Pattern = "/".parse().unwrap();
+        assert!(root.matches("/").is_some());
+        assert!(root.matches("/a").is_none());
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        for pattern in ["users", "/users/:", "/static/*", "/*path/more"] {
+            assert!(pattern.parse::<Pattern>().is_err(), "{pattern}");
+        }
+    }
+
+    #[test]
+    fn test_typed_params() {
+        let mut router = Router::new();
+        router.get("/users/:id", |req| {
+            match req.path_params.parse::<u64>("id") {
+                Ok(id) => text_response(&format!("user {id}")),
+                Err(e) => text_response(&e.to_string()),
+            }
+        });
+
+        let response = router.handle(&request(HttpMethod::Get, "/users/42"));
+        assert_eq!(body_of(response), "user 42");
+        let response = router.handle(&request(HttpMethod::Get, "/users/bob"));
+        assert_eq!(
+            body_of(response),
+            "invalid value for parameter \"id\": \"bob\""
+        );
+
+        router.get("/names/:name", |req| {
+            text_response(req.path_params.get("name").unwrap_or_default())
+        });
+        let response = router.handle(&request(HttpMethod::Get, "/names/a%2Fb"));
+        assert_eq!(body_of(response), "a/b");
+    }
+
+    #[test]
+    fn test_most_specific_route_wins() {
+        let mut router = Router::new();
+        router.get("/*path", |_| text_response("catch-all"));
+        router.post("/*path", |_| text_response("upload"));
+        router.get("/users/:id", |_| text_response("user"));
+        router.get("/users/me", |_| text_response("me"));
+        router.get("/users/:id", |_| text_response("shadowed"));
+        router.put("/uploads", |_| text_response("stored"));
+
+        let get = |target: &str| body_of(router.handle(&request(HttpMethod::Get, target)));
+        assert_eq!(get("/users/me"), "me");
+        assert_eq!(get("/users/42"), "user");
+        assert_eq!(get("/users/42/avatar"), "catch-all");
+
+        // A method the specific route leaves out falls to the catch-all...
+        let response = router.handle(&request(HttpMethod::Post, "/users/42"));
+        assert_eq!(body_of(response), "upload");
+
+        // ...and 405 lists the methods of every route matching
+        let response = router.handle(&request(HttpMethod::Put, "/users/42"));
+        assert!(matches!(response.status_code, StatusCode::MethodNotAllowed));
+        assert_eq!(
+            response.headers.get("allow"),
+            Some("GET, HEAD, OPTIONS, POST")
+        );
+    }
+
+    #[test]
+    fn test_handler_types() {
+        struct Greeter {
+            greeting: String,
+        }
+        impl Handler for Greeter {
+            fn handle(&self, http_request: &HttpRequest) -> HttpResponse {
+                let name = http_request.path_params.get("name").unwrap_or_default();
+                text_response(&format!("{} {name}", self.greeting))
+            }
+        }
+
+        let mut router = Router::new();
+        router.route(
+            HttpMethod::Get,
+            "/hello/:name",
+            Greeter {
+                greeting: "hello".into(),
+            },
+        );
+        router.route(HttpMethod::Get, "/bye", |_: &HttpRequest| {
+            text_response("bye")
+        });
+
+        // A router is a handler too, so routers nest
+        let mut outer = Router::new();
+        outer.route(HttpMethod::Get, "/*path", router);
+
+        let get = |target: &str| body_of(outer.handle(&request(HttpMethod::Get, target)));
+        assert_eq!(get("/hello/bob"), "hello bob");
+        assert_eq!(get("/bye"), "bye");
+    }
+
+    #[test]
+    fn test_head_options_and_unknown_methods() {
+        let mut router = Router::new();
+        router.get("/hello", |_| text_response("hello"));
+        router.put("/files/*path", |_| text_response("stored"));
+
+        let head = router.handle(&request(HttpMethod::Head, "/hello"));
+        assert!(head.omit_body);
+        assert_eq!(head.content_length, Some(5));
+
+        let options = router.handle(&request(HttpMethod::Options, "/files/a"));
+        assert!(matches!(options.status_code, StatusCode::Ok));
+        assert_eq!(options.headers.get("allow"), Some("PUT, OPTIONS"));
+
+        let options = router.handle(&request(HttpMethod::Options, "*"));
+        assert_eq!(
+            options.headers.get("allow"),
+            Some("GET, HEAD, OPTIONS, PUT")
+        );
+
+        let brew = router.handle(&request(HttpMethod::Extension("BREW".into()), "/hello"));
+        assert!(matches!(brew.status_code, StatusCode::NotImplemented));
+
+        let missing = router.handle(&request(HttpMethod::Get, "/nope"));
+        assert!(matches!(missing.status_code, StatusCode::NotFound));
+    }
+}
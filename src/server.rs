@@ -1,48 +1,262 @@
-use crate::http_request::HttpRequest;
-use crate::http_response::HttpResponse;
-use crate::thread_pool::ThreadPool;
+use crate::endpoints;
+#[cfg(target_os = "linux")]
+use crate::event_loop;
+use crate::handler::Handler;
+use crate::http2;
+use crate::http_commons::HttpVersion;
+use crate::http_request::{HttpRequest, Limits, RequestError};
+use crate::http_response::{Buildable, Builder, HttpResponse, StatusCode};
+use crate::middleware::Compression;
+use crate::router::Router;
+use crate::shutdown::{self, ServerHandle, Shutdown};
+use crate::thread_pool::{panic_message, QueuePolicy, ThreadPool};
 use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// When to retry, told to the clients refused for want of room in the pool's queue, or of a
+/// connection slot.
+pub(crate) const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// How the server waits on its connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// A worker per connection, blocking on its reads for as long as it stays open.
+    #[default]
+    Threaded,
+    /// One thread waiting on every connection with epoll (Linux only): the workers only run the
+    /// handlers, and idle connections cost no thread.
+    Epoll,
+}
+
+#[derive(Debug)]
+pub struct EngineParseError {
+    pub found: String,
+}
+
+impl FromStr for Engine {
+    type Err = EngineParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "threaded" => Ok(Engine::Threaded),
+            "epoll" => Ok(Engine::Epoll),
+            _ => Err(EngineParseError { found: s.into() }),
+        }
+    }
+}
+
+/// Bounds on how long, and how much, a client may keep a connection: so that a slow client (or
+/// a slow-loris one, trickling its bytes) can't hold a worker or a socket for good.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionLimits {
+    /// Longest a request's header section may take to come, from its first byte (408 beyond).
+    pub header_timeout: Duration,
+    /// Longest a request's body may take to come, from the end of its header section (408
+    /// beyond).
+    pub body_timeout: Duration,
+    /// Longest a keep-alive connection may wait for its next request, before being closed.
+    pub idle_timeout: Duration,
+    /// Longest a response may wait for the client to take more of it, before the connection is
+    /// closed.
+    pub write_timeout: Duration,
+    /// Most requests answered on a connection: the last response closes it.
+    pub max_requests: usize,
+    /// Most connections open at once (503 beyond).
+    pub max_connections: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_requests: 1000,
+            max_connections: 10_000,
+        }
+    }
+}
 
 pub struct Server {
     pub address: SocketAddr,
     pub thread_pool: ThreadPool,
-    pub data_dir: Arc<Path>, // NOTE: Arc vs plain String: pblm with Arc::Clone in run()
+    /// Bounds on the requests read from clients.
+    pub limits: Limits,
+    /// Bounds on the connections: timeouts, requests per connection, connections open.
+    pub connection_limits: ConnectionLimits,
+    pub engine: Engine,
+    /// What to do with the connections (or the requests, with epoll) the pool's queue has no
+    /// room for.
+    pub queue_policy: QueuePolicy,
+    /// How long the requests underway get to finish once a shutdown is requested.
+    pub shutdown_timeout: Duration,
+    pub(crate) handler: Arc<dyn Handler>, // NOTE: Arc vs Box: pblm with Arc::Clone in run()
+    pub(crate) shutdown: Arc<Shutdown>,
+    pub(crate) connections: Arc<OpenConnections>,
 }
 
 impl Server {
+    /// Server answering with the routes of `router` and the built-in endpoints (echo, user-agent,
+    /// sleep, and the files of `data_dir`), added after them: see `Router::route` for which
+    /// route answers.
+    /// Response bodies are compressed as the client accepts (the `Compression` middleware, added
+    /// after the router's own middlewares).
+    #[must_use]
+    pub fn new(
+        address: &SocketAddr,
+        pool_size: usize,
+        data_dir: &Path,
+        mut router: Router,
+    ) -> Self {
+        endpoints::register(&mut router, data_dir);
+        router.wrap(Compression);
+        Server::with_handler(address, pool_size, router)
+    }
+
+    /// Server passing every request to `handler`, without the built-in endpoints.
     #[must_use]
-    pub fn new(address: &SocketAddr, pool_size: usize, data_dir: &Path) -> Self {
+    pub fn with_handler<H: Handler + 'static>(
+        address: &SocketAddr,
+        pool_size: usize,
+        handler: H,
+    ) -> Self {
         Server {
             address: *address,
             thread_pool: ThreadPool::new(pool_size),
-            data_dir: Arc::from(data_dir),
+            limits: Limits::default(),
+            connection_limits: ConnectionLimits::default(),
+            engine: Engine::default(),
+            queue_policy: QueuePolicy::default(),
+            shutdown_timeout: Duration::from_secs(30),
+            handler: Arc::new(handler),
+            shutdown: Arc::default(),
+            connections: Arc::default(),
         }
     }
 
-    /// Start the server running.
+    /// Handle to shut the server down from another thread.
+    #[must_use]
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(&self.shutdown)
+    }
+
+    /// Shuts the server down gracefully on SIGTERM or SIGINT (Ctrl-C), as `ServerHandle::shutdown`
+    /// does. A second signal kills the process as usual.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the signal handlers can't be installed
+    #[cfg(unix)]
+    pub fn shutdown_on_signals(&self) -> io::Result<()> {
+        let handle = self.handle();
+        shutdown::on_termination(move || {
+            println!("Termination signal received");
+            handle.shutdown();
+        })
+    }
+
+    /// Start the server running, until a shutdown is requested (see `handle`): then the requests
+    /// underway get `shutdown_timeout` to finish, and the pool's workers are joined.
     ///
     /// # Errors
     ///
-    /// Returns an error when an incoming TCP connection can't be accepted
+    /// Returns an error when an incoming TCP connection can't be accepted, or when the engine
+    /// isn't available on this platform
     pub fn run(&self) -> Result<(), Box<dyn Error>> {
         let listener = TcpListener::bind(self.address)?;
+        self.shutdown.started(listener.local_addr()?);
+
+        match self.engine {
+            Engine::Threaded => self.run_threaded(&listener)?,
+            #[cfg(target_os = "linux")]
+            Engine::Epoll => event_loop::run(&listener, self)?,
+            #[cfg(not(target_os = "linux"))]
+            Engine::Epoll => return Err("the epoll engine only runs on Linux".into()),
+        }
+        drop(listener);
+
+        self.shutdown
+            .drain(self.shutdown.deadline(self.shutdown_timeout));
+        self.thread_pool.join();
+        Ok(())
+    }
+
+    fn run_threaded(&self, listener: &TcpListener) -> Result<(), Box<dyn Error>> {
         let pool = &self.thread_pool;
 
         for stream in listener.incoming() {
+            if self.shutdown.is_requested() {
+                break;
+            }
             match stream {
                 Ok(stream) => {
-                    let data_dir = Arc::clone(&self.data_dir); // NOTE: self vs Self vs Server
-                    pool.execute(move || {
-                        match Self::handle_stream(stream, &data_dir) {
+                    let Some(slot) = self
+                        .connections
+                        .open(self.connection_limits.max_connections)
+                    else {
+                        eprintln!(
+                            "Too many connections ({} open): 503",
+                            self.connection_limits.max_connections
+                        );
+                        refuse(stream);
+                        continue;
+                    };
+                    // Kept to answer 503 on, should the queue be full
+                    let refusal = match self.queue_policy {
+                        QueuePolicy::Reject => match stream.try_clone() {
+                            Ok(refusal) => Some(refusal),
+                            Err(e) => {
+                                eprintln!("Error handling the stream: {e}");
+                                continue;
+                            }
+                        },
+                        QueuePolicy::Block => None,
+                    };
+                    let handler = Arc::clone(&self.handler); // NOTE: self vs Self vs Server
+                    let limits = self.limits;
+                    let connection_limits = self.connection_limits;
+                    let shutdown = Arc::clone(&self.shutdown);
+                    let job = move || {
+                        let _slot = slot;
+                        // Still queued when the shutdown came: answered all the same, not cut
+                        if shutdown.is_requested() {
+                            eprintln!("Shutting down: 503");
+                            refuse(stream);
+                            return;
+                        }
+                        match Self::handle_stream(
+                            stream,
+                            handler.as_ref(),
+                            &limits,
+                            &connection_limits,
+                            &shutdown,
+                        ) {
                             Ok(()) => println!("Successfully handled stream"),
                             Err(e) => eprintln!("Error handling the stream: {e}"), // TODO: propagate
                                                                                    // the error to the main thread ?
                         };
-                    });
+                    };
+                    match refusal {
+                        None => pool.execute(job),
+                        Some(refusal) => {
+                            if pool.try_execute(job).is_err() {
+                                let stats = pool.stats();
+                                eprintln!(
+                                    "Job queue full ({} queued, {} rejected so far): 503",
+                                    stats.queued, stats.rejected
+                                );
+                                refuse(refusal);
+                            }
+                        }
+                    }
                 }
                 Err(e) => {
                     return Err(format!("Error accepting the connection: {e}").into());
@@ -52,36 +266,78 @@ impl Server {
         Ok(())
     }
 
-    fn handle_stream(mut stream: TcpStream, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    fn handle_stream(
+        mut stream: TcpStream,
+        handler: &dyn Handler,
+        limits: &Limits,
+        connection_limits: &ConnectionLimits,
+        shutdown: &Shutdown,
+    ) -> Result<(), Box<dyn Error>> {
         println!("accepted new connection");
-        stream.set_read_timeout(Some(Duration::new(30, 0)))?; // 30s
+        stream.set_write_timeout(Some(connection_limits.write_timeout))?;
+        let connection = shutdown.track(&stream)?;
 
         // TODO: if build_from_stream err, then we build error-404 reponse ? always want to answer
         // I guess
 
+        // One reader for the whole connection: it may buffer the start of a pipelined request
+        let mut reader = BufReader::new(TimedReader::new(stream.try_clone()?));
+
         let mut keep_alive = true;
+        let mut served = 0;
 
         while keep_alive {
-            match HttpRequest::build_from_stream(&mut stream) {
+            // Waiting for the next request, the connection is idle: a shutdown closes it, and so
+            // does the idle timeout
+            if !connection.idle() {
+                break;
+            }
+            reader.get_mut().expire_in(connection_limits.idle_timeout);
+            match reader.fill_buf() {
+                Ok([]) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    println!("Idle connection timed out");
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            connection.busy();
+
+            // From its first byte on, the request has its header timeout, then its body timeout
+            reader.get_mut().expire_in(connection_limits.header_timeout);
+            let on_head = |reader: &mut BufReader<TimedReader>| {
+                reader.get_mut().expire_in(connection_limits.body_timeout);
+            };
+            match Self::read_request(&mut reader, &mut stream, handler, limits, on_head) {
                 Ok(http_request) => {
+                    // Prior knowledge or `Upgrade: h2c`: the connection goes on as HTTP/2
+                    if http2::switches(&http_request) {
+                        println!("switching to HTTP/2");
+                        reader.get_mut().idle(connection_limits.idle_timeout)?;
+                        http2::serve(
+                            &mut reader,
+                            &stream,
+                            handler,
+                            limits,
+                            http_request,
+                            &connection,
+                        )?;
+                        return Ok(());
+                    }
                     println!("Parsed http-request: {http_request:?}\n");
 
-                    keep_alive = http_request.keep_alive();
+                    let mut http_response = respond(handler, &http_request);
+                    served += 1;
+                    if shutdown.is_requested() || served >= connection_limits.max_requests {
+                        http_response.headers.insert("connection", "close");
+                    }
+                    keep_alive = !http_response.conn_close();
                     println!("keep-alive: {keep_alive}");
-
-                    let http_response = HttpResponse::new_from_request(&http_request, data_dir);
-                    let request_line = http_request.request_target;
-                    let status_code = http_response.status_code;
-                    let content_type = http_response.content_type;
-                    println!(
-                        "Built http-response (status code: {status_code}) for {request_line}\nWith content type {content_type}\n"
-                    );
                     http_response.write_to(&mut stream)?;
                 }
-                Err(e) => {
-                    eprintln!("error parsing the http-request: {e}");
+                Err(http_response) => {
                     keep_alive = false; // terminate connection
-                    let http_response = HttpResponse::new_from_bad_request(&e);
                     http_response.write_to(&mut stream)?;
                 }
             }
@@ -89,4 +345,517 @@ impl Server {
 
         Ok(())
     }
+
+    /// Reads the next request, sending `100 Continue` before its body when the client waits for
+    /// it. `on_head` is called once the header section is read, before the body is. `Err` is the
+    /// final response to a request that doesn't get handled: unparsable, over a limit, too slow to
+    /// come, or refused by the handler before its body was read. Either way, the rest of the
+    /// connection isn't a request boundary any more.
+    fn read_request<R: BufRead, W: Write>(
+        reader: &mut R,
+        stream: &mut W,
+        handler: &dyn Handler,
+        limits: &Limits,
+        on_head: impl FnOnce(&mut R),
+    ) -> Result<HttpRequest, HttpResponse> {
+        let bad_request = |e: RequestError| {
+            eprintln!("error parsing the http-request: {e}");
+            HttpResponse::new_from_bad_request(&e)
+        };
+
+        let mut http_request = HttpRequest::read_head(reader, limits).map_err(bad_request)?;
+        on_head(reader);
+
+        if http_request.expects_continue() {
+            if let Some(mut rejection) = handler.reject_before_body(&http_request) {
+                rejection.headers.insert("connection", "close");
+                return Err(rejection);
+            }
+            HttpResponse::new_interim(StatusCode::Continue)
+                .write_to(stream)
+                .map_err(|e| bad_request(e.into()))?;
+        }
+
+        http_request
+            .read_body(reader, limits)
+            .map_err(bad_request)?;
+        Ok(http_request)
+    }
+}
+
+/// The handler's response to `http_request`, in the client's version and telling whether the
+/// connection persists.
+pub(crate) fn respond(handler: &dyn Handler, http_request: &HttpRequest) -> HttpResponse {
+    finish(http_request, run_handler(handler, http_request))
+}
+
+/// The handler's response to `http_request`, or a `500 Internal Server Error` if it panics: the
+/// connection, the worker and the other requests carry on.
+pub(crate) fn run_handler(handler: &dyn Handler, http_request: &HttpRequest) -> HttpResponse {
+    panic::catch_unwind(AssertUnwindSafe(|| handler.handle(http_request))).unwrap_or_else(|panic| {
+        eprintln!(
+            "Handler panicked on {}: {}",
+            http_request.request_target,
+            panic_message(panic.as_ref())
+        );
+        let mut builder = HttpResponse::builder();
+        builder.with_status_code(StatusCode::InternalServerError);
+        builder.build()
+    })
+}
+
+/// Puts the response to `http_request` in the client's version, and says whether the
+/// connection persists.
+pub(crate) fn finish(http_request: &HttpRequest, mut http_response: HttpResponse) -> HttpResponse {
+    // Answer in the client's version, framing included: no chunked for 1.0
+    http_response.set_protocol_version(http_request.protocol_version);
+    if !http_request.keep_alive() {
+        http_response.headers.insert("connection", "close");
+    } else if http_request.protocol_version == HttpVersion::Http10 && !http_response.conn_close() {
+        // Persistence is opt-in for HTTP/1.0: confirm it
+        http_response.headers.insert("connection", "keep-alive");
+    }
+
+    let request_line = &http_request.request_target;
+    let status_code = http_response.status_code;
+    let content_type = http_response
+        .headers
+        .get("content-type")
+        .unwrap_or_default();
+    println!(
+        "Built http-response (status code: {status_code}) for {request_line}\nWith content type {content_type}\n"
+    );
+    http_response
+}
+
+/// Answers 503 on a connection the server has no room for (no slot, or no room in the pool's
+/// queue), from the accept loop, or on one still queued at shutdown: the socket never blocks it.
+pub(crate) fn refuse(mut stream: TcpStream) {
+    let _ = stream.set_nonblocking(true);
+    // What came of the request is read first: closing on unread bytes resets the
+    // connection, and the client may never see the response
+    let _ = stream.read(&mut [0; 8 * 1024]);
+    let _ = HttpResponse::new_service_unavailable(RETRY_AFTER).write_to(&mut stream);
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+}
+
+/// The connections open, counted against `ConnectionLimits::max_connections`.
+#[derive(Default)]
+pub(crate) struct OpenConnections(AtomicUsize);
+
+impl OpenConnections {
+    /// A slot for one more connection, freed on drop. `None` if `max` are open already.
+    pub(crate) fn open(self: &Arc<Self>, max: usize) -> Option<Slot> {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()?;
+        Some(Slot(Arc::clone(self)))
+    }
+}
+
+/// A connection counted as open, until dropped.
+pub(crate) struct Slot(Arc<OpenConnections>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The read side of a connection, held to a deadline: each read waits for the time left only,
+/// however slowly the bytes trickle in. Past the deadline, reads fail with `TimedOut`.
+struct TimedReader {
+    stream: TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl TimedReader {
+    fn new(stream: TcpStream) -> TimedReader {
+        TimedReader {
+            stream,
+            deadline: None,
+        }
+    }
+
+    /// The reads to come must be done within `timeout` from now.
+    fn expire_in(&mut self, timeout: Duration) {
+        self.deadline = Some(Instant::now() + timeout);
+    }
+
+    /// No more deadline: each read waits up to `timeout` on its own.
+    fn idle(&mut self, timeout: Duration) -> io::Result<()> {
+        self.deadline = None;
+        self.stream.set_read_timeout(Some(timeout))
+    }
+}
+
+impl Read for TimedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.stream.set_read_timeout(Some(left))?;
+        }
+        // A blocking socket reports its read timeout as WouldBlock
+        self.stream.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => io::ErrorKind::TimedOut.into(),
+            _ => e,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::{Middleware, Next};
+    use std::io::{Cursor, Read};
+
+    /// Refuses uploads without an `Authorization` header, before their body if possible.
+    struct RequireAuth;
+
+    impl RequireAuth {
+        fn unauthorized(http_request: &HttpRequest) -> Option<HttpResponse> {
+            if http_request.headers.contains("authorization") {
+                return None;
+            }
+            let mut builder = HttpResponse::builder();
+            builder.with_status_code(StatusCode::Unauthorized);
+            Some(builder.build())
+        }
+    }
+
+    impl Middleware for RequireAuth {
+        fn handle(&self, http_request: &HttpRequest, next: Next<'_>) -> HttpResponse {
+            Self::unauthorized(http_request).unwrap_or_else(|| next.run(http_request))
+        }
+
+        fn reject_before_body(&self, http_request: &HttpRequest) -> Option<HttpResponse> {
+            Self::unauthorized(http_request)
+        }
+    }
+
+    fn upload_router() -> Router {
+        let mut router = Router::new();
+        router.put("/upload", |_| HttpResponse::builder().build());
+        router.wrap(RequireAuth);
+        router
+    }
+
+    /// Reads one request, returning what was written back before its final response and the
+    /// final response if it was refused.
+    fn read(request: &str) -> (String, Result<HttpRequest, HttpResponse>) {
+        let mut reader = Cursor::new(request.to_string());
+        let mut written = Vec::new();
+        let result = Server::read_request(
+            &mut reader,
+            &mut written,
+            &upload_router(),
+            &Limits::default(),
+            |_| {},
+        );
+        (String::from_utf8(written).unwrap(), result)
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let (written, result) = read(
+            "PUT /upload HTTP/1.1\r\nAuthorization: secret\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nok",
+        );
+        assert_eq!(written, "HTTP/1.1 100 Continue\r\n\r\n");
+        assert_eq!(result.unwrap().body.unwrap(), "ok");
+
+        // The router answers OPTIONS itself, but not before the body
+        let (written, result) = read(
+            "OPTIONS /upload HTTP/1.1\r\nAuthorization: secret\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nok",
+        );
+        assert_eq!(written, "HTTP/1.1 100 Continue\r\n\r\n");
+        assert_eq!(result.unwrap().body.unwrap(), "ok");
+
+        // Without the expectation, no interim response
+        let (written, result) =
+            read("PUT /upload HTTP/1.1\r\nAuthorization: secret\r\nContent-Length: 2\r\n\r\nok");
+        assert!(written.is_empty());
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_expect_continue_rejected_before_body() {
+        let (written, result) =
+            read("PUT /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n");
+        assert!(written.is_empty());
+        let rejection = result.unwrap_err();
+        assert!(matches!(rejection.status_code, StatusCode::Unauthorized));
+        assert!(rejection.conn_close());
+
+        let (written, result) = read(
+            "PUT /elsewhere HTTP/1.1\r\nAuthorization: secret\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n",
+        );
+        assert!(written.is_empty());
+        assert!(matches!(
+            result.unwrap_err().status_code,
+            StatusCode::NotFound
+        ));
+
+        let (_, result) = read(
+            "PUT /upload HTTP/1.1\r\nExpect: 100-continue, something-else\r\nContent-Length: 2\r\n\r\n",
+        );
+        assert!(matches!(
+            result.unwrap_err().status_code,
+            StatusCode::ExpectationFailed
+        ));
+    }
+
+    const ENGINES: &[Engine] = &[
+        Engine::Threaded,
+        #[cfg(target_os = "linux")]
+        Engine::Epoll,
+    ];
+
+    /// Runs a server with a slow route and a panicking one on a free port, with `engine` and two
+    /// workers.
+    fn serve(
+        engine: Engine,
+        queue_capacity: usize,
+        connection_limits: ConnectionLimits,
+    ) -> (SocketAddr, ServerHandle, std::thread::JoinHandle<()>) {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let mut router = Router::new();
+        router.get("/slow", |_| {
+            std::thread::sleep(Duration::from_millis(300));
+            HttpResponse::builder().build()
+        });
+        router.get("/panic", |_| panic!("handler bug"));
+        let mut server = Server::with_handler(&address, 2, router);
+        server.engine = engine;
+        server.thread_pool.set_queue_capacity(queue_capacity);
+        server.connection_limits = connection_limits;
+        let handle = server.handle();
+        let running = std::thread::spawn(move || server.run().unwrap());
+        std::thread::sleep(Duration::from_millis(100));
+        (address, handle, running)
+    }
+
+    #[test]
+    fn test_graceful_shutdown() {
+        for &engine in ENGINES {
+            let (address, handle, running) = serve(engine, 16, ConnectionLimits::default());
+            let mut idle = TcpStream::connect(address).unwrap();
+            let mut busy = TcpStream::connect(address).unwrap();
+            busy.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+            std::thread::sleep(Duration::from_millis(100));
+
+            handle.shutdown();
+            // The idle connection is closed, the request underway finishes, and `run` returns
+            assert_eq!(idle.read(&mut [0]).unwrap(), 0);
+            let mut response = String::new();
+            busy.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+            assert!(response.contains("connection: close"));
+            running.join().unwrap();
+            assert!(TcpStream::connect(address).is_err());
+        }
+    }
+
+    #[test]
+    fn test_queued_connections_answered_503_on_shutdown() {
+        let (address, handle, running) = serve(Engine::Threaded, 16, ConnectionLimits::default());
+        // Both workers busy, then a connection left in the queue
+        let mut busy: Vec<_> = (0..2)
+            .map(|_| {
+                let mut stream = TcpStream::connect(address).unwrap();
+                stream.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+                stream
+            })
+            .collect();
+        std::thread::sleep(Duration::from_millis(50));
+        let mut queued = TcpStream::connect(address).unwrap();
+        queued.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        handle.shutdown();
+        let mut response = String::new();
+        queued.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(response.contains("connection: close"));
+        for stream in &mut busy {
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+        }
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn test_http2_connections_sent_goaway_on_shutdown() {
+        for &engine in ENGINES {
+            let (address, handle, running) = serve(engine, 16, ConnectionLimits::default());
+            let mut stream = TcpStream::connect(address).unwrap();
+            // Prior knowledge, with empty SETTINGS; the server's and its ack come back
+            stream
+                .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0")
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(100));
+
+            handle.shutdown();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            // The idle connection ends on GOAWAY(NO_ERROR), no stream taken
+            assert!(received.ends_with(&[0, 0, 8, 0x7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_full_queue_rejected_with_503() {
+        for &engine in ENGINES {
+            let (address, handle, running) = serve(engine, 1, ConnectionLimits::default());
+            // Two requests for the workers, one queued, and one too many
+            let mut streams: Vec<_> = (0..4)
+                .map(|_| {
+                    let mut stream = TcpStream::connect(address).unwrap();
+                    stream
+                        .write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+                        .unwrap();
+                    std::thread::sleep(Duration::from_millis(50));
+                    stream
+                })
+                .collect();
+
+            let mut refused = String::new();
+            streams.pop().unwrap().read_to_string(&mut refused).unwrap();
+            assert!(refused.starts_with("HTTP/1.1 503 Service Unavailable"));
+            assert!(refused.contains("retry-after: 1"));
+            for mut stream in streams {
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                assert!(response.starts_with("HTTP/1.1 200 OK"));
+            }
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_panicking_handler_answered_500() {
+        for &engine in ENGINES {
+            let (address, handle, running) = serve(engine, 16, ConnectionLimits::default());
+            // More panics than workers, then requests that still get answered
+            for _ in 0..3 {
+                let mut stream = TcpStream::connect(address).unwrap();
+                stream
+                    .write_all(b"GET /panic HTTP/1.1\r\n\r\nGET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+                    .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                assert!(response.starts_with("HTTP/1.1 500 Internal Server Error"));
+                assert!(response.contains("HTTP/1.1 200 OK"));
+            }
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_slow_requests_timed_out_with_408() {
+        let connection_limits = ConnectionLimits {
+            header_timeout: Duration::from_millis(400),
+            body_timeout: Duration::from_millis(300),
+            idle_timeout: Duration::from_millis(300),
+            ..ConnectionLimits::default()
+        };
+        for &engine in ENGINES {
+            let (address, handle, running) = serve(engine, 16, connection_limits);
+
+            // A header section trickling in: each byte comes in time, the whole of it doesn't
+            let start = std::time::Instant::now();
+            let mut stream = TcpStream::connect(address).unwrap();
+            for part in ["GET /slow HTTP/1.1\r\n", "Host: a\r\n", "X-A: b\r\n"] {
+                stream.write_all(part.as_bytes()).unwrap();
+                std::thread::sleep(Duration::from_millis(150));
+            }
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+            assert!(start.elapsed() < Duration::from_millis(700));
+
+            // A body short of its length
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(b"PUT /slow HTTP/1.1\r\nContent-Length: 10\r\n\r\nab")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+
+            // An idle connection is closed without a word
+            let mut stream = TcpStream::connect(address).unwrap();
+            assert_eq!(stream.read(&mut [0]).unwrap(), 0);
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_max_requests_per_connection() {
+        let connection_limits = ConnectionLimits {
+            max_requests: 2,
+            ..ConnectionLimits::default()
+        };
+        for &engine in ENGINES {
+            let (address, handle, running) = serve(engine, 16, connection_limits);
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(b"GET /none HTTP/1.1\r\n\r\nGET /none HTTP/1.1\r\n\r\n")
+                .unwrap();
+            // The second response closes the connection
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert_eq!(response.matches("HTTP/1.1 404 Not Found").count(), 2);
+            assert_eq!(response.matches("connection: close").count(), 1);
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_max_connections_refused_with_503() {
+        let connection_limits = ConnectionLimits {
+            max_connections: 1,
+            ..ConnectionLimits::default()
+        };
+        for &engine in ENGINES {
+            let (address, handle, running) = serve(engine, 16, connection_limits);
+            let open = TcpStream::connect(address).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+
+            let mut refused = TcpStream::connect(address).unwrap();
+            let mut response = String::new();
+            refused.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+
+            // Once the first connection closes, its slot is free again
+            drop(open);
+            std::thread::sleep(Duration::from_millis(50));
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(b"GET /none HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
 }
@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// Graceful shutdown, in this order:
+//
+//   1. stop accepting: the accept loop is woken by a connection to itself
+//   2. close the idle keep-alive connections (HTTP/2 ones after a GOAWAY), and every other one
+//      after its response
+//   3. wait for the connections still open, up to the deadline; then cut them
+//   4. join the pool's workers
+//
+// Connections served on a worker for all of their life (threaded engine, HTTP/2) are tracked
+// here, to know which ones are idle; the event loop keeps track of its own.
+
+/// Lets you stop a running `Server` from another thread, gracefully. Clones stop the same server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    shutdown: Arc<Shutdown>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(shutdown: &Arc<Shutdown>) -> ServerHandle {
+        ServerHandle {
+            shutdown: Arc::clone(shutdown),
+        }
+    }
+
+    /// Has `Server::run` stop accepting connections, finish the requests underway (up to the
+    /// server's `shutdown_timeout`) and return. Doesn't wait for it.
+    pub fn shutdown(&self) {
+        self.shutdown.request();
+    }
+}
+
+/// Shutdown state of a server, shared with its handles and its connections.
+#[derive(Default)]
+pub(crate) struct Shutdown {
+    requested: AtomicBool,
+    requested_at: Mutex<Option<Instant>>,
+    /// Where the server listens, once it runs: to wake its accept loop up.
+    local_addr: Mutex<Option<SocketAddr>>,
+    connections: Mutex<HashMap<u64, Tracked>>,
+    all_closed: Condvar,
+    next_id: AtomicU64,
+}
+
+struct Tracked {
+    stream: TcpStream,
+    /// How a shutdown closes the connection while it's idle; `None` while it's busy.
+    idle: Option<net::Shutdown>,
+}
+
+impl Shutdown {
+    pub(crate) fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// When the requests underway must be done, `timeout` after the shutdown was requested.
+    pub(crate) fn deadline(&self, timeout: Duration) -> Instant {
+        self.requested_at
+            .lock()
+            .unwrap()
+            .unwrap_or_else(Instant::now)
+            + timeout
+    }
+
+    /// The server listens on `local_addr` from now on.
+    pub(crate) fn started(&self, local_addr: SocketAddr) {
+        *self.local_addr.lock().unwrap() = Some(local_addr);
+    }
+
+    fn request(&self) {
+        {
+            let connections = self.connections.lock().unwrap();
+            if self.requested.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            *self.requested_at.lock().unwrap() = Some(Instant::now());
+            println!("Shutdown requested: no more connections accepted");
+            for tracked in connections.values() {
+                if let Some(how) = tracked.idle {
+                    let _ = tracked.stream.shutdown(how);
+                }
+            }
+        }
+
+        // A connection of our own gets the accept loop out of `accept`
+        if let Some(local_addr) = *self.local_addr.lock().unwrap() {
+            let _ = TcpStream::connect(loopback(local_addr));
+        }
+    }
+
+    /// Tracks the connection until the guard returned is dropped.
+    /// # Errors
+    /// Returns an error if the stream can't be cloned
+    pub(crate) fn track(&self, stream: &TcpStream) -> io::Result<Guard<'_>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stream = stream.try_clone()?;
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(id, Tracked { stream, idle: None });
+        Ok(Guard { shutdown: self, id })
+    }
+
+    /// Waits for the tracked connections to close, up to `deadline`, then cuts those left.
+    pub(crate) fn drain(&self, deadline: Instant) {
+        let mut connections = self.connections.lock().unwrap();
+        while !connections.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                println!(
+                    "Shutdown deadline passed: closing {} connections",
+                    connections.len()
+                );
+                for tracked in connections.values() {
+                    let _ = tracked.stream.shutdown(net::Shutdown::Both);
+                }
+                return;
+            }
+            connections = self
+                .all_closed
+                .wait_timeout(connections, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+/// A tracked connection, untracked on drop.
+pub(crate) struct Guard<'a> {
+    shutdown: &'a Shutdown,
+    id: u64,
+}
+
+impl Guard<'_> {
+    pub(crate) fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_requested()
+    }
+
+    /// Marks the connection idle, waiting for its next request: a shutdown closes it. `false` if
+    /// the shutdown is underway already, for the connection to close now.
+    pub(crate) fn idle(&self) -> bool {
+        self.set_idle(Some(net::Shutdown::Both))
+    }
+
+    /// Like `idle`, but a shutdown only ends the reads: the connection still has its last words
+    /// to write, an HTTP/2 GOAWAY.
+    pub(crate) fn idle_reading(&self) -> bool {
+        self.set_idle(Some(net::Shutdown::Read))
+    }
+
+    /// Marks the connection busy with a request, which a shutdown lets finish.
+    pub(crate) fn busy(&self) {
+        self.set_idle(None);
+    }
+
+    fn set_idle(&self, idle: Option<net::Shutdown>) -> bool {
+        let mut connections = self.shutdown.connections.lock().unwrap();
+        if idle.is_some() && self.shutdown.is_requested() {
+            return false;
+        }
+        if let Some(tracked) = connections.get_mut(&self.id) {
+            tracked.idle = idle;
+        }
+        true
+    }
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        let mut connections = self.shutdown.connections.lock().unwrap();
+        connections.remove(&self.id);
+        if connections.is_empty() {
+            self.shutdown.all_closed.notify_all();
+        }
+    }
+}
+
+/// The address to reach a server listening on `local_addr` from the same host.
+fn loopback(local_addr: SocketAddr) -> SocketAddr {
+    match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), local_addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), local_addr.port())
+        }
+        _ => local_addr,
+    }
+}
+
+#[cfg(unix)]
+pub(crate) use signals::on_termination;
+
+/// SIGTERM and SIGINT, turned into a call on a thread of ours: a signal handler may do next to
+/// nothing, so it only writes to a pipe that thread waits on.
+#[cfg(unix)]
+mod signals {
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::fd::{FromRawFd, OwnedFd};
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+
+    const SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+    /// Write end of the pipe, for the signal handler.
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn on_signal(_signal: libc::c_int) {
+        let fd = PIPE.load(Ordering::Relaxed);
+        // SAFETY: write is async-signal-safe, and the buffer lives for the call
+        unsafe { libc::write(fd, [1u8].as_ptr().cast(), 1) };
+    }
+
+    /// Calls `f` on the first SIGTERM or SIGINT. The next one gets the default behavior back:
+    /// a second Ctrl-C kills the process.
+    /// # Errors
+    /// Returns an error if the pipe or the signal handlers can't be set up
+    pub(crate) fn on_termination<F: FnOnce() + Send + 'static>(f: F) -> io::Result<()> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for both ends
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the read end is ours alone; the write end stays open for the handler
+        let reader = File::from(unsafe { OwnedFd::from_raw_fd(fds[0]) });
+        PIPE.store(fds[1], Ordering::Relaxed);
+
+        for signal in SIGNALS {
+            set_handler(signal, on_signal as *const () as libc::sighandler_t)?;
+        }
+
+        thread::spawn(move || {
+            let mut byte = [0];
+            if (&reader).read_exact(&mut byte).is_ok() {
+                for signal in SIGNALS {
+                    let _ = set_handler(signal, libc::SIG_DFL);
+                }
+                f();
+            }
+        });
+        Ok(())
+    }
+
+    fn set_handler(signal: libc::c_int, handler: libc::sighandler_t) -> io::Result<()> {
+        // SAFETY: an all-zero sigaction is valid (empty mask, no flags), then filled in
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_RESTART;
+        // SAFETY: `action` is initialized; the previous action isn't asked for
+        if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn connected_pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_idle_connections_closed_busy_ones_drained() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let shutdown = Arc::new(Shutdown::default());
+
+        let (mut idle_client, idle_server) = connected_pair(&listener);
+        let (_busy_client, busy_server) = connected_pair(&listener);
+        let idle_guard = shutdown.track(&idle_server).unwrap();
+        assert!(idle_guard.idle());
+
+        let busy = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                let _guard = shutdown.track(&busy_server).unwrap();
+                thread::sleep(Duration::from_millis(200));
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        ServerHandle::new(&shutdown).shutdown();
+        assert!(shutdown.is_requested());
+        // The idle connection is closed right away...
+        assert_eq!(idle_client.read(&mut [0]).unwrap(), 0);
+        assert!(!idle_guard.idle());
+        drop(idle_guard);
+
+        // ...the busy one is waited for
+        let start = Instant::now();
+        shutdown.drain(start + Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        busy.join().unwrap();
+    }
+
+    #[test]
+    fn test_drain_cuts_at_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let shutdown = Shutdown::default();
+        let (mut client, server) = connected_pair(&listener);
+        let _guard = shutdown.track(&server).unwrap();
+
+        shutdown.request();
+        shutdown.drain(Instant::now() + Duration::from_millis(100));
+        assert_eq!(client.read(&mut [0]).unwrap(), 0);
+    }
+}
@@ -1,9 +1,19 @@
-use std::sync::{mpsc, Arc, Mutex};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many jobs may wait for a worker, by default.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
+    /// Grows the pool, when it may: see `PoolSizing`.
+    manager: Mutex<Option<thread::JoinHandle<()>>>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -15,16 +25,29 @@ struct Worker {
 
 impl Worker {
     #[must_use]
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(id: usize, shared: Arc<Shared>) -> Worker {
+        let mut starting = true;
         let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-
-            if let Ok(job) = message {
-                println!("Worker {id} got a job; executing.");
-                job();
-            } else {
-                println!("Worker {id} disconnected; shutting down.");
-                break;
+            match shared.next_job(std::mem::take(&mut starting)) {
+                Next::Job(job) => {
+                    println!("Worker {id} got a job; executing.");
+                    // A panicking job takes neither the worker nor the pool down with it
+                    if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        shared.panicked.fetch_add(1, Ordering::Relaxed);
+                        eprintln!(
+                            "Worker {id} recovered from a panicking job: {}",
+                            panic_message(panic.as_ref())
+                        );
+                    }
+                }
+                Next::Retire => {
+                    println!("Worker {id} idle; retiring.");
+                    break;
+                }
+                Next::Exit => {
+                    println!("Worker {id} disconnected; shutting down.");
+                    break;
+                }
             }
         });
         Worker {
@@ -34,6 +57,222 @@ impl Worker {
     }
 }
 
+/// How many workers a pool runs: from `min` to `max`, as the load goes.
+///
+/// Once a queued job has waited for `grow_after`, a worker is added (up to `max`); a worker idle
+/// for `keep_alive` retires (down to `min`). With `min == max`, the pool keeps its size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSizing {
+    pub min: usize,
+    pub max: usize,
+    pub grow_after: Duration,
+    pub keep_alive: Duration,
+}
+
+impl PoolSizing {
+    /// Defaults for the thresholds of an elastic pool.
+    pub const GROW_AFTER: Duration = Duration::from_millis(100);
+    pub const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+    /// A pool of `size` workers, neither growing nor shrinking.
+    #[must_use]
+    pub fn fixed(size: usize) -> PoolSizing {
+        PoolSizing::elastic(size, size)
+    }
+
+    /// A pool from `min` to `max` workers, with the default thresholds.
+    #[must_use]
+    pub fn elastic(min: usize, max: usize) -> PoolSizing {
+        PoolSizing {
+            min,
+            max,
+            grow_after: PoolSizing::GROW_AFTER,
+            keep_alive: PoolSizing::KEEP_ALIVE,
+        }
+    }
+}
+
+/// What the pool, its workers and its manager share: the jobs waiting for a worker, up to a
+/// capacity, and the workers themselves.
+struct Shared {
+    state: Mutex<State>,
+    /// Signaled when a job is queued, or the queue closed.
+    job_queued: Condvar,
+    /// Signaled when a job is taken off the queue, or the queue closed.
+    job_taken: Condvar,
+    /// Signaled for the manager, when the sizing changes or the queue closed.
+    resized: Condvar,
+    workers: Mutex<Vec<Worker>>,
+    next_id: AtomicUsize,
+    rejected: AtomicU64,
+    panicked: AtomicU64,
+}
+
+struct State {
+    jobs: VecDeque<Queued>,
+    capacity: usize,
+    sizing: PoolSizing,
+    /// Workers running, and how many of them wait for a job (or are about to).
+    workers: usize,
+    idle: usize,
+    /// No more jobs are queued: the workers exit once the queue is empty.
+    closed: bool,
+}
+
+struct Queued {
+    job: Job,
+    at: Instant,
+}
+
+/// What a worker does next.
+enum Next {
+    Job(Job),
+    /// Idle for too long, while the pool has more workers than it needs.
+    Retire,
+    /// The queue is closed and empty.
+    Exit,
+}
+
+impl Shared {
+    /// The pool's state. No job runs with it locked, so a panic can't leave it halfway updated:
+    /// a poisoned lock is taken all the same.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn workers(&self) -> MutexGuard<'_, Vec<Worker>> {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts a worker, counted in `state` as idle until it looks for its first job.
+    fn spawn(self: &Arc<Self>, state: &mut State) {
+        state.workers += 1;
+        state.idle += 1;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut workers = self.workers();
+        // Retired workers are done with already
+        workers.retain(|worker| worker.thread.as_ref().is_some_and(|t| !t.is_finished()));
+        workers.push(Worker::new(id, Arc::clone(self)));
+    }
+
+    /// The next job, waiting for one if need be, unless the worker is to stop. `starting` on a
+    /// worker's first call.
+    fn next_job(&self, starting: bool) -> Next {
+        let mut state = self.lock();
+        if starting {
+            state.idle -= 1;
+        }
+        let next = loop {
+            if state.workers > state.sizing.max {
+                break Next::Retire;
+            }
+            if let Some(queued) = state.jobs.pop_front() {
+                self.job_taken.notify_one();
+                return Next::Job(queued.job);
+            }
+            if state.closed {
+                break Next::Exit;
+            }
+
+            state.idle += 1;
+            let keep_alive = state.sizing.keep_alive;
+            let (guard, wait) = self
+                .job_queued
+                .wait_timeout(state, keep_alive)
+                .unwrap_or_else(PoisonError::into_inner);
+            state = guard;
+            state.idle -= 1;
+            if wait.timed_out() && state.jobs.is_empty() && state.workers > state.sizing.min {
+                break Next::Retire;
+            }
+        };
+        state.workers -= 1;
+        next
+    }
+
+    /// Adds workers while queued jobs wait past `grow_after` and no worker is idle, up to
+    /// `max`. Runs until the queue closes.
+    fn manage(self: &Arc<Self>) {
+        let mut state = self.lock();
+        while !state.closed {
+            let grow_after = state.sizing.grow_after;
+            let waited = state.jobs.front().map(|queued| queued.at.elapsed());
+            let wait = match waited {
+                Some(waited) if waited >= grow_after => {
+                    if state.idle == 0 && state.workers < state.sizing.max {
+                        println!("Job waiting for {waited:?}: adding a worker");
+                        self.spawn(&mut state);
+                        continue;
+                    }
+                    // Whoever is idle is about to take it
+                    grow_after / 4
+                }
+                Some(waited) => grow_after - waited,
+                None => grow_after,
+            };
+            state = self
+                .resized
+                .wait_timeout(state, wait.max(Duration::from_millis(1)))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+/// What a panic was about, from its payload: the message of `panic!` and the like.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+/// What the server does with a connection, or a request, when the pool's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueuePolicy {
+    /// Answer `503 Service Unavailable` right away, with a `Retry-After`.
+    #[default]
+    Reject,
+    /// Wait for room in the queue: the threaded engine stops accepting meanwhile, the epoll
+    /// engine holds the request back and goes on with the other connections.
+    Block,
+}
+
+#[derive(Debug)]
+pub struct QueuePolicyParseError {
+    pub found: String,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = QueuePolicyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(QueuePolicy::Reject),
+            "block" => Ok(QueuePolicy::Block),
+            _ => Err(QueuePolicyParseError { found: s.into() }),
+        }
+    }
+}
+
+/// A snapshot of a pool's load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub workers: usize,
+    /// Workers waiting for a job.
+    pub idle: usize,
+    /// Jobs waiting for a worker.
+    pub queued: usize,
+    pub queue_capacity: usize,
+    /// Jobs refused by `try_execute` since the pool started, the queue being full.
+    pub rejected: u64,
+    /// Jobs that panicked since the pool started. Their workers went on.
+    pub panicked: u64,
+}
+
 // Here is the new process that will happen when we create a `ThreadPool`. We’ll implement the code that sends the closure to the thread after we have Worker set up in this way:
 //
 // Define a Worker::new function that takes an id number and returns a Worker instance that holds the id and a thread spawned with an empty closure.
@@ -42,7 +281,9 @@ impl Worker {
 impl ThreadPool {
     /// Create a new `ThreadPool`.
     ///
-    /// The size is the number of threads in the pool.
+    /// The size is the number of threads in the pool, for good unless `set_sizing` says
+    /// otherwise. Up to `DEFAULT_QUEUE_CAPACITY` jobs may wait for one of them, see
+    /// `set_queue_capacity`.
     ///
     /// # Panics
     ///
@@ -51,45 +292,321 @@ impl ThreadPool {
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0);
 
-        let (tx, rx) = mpsc::channel(); // transmitter (tx), receiver (rx)
-        let rx = Arc::new(Mutex::new(rx));
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                capacity: DEFAULT_QUEUE_CAPACITY,
+                sizing: PoolSizing::fixed(size),
+                workers: 0,
+                idle: 0,
+                closed: false,
+            }),
+            job_queued: Condvar::new(),
+            job_taken: Condvar::new(),
+            resized: Condvar::new(),
+            workers: Mutex::new(Vec::with_capacity(size)),
+            next_id: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+        });
 
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&rx)));
+        {
+            let mut state = shared.lock();
+            for _ in 0..size {
+                shared.spawn(&mut state);
+            }
         }
 
         ThreadPool {
-            workers,
-            sender: Some(tx),
+            shared,
+            manager: Mutex::new(None),
+        }
+    }
+
+    /// Lets the pool grow and shrink within the bounds of `sizing`. Workers beyond `max`
+    /// retire as soon as they are done with their job; those missing to `min` start now.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `0 < min <= max`.
+    pub fn set_sizing(&self, sizing: PoolSizing) {
+        assert!(sizing.min > 0 && sizing.min <= sizing.max);
+        let mut state = self.shared.lock();
+        state.sizing = sizing;
+        while state.workers < sizing.min && !state.closed {
+            self.shared.spawn(&mut state);
+        }
+        // Idle workers see about the new bounds
+        self.shared.job_queued.notify_all();
+        self.shared.resized.notify_all();
+
+        let mut manager = self.manager.lock().unwrap_or_else(PoisonError::into_inner);
+        if sizing.max > sizing.min && manager.is_none() && !state.closed {
+            let shared = Arc::clone(&self.shared);
+            *manager = Some(thread::spawn(move || shared.manage()));
         }
     }
+
+    /// Sets how many jobs may wait for a worker. Jobs queued already stay, even past it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the capacity is zero.
+    pub fn set_queue_capacity(&self, capacity: usize) {
+        assert!(capacity > 0);
+        self.shared.lock().capacity = capacity;
+        self.shared.job_taken.notify_all();
+    }
+
     /// Execute a task on the threadpool.
     /// Creates a `Job` from a task `f` and dispatch it to a worker which will carry-on the job
-    /// execution.
+    /// execution. Waits for room in the queue if it is full.
     ///
     /// # Panics
     ///
-    /// Sending the job to a worker can panic.
+    /// Executing a job on a pool that was joined panics.
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        let mut state = self.shared.lock();
+        while state.jobs.len() >= state.capacity && !state.closed {
+            state = self
+                .shared
+                .job_taken
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        assert!(!state.closed, "thread pool joined already");
+        state.jobs.push_back(Queued {
+            job: Box::new(f),
+            at: Instant::now(),
+        });
+        self.shared.job_queued.notify_one();
+    }
+
+    /// Same as `execute`, unless the queue is full: then `f` is given back, and counted as
+    /// rejected.
+    ///
+    /// # Errors
+    ///
+    /// Returns `f` when the queue is full
+    ///
+    /// # Panics
+    ///
+    /// Executing a job on a pool that was joined panics.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.shared.lock();
+        assert!(!state.closed, "thread pool joined already");
+        if state.jobs.len() >= state.capacity {
+            self.shared.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(f);
+        }
+        state.jobs.push_back(Queued {
+            job: Box::new(f),
+            at: Instant::now(),
+        });
+        self.shared.job_queued.notify_one();
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> PoolStats {
+        let state = self.shared.lock();
+        PoolStats {
+            workers: state.workers,
+            idle: state.idle,
+            queued: state.jobs.len(),
+            queue_capacity: state.capacity,
+            rejected: self.shared.rejected.load(Ordering::Relaxed),
+            panicked: self.shared.panicked.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Lets the workers finish the jobs sent so far, then waits for them to exit.
+    pub fn join(&self) {
+        self.shared.lock().closed = true;
+        self.shared.job_queued.notify_all();
+        self.shared.job_taken.notify_all();
+        self.shared.resized.notify_all();
+
+        // No worker starts once the manager is gone
+        let manager = self
+            .manager
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(manager) = manager {
+            let _ = manager.join();
+        }
+        for worker in self.shared.workers().iter_mut() {
+            if let Some(thread) = worker.thread.take() {
+                println!("Shutting down worker {}", worker.id);
+                // Jobs' panics are caught: a worker's own would be a bug of the pool's
+                if thread.join().is_err() {
+                    eprintln!("Worker {} had panicked", worker.id);
+                }
+            }
+        }
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        self.join();
+    }
+}
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+    /// A pool of one worker, kept busy until the sender returned is dropped.
+    fn busy_pool(queue_capacity: usize) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::new(1);
+        pool.set_queue_capacity(queue_capacity);
+        let (release, released) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = released.recv();
+        });
+        // Until the worker has taken it, the job is queued
+        while pool.stats().queued > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        (pool, release)
+    }
+
+    #[test]
+    fn test_try_execute_rejects_when_full() {
+        let (pool, release) = busy_pool(2);
+        assert!(pool.try_execute(|| {}).is_ok());
+        assert!(pool.try_execute(|| {}).is_ok());
+        assert!(pool.try_execute(|| {}).is_err());
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                workers: 1,
+                idle: 0,
+                queued: 2,
+                queue_capacity: 2,
+                rejected: 1,
+                panicked: 0
+            }
+        );
+
+        drop(release);
+        pool.join();
+        assert_eq!(pool.stats().queued, 0);
+    }
+
+    #[test]
+    fn test_execute_waits_for_room() {
+        let (pool, release) = busy_pool(1);
+        let pool = Arc::new(pool);
+        pool.execute(|| {});
+
+        let (done, executed) = mpsc::channel();
+        let blocked = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                pool.execute(move || done.send(()).unwrap());
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+
+        drop(release);
+        blocked.join().unwrap();
+        executed.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(pool.stats().rejected, 0);
+    }
+
+    #[test]
+    fn test_queue_policy_from_str() {
+        assert_eq!("block".parse::<QueuePolicy>().unwrap(), QueuePolicy::Block);
+        assert_eq!(
+            "reject".parse::<QueuePolicy>().unwrap(),
+            QueuePolicy::Reject
+        );
+        assert_eq!("drop".parse::<QueuePolicy>().unwrap_err().found, "drop");
+    }
+
+    #[test]
+    fn test_worker_survives_panicking_job() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("boom"));
+        pool.execute(|| std::panic::panic_any(42));
+
+        let (done, executed) = mpsc::channel();
+        pool.execute(move || done.send(()).unwrap());
+        executed.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(pool.stats().panicked, 2);
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let message = |f: fn()| {
+            let payload = std::panic::catch_unwind(f).unwrap_err();
+            panic_message(payload.as_ref()).to_string()
+        };
+        assert_eq!(message(|| panic!("static")), "static");
+        assert_eq!(message(|| panic!("formatted {}", 1)), "formatted 1");
+        assert_eq!(message(|| std::panic::panic_any(1)), "Box<dyn Any>");
+    }
+
+    /// Waits up to a second for the pool to have `workers` workers.
+    fn wait_for_workers(pool: &ThreadPool, workers: usize) -> bool {
+        let start = std::time::Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            if pool.stats().workers == workers {
+                return true;
             }
+            thread::sleep(Duration::from_millis(5));
         }
+        false
+    }
+
+    #[test]
+    fn test_elastic_pool_grows_and_shrinks() {
+        let pool = ThreadPool::new(1);
+        pool.set_sizing(PoolSizing {
+            min: 1,
+            max: 3,
+            grow_after: Duration::from_millis(20),
+            keep_alive: Duration::from_millis(100),
+        });
+
+        // Four jobs holding their worker: the pool grows to its max, one job left waiting
+        let (release, released) = mpsc::channel::<()>();
+        let released = Arc::new(Mutex::new(released));
+        for _ in 0..4 {
+            let released = Arc::clone(&released);
+            pool.execute(move || {
+                let _ = released.lock().unwrap().recv();
+            });
+        }
+        assert!(wait_for_workers(&pool, 3));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.stats().workers, 3);
+        assert_eq!(pool.stats().queued, 1);
+
+        // Idle again, the extra workers retire
+        drop(release);
+        assert!(wait_for_workers(&pool, 1));
+        pool.join();
+    }
+
+    #[test]
+    fn test_shrinking_max_retires_workers() {
+        let pool = ThreadPool::new(4);
+        pool.set_sizing(PoolSizing::fixed(2));
+        assert!(wait_for_workers(&pool, 2));
+        pool.set_sizing(PoolSizing::elastic(3, 5));
+        assert_eq!(pool.stats().workers, 3);
     }
 }
@@ -0,0 +1,275 @@
+use crate::router::ParamError;
+
+use std::fmt;
+use std::str::FromStr;
+
+// The request-target (RFC 9112, section 3.2) comes in one of these forms:
+//
+//   /where?q=now           origin-form: absolute path and optional query
+//   http://host/where?q    absolute-form: sent to proxies, servers must accept it too
+//   *                      asterisk-form: `OPTIONS *` only
+//
+// (authority-form is for CONNECT, which isn't implemented.) Clients shouldn't send a fragment,
+// but one is split off anyway rather than ending up in the path.
+//
+// The path is percent-decoded exactly once, here: handlers only ever see the decoded form.
+// Routes are the exception: they split the raw path into segments first, then decode each one,
+// so that an encoded `/` (`%2F`) stays inside its segment. Decoding can produce `/`, `.` or `..`
+// that weren't visible in the raw target, so anything mapping the path onto the file system
+// must check it *after* this.
+
+/// The request-target split into its components.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestTarget {
+    /// Percent-decoded path, `*` for the asterisk-form.
+    pub path: String,
+    /// Raw query, without the `?`.
+    pub query: Option<String>,
+    /// Raw fragment, without the `#`.
+    pub fragment: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct RequestTargetParseError {
+    pub found: String,
+}
+
+impl FromStr for RequestTarget {
+    type Err = RequestTargetParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || RequestTargetParseError { found: s.into() };
+
+        if s == "*" {
+            return Ok(RequestTarget {
+                path: s.into(),
+                query: None,
+                fragment: None,
+            });
+        }
+
+        let (raw_path, query, fragment) = split(s);
+        if !raw_path.starts_with('/') {
+            return Err(err());
+        }
+        let path = decode_path(raw_path).ok_or_else(err)?;
+
+        Ok(RequestTarget {
+            path,
+            query: query.map(String::from),
+            fragment: fragment.map(String::from),
+        })
+    }
+}
+
+/// The raw path, query and fragment of a target other than `*`.
+fn split(target: &str) -> (&str, Option<&str>, Option<&str>) {
+    let (rest, fragment) = match target.split_once('#') {
+        Some((rest, fragment)) => (rest, Some(fragment)),
+        None => (target, None),
+    };
+    let (raw_path, query) = match rest.split_once('?') {
+        Some((raw_path, query)) => (raw_path, Some(query)),
+        None => (rest, None),
+    };
+    let raw_path = match strip_scheme_and_authority(raw_path) {
+        Some("") => "/",
+        Some(raw_path) => raw_path,
+        None => raw_path,
+    };
+    (raw_path, query, fragment)
+}
+
+/// The path of the target as sent, still percent-encoded. `None` for the asterisk-form.
+pub(crate) fn raw_path(target: &str) -> Option<&str> {
+    (target != "*").then(|| split(target).0)
+}
+
+/// Decodes a path, or a part of one. `None` if an escape is malformed, or the result isn't
+/// UTF-8 or holds a NUL.
+pub(crate) fn decode_path(raw_path: &str) -> Option<String> {
+    percent_decode(raw_path)
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        // NUL has no business in a path, and ends C strings early
+        .filter(|path| !path.contains('\0'))
+}
+
+/// The path of an absolute-form target (`http://host/path`), `None` for other forms.
+fn strip_scheme_and_authority(target: &str) -> Option<&str> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    Some(rest.find('/').map_or("", |i| &rest[i..]))
+}
+
+/// Decodes `%XX` escapes. `None` if an escape is truncated or not hexadecimal.
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hi = hex_value(bytes.next()?)?;
+            let lo = hex_value(bytes.next()?)?;
+            decoded.push(hi << 4 | lo);
+        } else {
+            decoded.push(b);
+        }
+    }
+    Some(decoded)
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    char::from(b)
+        .to_digit(16)
+        .and_then(|d| u8::try_from(d).ok())
+}
+
+/// Decodes a query component the way HTML forms encode it: `+` is a space, and malformed
+/// escapes or invalid UTF-8 are kept (lossily) rather than failing the whole request.
+fn form_decode(s: &str) -> String {
+    let s = s.replace('+', " ");
+    match percent_decode(&s) {
+        Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        None => s,
+    }
+}
+
+/// Decoded `name=value` pairs of the query, in query order. A name may appear more than once.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryParams {
+    params: Vec<(String, String)>,
+}
+
+impl QueryParams {
+    /// Parses an `application/x-www-form-urlencoded` query. A pair without `=` has an empty
+    /// value.
+    #[must_use]
+    pub fn from_query(query: &str) -> QueryParams {
+        let params = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (form_decode(name), form_decode(value))
+            })
+            .collect();
+        QueryParams { params }
+    }
+
+    /// The first value of the parameter.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of the parameter (`?tag=a&tag=b`), in query order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.params
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The first value of the parameter parsed as a `T`, e.g. `query_params.parse::<u32>("page")`.
+    /// # Errors
+    /// Returns a `ParamError` if there is no such parameter or its value doesn't parse.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T, ParamError> {
+        let value = self
+            .get(name)
+            .ok_or_else(|| ParamError::Missing(name.into()))?;
+        value.parse().map_err(|_| ParamError::Invalid {
+            name: name.into(),
+            value: value.into(),
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+}
+
+impl fmt::Display for RequestTargetParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid request-target: {:?}", self.found)
+    }
+}
+
+impl std::error::Error for RequestTargetParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(s: &str) -> RequestTarget {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_target_forms() {
+        assert_eq!(
+            target("/files/a.txt?download=1#top"),
+            RequestTarget {
+                path: "/files/a.txt".into(),
+                query: Some("download=1".into()),
+                fragment: Some("top".into()),
+            }
+        );
+        assert_eq!(target("http://example.com/echo/hi?x").path, "/echo/hi");
+        assert_eq!(target("http://example.com").path, "/");
+        assert_eq!(target("*").path, "*");
+        assert!("echo/hi".parse::<RequestTarget>().is_err());
+    }
+
+    #[test]
+    fn test_path_percent_decoding() {
+        assert_eq!(target("/echo/hello%20world").path, "/echo/hello world");
+        assert_eq!(target("/files/caf%C3%A9").path, "/files/café");
+        // Decoding may reveal traversal: it's the file system side's job to refuse it
+        assert_eq!(target("/files/%2e%2e%2Fetc").path, "/files/../etc");
+        // Query delimiters stay data once escaped
+        assert_eq!(target("/echo/a%3Fb%23c").path, "/echo/a?b#c");
+
+        for invalid in ["/echo/%", "/echo/%2", "/echo/%zz", "/echo/%00", "/echo/%ff"] {
+            assert!(invalid.parse::<RequestTarget>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_query_params() {
+        let query = QueryParams::from_query("q=hello+world&tag=a&tag=b%26c&flag&&bad=%zz");
+
+        assert_eq!(query.get("q"), Some("hello world"));
+        assert_eq!(query.get_all("tag").collect::<Vec<_>>(), ["a", "b&c"]);
+        assert_eq!(query.get("flag"), Some(""));
+        assert_eq!(query.get("bad"), Some("%zz"));
+        assert_eq!(query.get("missing"), None);
+        assert_eq!(query.iter().count(), 5);
+    }
+
+    #[test]
+    fn test_typed_query_params() {
+        let query = QueryParams::from_query("page=3&size=big");
+
+        assert_eq!(query.parse::<u32>("page"), Ok(3));
+        assert_eq!(
+            query.parse::<u32>("size"),
+            Err(ParamError::Invalid {
+                name: "size".into(),
+                value: "big".into()
+            })
+        );
+        assert_eq!(
+            query.parse::<u32>("limit"),
+            Err(ParamError::Missing("limit".into()))
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use flyweight_http_server::Server;
+use flyweight_http_server::{Router, Server};
 
 use std::io::{Read, Write};
 use std::net::{SocketAddr, TcpStream};
@@ -27,7 +27,7 @@ impl TestServer {
 
         // Start server in background thread
         thread::spawn(move || {
-            let server = Server::new(&address, 4, &data_dir);
+            let server = Server::new(&address, 4, &data_dir, Router::new());
             // This runs in an infinite loop
             let _ = server.run();
         });